@@ -0,0 +1,131 @@
+//! Window showing the frames drawn by the PPU
+//!
+//! Frames are uploaded to a streaming texture once per VBlank. The texture is drawn at the largest
+//! integer scale that fits the window and centered, the rest of the window stays black.
+
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
+use sdl2::render::{Canvas, Texture, TextureCreator};
+use sdl2::video::{FullscreenType, Window, WindowContext};
+use sdl2::VideoSubsystem;
+
+use crate::emulator::ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+pub const MIN_SCALE: u32 = 1;
+pub const MAX_SCALE: u32 = 8;
+
+// RGB of the 4 shades, white to black
+const SHADES: [[u8; 3]; 4] = [[0xff, 0xff, 0xff], [0xaa, 0xaa, 0xaa], [0x55, 0x55, 0x55], [0x00, 0x00, 0x00]];
+
+pub struct Display {
+    canvas: Canvas<Window>,
+    title: String,
+    scale: u32, // Window size in windowed mode, as a multiple of the screen size
+}
+
+impl Display {
+    /// Open a resizable window
+    ///
+    /// # Arguments
+    ///
+    /// * `video` - SDL video subsystem
+    /// * `title` - Window title
+    /// * `scale` - Initial window size, as a multiple of 160x144
+    ///
+    pub fn new(video: &VideoSubsystem, title: &str, scale: u32) -> Result<Self, String> {
+        let scale = scale.clamp(MIN_SCALE, MAX_SCALE);
+        let mut window = video
+            .window(title, SCREEN_WIDTH as u32 * scale, SCREEN_HEIGHT as u32 * scale)
+            .position_centered()
+            .resizable()
+            .build()
+            .map_err(|err| err.to_string())?;
+        window.set_minimum_size(SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32).map_err(|err| err.to_string())?;
+        let mut canvas = window.into_canvas().build().map_err(|err| err.to_string())?;
+        canvas.set_draw_color(Color::RGB(0, 0, 0));
+        canvas.clear();
+        canvas.present();
+        Ok(Self { canvas, title: title.to_string(), scale })
+    }
+
+    /// Show a status after the title, like the speed or pause state. Empty to only show the title
+    pub fn set_status(&mut self, status: &str) {
+        let title = if status.is_empty() { self.title.clone() } else { format!("{} - {}", self.title, status) };
+        // Only fails on titles with a nul byte, which the header parser replaces
+        let _ = self.canvas.window_mut().set_title(&title);
+    }
+
+    pub fn texture_creator(&self) -> TextureCreator<WindowContext> {
+        self.canvas.texture_creator()
+    }
+
+    /// Create the texture frames are uploaded to
+    pub fn create_texture(texture_creator: &TextureCreator<WindowContext>) -> Result<Texture<'_>, String> {
+        texture_creator
+            .create_texture_streaming(PixelFormatEnum::RGB24, SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32)
+            .map_err(|err| err.to_string())
+    }
+
+    /// Upload a frame and show it
+    ///
+    /// # Arguments
+    ///
+    /// * `texture` - Texture from `create_texture`
+    /// * `frame` - Shades (0-3) of the 160x144 pixels, row by row
+    ///
+    pub fn present(&mut self, texture: &mut Texture, frame: &[u8]) -> Result<(), String> {
+        texture.with_lock(None, |pixels: &mut [u8], pitch: usize| {
+            for (y, row) in frame.chunks_exact(SCREEN_WIDTH).enumerate() {
+                let line = &mut pixels[y * pitch..y * pitch + SCREEN_WIDTH * 3];
+                for (pixel, &shade) in line.chunks_exact_mut(3).zip(row) {
+                    pixel.copy_from_slice(&SHADES[shade as usize]);
+                }
+            }
+        })?;
+        let (width, height) = self.canvas.output_size()?;
+        self.canvas.clear();
+        self.canvas.copy(texture, None, Self::screen_rect(width, height))?;
+        self.canvas.present();
+        Ok(())
+    }
+
+    // Area of the window the screen is drawn to. It is scaled by the largest integer that fits, or
+    // scaled down keeping the aspect ratio when the window is smaller than the screen
+    fn screen_rect(width: u32, height: u32) -> Rect {
+        let (screen_width, screen_height) = (SCREEN_WIDTH as u32, SCREEN_HEIGHT as u32);
+        let scale = (width / screen_width).min(height / screen_height);
+        let (w, h) = if scale >= 1 {
+            (screen_width * scale, screen_height * scale)
+        } else if width * screen_height < height * screen_width {
+            (width, width * screen_height / screen_width)
+        } else {
+            (height * screen_width / screen_height, height)
+        };
+        Rect::new(((width - w) / 2) as i32, ((height - h) / 2) as i32, w.max(1), h.max(1))
+    }
+
+    /// Resize the window to a multiple of the screen size, ignored in fullscreen
+    pub fn set_scale(&mut self, scale: u32) -> Result<(), String> {
+        self.scale = scale.clamp(MIN_SCALE, MAX_SCALE);
+        if self.canvas.window().fullscreen_state() != FullscreenType::Off {
+            return Ok(());
+        }
+        self.canvas
+            .window_mut()
+            .set_size(SCREEN_WIDTH as u32 * self.scale, SCREEN_HEIGHT as u32 * self.scale)
+            .map_err(|err| err.to_string())
+    }
+
+    pub fn scale(&self) -> u32 {
+        self.scale
+    }
+
+    /// Switch between a window and fullscreen at the desktop resolution
+    pub fn toggle_fullscreen(&mut self) -> Result<(), String> {
+        let fullscreen = match self.canvas.window().fullscreen_state() {
+            FullscreenType::Off => FullscreenType::Desktop,
+            _ => FullscreenType::Off,
+        };
+        self.canvas.window_mut().set_fullscreen(fullscreen)
+    }
+}
@@ -0,0 +1,55 @@
+//! Volume envelope, NRx2 of the square and noise channels
+//!
+//! Bits 7-4 are the initial volume, bit 3 the direction (1 increases) and bits 2-0 the period in
+//! 64 Hz frame sequencer steps, 0 stops the envelope. The DAC of the channel is off when the upper
+//! 5 bits are 0.
+
+pub struct Envelope {
+    register: u8, // NRx2, applied on the next trigger
+    volume: u8,
+    timer: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Self {
+        Self { register: 0, volume: 0, timer: 0 }
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.register & 0xf8 != 0
+    }
+
+    pub fn volume(&self) -> u8 {
+        self.volume
+    }
+
+    fn period(&self) -> u8 {
+        self.register & 0b111
+    }
+
+    pub fn trigger(&mut self) {
+        self.volume = self.register >> 4;
+        self.timer = self.period();
+    }
+
+    /// Clock from the frame sequencer
+    pub fn clock(&mut self) {
+        if self.period() == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period();
+        if self.register & 0b1000 != 0 {
+            self.volume = (self.volume + 1).min(15);
+        } else {
+            self.volume = self.volume.saturating_sub(1);
+        }
+    }
+}
@@ -0,0 +1,111 @@
+//! Length counter, switches a channel off after it played for a set time
+//!
+//! The counter is loaded by NRx1 and decremented by the frame sequencer at 256 Hz while enabled
+//! by NRx4 bit 6. The channel is disabled when it reaches 0.
+
+pub struct Length {
+    max: u16, // 64, or 256 for the wave channel
+    counter: u16,
+    enabled: bool,
+}
+
+impl Length {
+    pub fn new(max: u16) -> Self {
+        Self { max, counter: 0, enabled: false }
+    }
+
+    /// Load the counter from the length bits of NRx1
+    pub fn load(&mut self, value: u8) {
+        self.counter = self.max - value as u16;
+    }
+
+    /// Clock from the frame sequencer
+    /// Returns true when the channel should be disabled
+    pub fn clock(&mut self) -> bool {
+        if !self.enabled || self.counter == 0 {
+            return false;
+        }
+        self.counter -= 1;
+        self.counter == 0
+    }
+
+    /// Handle a write to NRx4
+    /// Returns true when the channel should be disabled
+    ///
+    /// # Arguments
+    ///
+    /// * `enable` - Bit 6, length enable
+    /// * `trigger` - Bit 7, restarts the channel
+    /// * `extra_clock` - The next frame sequencer step does not clock the length
+    ///
+    pub fn write(&mut self, enable: bool, trigger: bool, extra_clock: bool) -> bool {
+        let was_enabled = self.enabled;
+        self.enabled = enable;
+        let mut disable = false;
+        // Enabling the length in the half of the period where it is not clocked clocks it once
+        if extra_clock && enable && !was_enabled && self.counter > 0 {
+            self.counter -= 1;
+            disable = self.counter == 0 && !trigger;
+        }
+        // An expired length is reloaded on trigger, and clocked as above
+        if trigger && self.counter == 0 {
+            self.counter = if enable && extra_clock { self.max - 1 } else { self.max };
+        }
+        disable
+    }
+
+    pub fn disable(&mut self) {
+        self.enabled = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn length(value: u8) -> Length {
+        let mut length = Length::new(64);
+        length.load(value);
+        length
+    }
+
+    #[test]
+    fn clocks_down_to_zero_when_enabled() {
+        let mut length = length(62);
+        assert!(!length.clock());
+        assert_eq!(length.counter, 2);
+        length.write(true, false, false);
+        assert!(!length.clock());
+        assert!(length.clock());
+        assert!(!length.clock());
+    }
+
+    #[test]
+    fn enabling_in_first_half_clocks_once() {
+        let mut length = length(60);
+        assert!(!length.write(true, false, true));
+        assert_eq!(length.counter, 3);
+        // Only when it goes from disabled to enabled
+        length.write(true, false, true);
+        assert_eq!(length.counter, 3);
+        // Reaching 0 disables the channel
+        let mut length = self::length(63);
+        assert!(length.write(true, false, true));
+        assert_eq!(length.counter, 0);
+    }
+
+    #[test]
+    fn trigger_reloads_expired_length() {
+        let mut length = length(64);
+        length.write(false, true, false);
+        assert_eq!(length.counter, 64);
+        // Reloaded then clocked, when enabled in the first half
+        let mut length = self::length(64);
+        assert!(!length.write(true, true, true));
+        assert_eq!(length.counter, 63);
+        // Clocked to 0 by the enable, then reloaded by the trigger without disabling the channel
+        let mut length = self::length(63);
+        assert!(!length.write(true, true, true));
+        assert_eq!(length.counter, 63);
+    }
+}
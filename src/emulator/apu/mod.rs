@@ -0,0 +1,360 @@
+//! Audio processing unit, NR10-NR52 (0xFF10-0xFF26) and wave RAM (0xFF30-0xFF3F)
+//!
+//! The four channels produce a digital value (0-15) every T-cycle, which their DAC turns into an
+//! analog level between -1 and 1. NR51 routes every channel to the left and right outputs and NR50
+//! sets the volume of each output. The mix is averaged down to the host sample rate and goes
+//! through a high-pass filter, like the capacitor on the hardware output, to remove the DC offset.
+//!
+//! The frame sequencer steps at 512 Hz, on the falling edge of bit 4 of DIV. It clocks the length
+//! counters on even steps, the sweep on steps 2 and 6, and the envelopes on step 7.
+
+mod envelope;
+mod length;
+mod noise;
+mod square;
+mod wave;
+
+use std::mem;
+
+use noise::Noise;
+use square::Square;
+use wave::Wave;
+
+use super::mapper::CYCLES_PER_SECOND;
+
+// NR52 bit 7, powers the APU
+const NR52_POWER: u8 = 0x80;
+// Register offsets from 0xFF10
+const NR10: u8 = 0x00;
+const NR11: u8 = 0x01;
+const NR12: u8 = 0x02;
+const NR13: u8 = 0x03;
+const NR14: u8 = 0x04;
+const NR21: u8 = 0x06;
+const NR22: u8 = 0x07;
+const NR23: u8 = 0x08;
+const NR24: u8 = 0x09;
+const NR30: u8 = 0x0a;
+const NR31: u8 = 0x0b;
+const NR32: u8 = 0x0c;
+const NR33: u8 = 0x0d;
+const NR34: u8 = 0x0e;
+const NR41: u8 = 0x10;
+const NR42: u8 = 0x11;
+const NR43: u8 = 0x12;
+const NR44: u8 = 0x13;
+const NR50: u8 = 0x14;
+const NR51: u8 = 0x15;
+const NR52: u8 = 0x16;
+// Register values after the DMG boot ROM. The trigger bits are left out, the boot sound is over
+const BOOT_REGISTERS: [(u8, u8); 20] = [
+    (NR10, 0x80), (NR11, 0xbf), (NR12, 0xf3), (NR13, 0xff), (NR14, 0x3f),
+    (NR21, 0x3f), (NR22, 0x00), (NR23, 0xff), (NR24, 0x3f),
+    (NR30, 0x7f), (NR31, 0xff), (NR32, 0x9f), (NR33, 0xff), (NR34, 0x3f),
+    (NR41, 0xff), (NR42, 0x00), (NR43, 0x00), (NR44, 0x3f),
+    (NR50, 0x77), (NR51, 0xf3),
+];
+// Bit of DIV whose falling edge steps the frame sequencer
+const DIV_FRAME_SEQUENCER_BIT: u8 = 0x10;
+// Charge kept by the high-pass filter capacitor every T-cycle
+const CAPACITOR_CHARGE: f64 = 0.999958;
+
+pub struct APU {
+    registers: [u8; 0x17], // NR10-NR52 as written, offset from 0xFF10
+    powered: bool,
+    square1: Square,
+    square2: Square,
+    wave: Wave,
+    noise: Noise,
+    frame_step: u8, // Next frame sequencer step, 0-7
+    div_bit: bool, // Last value of the DIV bit clocking the frame sequencer
+    sample_rate: u32, // Host sample rate, 0 when no samples are wanted
+    sample_clock: u32, // Fraction of a sample elapsed, in sample_rate units per T-cycle
+    mix: (f32, f32), // Sum of the left and right outputs since the last sample
+    mixed: u32, // Number of T-cycles summed in `mix`
+    capacitor: (f32, f32),
+    capacitor_charge: f32, // Charge kept by the capacitor every sample
+    samples: Vec<f32>, // Interleaved left and right samples
+}
+
+impl APU {
+    pub fn new() -> Self {
+        let mut apu = Self {
+            registers: [0; 0x17],
+            powered: true,
+            square1: Square::new(true),
+            square2: Square::new(false),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            frame_step: 0,
+            div_bit: false,
+            sample_rate: 0,
+            sample_clock: 0,
+            mix: (0.0, 0.0),
+            mixed: 0,
+            capacitor: (0.0, 0.0),
+            capacitor_charge: 0.0,
+            samples: Vec::new(),
+        };
+        for (offset, value) in BOOT_REGISTERS {
+            apu.write(offset, value);
+        }
+        apu
+    }
+
+    /// Start producing samples
+    ///
+    /// # Arguments
+    ///
+    /// * `sample_rate` - Samples per second, for each of the left and right outputs
+    ///
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.capacitor_charge = CAPACITOR_CHARGE.powf(CYCLES_PER_SECOND as f64 / sample_rate as f64) as f32;
+    }
+
+    /// Take the samples produced since the last call, interleaved left and right, between -1 and 1
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        mem::take(&mut self.samples)
+    }
+
+    /// Advance the APU by a number of T-cycles
+    ///
+    /// # Arguments
+    ///
+    /// * `cycles` - Number of T-cycles to advance by
+    /// * `div` - Value of DIV after those cycles, to clock the frame sequencer
+    ///
+    pub fn tick(&mut self, cycles: u8, div: u8) {
+        let div_bit = div & DIV_FRAME_SEQUENCER_BIT != 0;
+        if self.div_bit && !div_bit && self.powered {
+            self.step_frame_sequencer();
+        }
+        self.div_bit = div_bit;
+        for _ in 0..cycles {
+            if self.powered {
+                self.square1.tick();
+                self.square2.tick();
+                self.wave.tick();
+                self.noise.tick();
+            }
+            if self.sample_rate != 0 {
+                self.mix_cycle();
+            }
+        }
+    }
+
+    fn step_frame_sequencer(&mut self) {
+        if self.frame_step.is_multiple_of(2) {
+            self.square1.clock_length();
+            self.square2.clock_length();
+            self.wave.clock_length();
+            self.noise.clock_length();
+        }
+        if self.frame_step == 2 || self.frame_step == 6 {
+            self.square1.clock_sweep();
+        }
+        if self.frame_step == 7 {
+            self.square1.clock_envelope();
+            self.square2.clock_envelope();
+            self.noise.clock_envelope();
+        }
+        self.frame_step = (self.frame_step + 1) % 8;
+    }
+
+    // Analog level of a channel, silent when its DAC is off
+    fn dac(output: u8, enabled: bool) -> f32 {
+        if enabled { output as f32 / 7.5 - 1.0 } else { 0.0 }
+    }
+
+    // Add the output of this T-cycle to the mix, and produce a sample when one is due
+    fn mix_cycle(&mut self) {
+        let channels = [
+            Self::dac(self.square1.output(), self.square1.dac_enabled()),
+            Self::dac(self.square2.output(), self.square2.dac_enabled()),
+            Self::dac(self.wave.output(), self.wave.dac_enabled()),
+            Self::dac(self.noise.output(), self.noise.dac_enabled()),
+        ];
+        let panning = self.registers[NR51 as usize];
+        for (channel, level) in channels.iter().enumerate() {
+            if panning & (0x10 << channel) != 0 {
+                self.mix.0 += level;
+            }
+            if panning & (0x01 << channel) != 0 {
+                self.mix.1 += level;
+            }
+        }
+        self.mixed += 1;
+        self.sample_clock += self.sample_rate;
+        if self.sample_clock < CYCLES_PER_SECOND {
+            return;
+        }
+        self.sample_clock -= CYCLES_PER_SECOND;
+        // Average of the T-cycles since the last sample, scaled by the NR50 volumes
+        let volume = self.registers[NR50 as usize];
+        let left = self.mix.0 / self.mixed as f32 * (((volume >> 4) & 0b111) + 1) as f32 / 8.0 / 4.0;
+        let right = self.mix.1 / self.mixed as f32 * ((volume & 0b111) + 1) as f32 / 8.0 / 4.0;
+        self.mix = (0.0, 0.0);
+        self.mixed = 0;
+        let left = self.high_pass(left, true);
+        let right = self.high_pass(right, false);
+        self.samples.extend([left, right]);
+    }
+
+    // Remove the DC offset of one output
+    fn high_pass(&mut self, input: f32, left: bool) -> f32 {
+        let capacitor = if left { &mut self.capacitor.0 } else { &mut self.capacitor.1 };
+        if !self.powered {
+            *capacitor = 0.0;
+            return 0.0;
+        }
+        let output = input - *capacitor;
+        *capacitor = input - output * self.capacitor_charge;
+        output
+    }
+
+    /// Read a register
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the register from 0xFF10
+    ///
+    pub fn read(&self, offset: u8) -> u8 {
+        if offset != NR52 {
+            return self.registers[offset as usize];
+        }
+        // The lower bits report which channels are playing
+        let channels = [self.square1.enabled, self.square2.enabled, self.wave.enabled, self.noise.enabled];
+        let playing = channels.iter().enumerate().fold(0, |bits, (channel, &enabled)| bits | (enabled as u8) << channel);
+        if self.powered { NR52_POWER | playing } else { 0 }
+    }
+
+    /// Write a register
+    ///
+    /// # Arguments
+    ///
+    /// * `offset` - Offset of the register from 0xFF10
+    /// * `value` - Value to write
+    ///
+    pub fn write(&mut self, offset: u8, value: u8) {
+        if offset == NR52 {
+            self.write_power(value & NR52_POWER != 0);
+            return;
+        }
+        if !self.powered {
+            // Registers are read only while the APU is off, except for the length counters
+            match offset {
+                NR11 => self.square1.length.load(value & 0x3f),
+                NR21 => self.square2.length.load(value & 0x3f),
+                NR31 => self.wave.length.load(value),
+                NR41 => self.noise.length.load(value & 0x3f),
+                _ => {}
+            }
+            return;
+        }
+        self.registers[offset as usize] = value;
+        // Writing NRx4 in the first half of a length period clocks the length counter
+        let extra_clock = !self.frame_step.is_multiple_of(2);
+        match offset {
+            NR10 => self.square1.write_sweep(value),
+            NR11 => self.square1.write_duty_length(value),
+            NR12 => self.square1.write_envelope(value),
+            NR13 => self.square1.write_frequency_low(value),
+            NR14 => self.square1.write_control(value, extra_clock),
+            NR21 => self.square2.write_duty_length(value),
+            NR22 => self.square2.write_envelope(value),
+            NR23 => self.square2.write_frequency_low(value),
+            NR24 => self.square2.write_control(value, extra_clock),
+            NR30 => self.wave.write_dac(value),
+            NR31 => self.wave.length.load(value),
+            NR32 => self.wave.write_volume(value),
+            NR33 => self.wave.write_frequency_low(value),
+            NR34 => self.wave.write_control(value, extra_clock),
+            NR41 => self.noise.length.load(value & 0x3f),
+            NR42 => self.noise.write_envelope(value),
+            NR43 => self.noise.write_polynomial(value),
+            NR44 => self.noise.write_control(value, extra_clock),
+            // NR50 and NR51 are used from the registers when mixing
+            _ => {}
+        }
+    }
+
+    // Turning the APU off clears every register and silences the channels
+    fn write_power(&mut self, on: bool) {
+        if on && !self.powered {
+            self.frame_step = 0;
+        } else if !on && self.powered {
+            self.registers = [0; 0x17];
+            self.square1.power_off();
+            self.square2.power_off();
+            self.wave.power_off();
+            self.noise.power_off();
+        }
+        self.powered = on;
+    }
+
+    /// Select the behaviour of CGB hardware instead of DMG
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.wave.set_cgb(cgb);
+    }
+
+    pub fn read_wave_ram(&self, offset: u8) -> u8 {
+        self.wave.read_ram(offset)
+    }
+
+    pub fn write_wave_ram(&mut self, offset: u8, value: u8) {
+        self.wave.write_ram(offset, value);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mmu::MMU;
+
+    #[test]
+    fn power_off_clears_registers_and_keeps_wave_ram() {
+        let mut apu = APU::new();
+        apu.write_wave_ram(0x03, 0x12);
+        apu.write(NR12, 0xf0);
+        apu.write(NR14, 0x80);
+        assert_eq!(apu.read(NR52), NR52_POWER | 0b0001);
+        apu.write(NR52, 0x00);
+        assert_eq!(apu.read(NR52), 0x00);
+        assert!((NR10..NR52).all(|offset| apu.read(offset) == 0));
+        assert_eq!(apu.read_wave_ram(0x03), 0x12);
+        // Registers are read only while off
+        apu.write(NR50, 0x77);
+        assert_eq!(apu.read(NR50), 0x00);
+        apu.write(NR52, NR52_POWER);
+        assert_eq!(apu.read(NR52), NR52_POWER);
+        apu.write(NR50, 0x77);
+        assert_eq!(apu.read(NR50), 0x77);
+    }
+
+    #[test]
+    fn registers_read_back_through_masks() {
+        let mut mmu = MMU::new();
+        mmu.write_memory(0xff26, 0x00);
+        mmu.write_memory(0xff26, 0x80);
+        for address in 0xff10..0xff26 {
+            mmu.write_memory(address, 0x00);
+        }
+        let read: Vec<u8> = (0xff10..=0xff26).map(|address| mmu.read_memory(address)).collect();
+        assert_eq!(read, [
+            0x80, 0x3f, 0x00, 0xff, 0xbf,
+            0xff, 0x3f, 0x00, 0xff, 0xbf,
+            0x7f, 0xff, 0x9f, 0xff, 0xbf,
+            0xff, 0xff, 0x00, 0x00, 0xbf,
+            0x00, 0x00, 0xf0,
+        ]);
+        mmu.write_memory(0xff11, 0x80);
+        mmu.write_memory(0xff12, 0x5a);
+        mmu.write_memory(0xff1c, 0x20);
+        assert_eq!(mmu.read_memory(0xff11), 0xbf);
+        assert_eq!(mmu.read_memory(0xff12), 0x5a);
+        assert_eq!(mmu.read_memory(0xff1c), 0xbf);
+        mmu.write_memory(0xff26, 0x00);
+        assert_eq!(mmu.read_memory(0xff26), 0x70);
+    }
+}
@@ -0,0 +1,174 @@
+//! Noise channel, channel 4 (NR41-NR44)
+//!
+//! A 15 bit linear feedback shift register is clocked every divisor << shift T-cycles, NR43 bits
+//! 7-4 are the shift and bits 2-0 select the divisor. Bit 3 shortens the register to 7 bits, for a
+//! more regular sound. The channel outputs its volume while bit 0 of the register is 0.
+
+use super::envelope::Envelope;
+use super::length::Length;
+
+const DIVISORS: [u16; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+pub struct Noise {
+    pub enabled: bool,
+    pub length: Length,
+    envelope: Envelope,
+    register: u8, // NR43
+    lfsr: u16,
+    timer: u32, // T-cycles until the next LFSR clock
+}
+
+impl Noise {
+    pub fn new() -> Self {
+        Self { enabled: false, length: Length::new(64), envelope: Envelope::new(), register: 0, lfsr: 0, timer: 0 }
+    }
+
+    /// Reset everything but the length counter, when the APU is switched off
+    pub fn power_off(&mut self) {
+        let length = std::mem::replace(&mut self.length, Length::new(64));
+        *self = Self::new();
+        self.length = length;
+        self.length.disable();
+    }
+
+    /// Write NR42
+    pub fn write_envelope(&mut self, value: u8) {
+        self.envelope.write(value);
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    /// Write NR43
+    pub fn write_polynomial(&mut self, value: u8) {
+        self.register = value;
+    }
+
+    fn period(&self) -> u32 {
+        (DIVISORS[(self.register & 0b111) as usize] as u32) << (self.register >> 4)
+    }
+
+    /// Write NR44
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Value written
+    /// * `extra_clock` - The next frame sequencer step does not clock the length
+    ///
+    pub fn write_control(&mut self, value: u8, extra_clock: bool) {
+        let trigger = value & 0x80 != 0;
+        if self.length.write(value & 0x40 != 0, trigger, extra_clock) {
+            self.enabled = false;
+        }
+        if trigger {
+            self.enabled = self.envelope.dac_enabled();
+            self.lfsr = 0x7fff;
+            self.timer = self.period();
+            self.envelope.trigger();
+        }
+    }
+
+    /// Advance by a T-cycle
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period();
+        // Shifts of 14 and 15 stop the clock
+        if self.register >> 4 >= 14 {
+            return;
+        }
+        let feedback = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+        self.lfsr = (self.lfsr >> 1) | (feedback << 14);
+        if self.register & 0b1000 != 0 {
+            self.lfsr = (self.lfsr & !(1 << 6)) | (feedback << 6);
+        }
+    }
+
+    /// Digital output, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.lfsr & 1 != 0 {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Triggered channel clocking the LFSR every 8 T-cycles
+    fn noise(polynomial: u8) -> Noise {
+        let mut noise = Noise::new();
+        noise.write_envelope(0xf0);
+        noise.write_polynomial(polynomial);
+        noise.write_control(0x80, false);
+        noise
+    }
+
+    fn clock(noise: &mut Noise) {
+        for _ in 0..8 {
+            noise.tick();
+        }
+    }
+
+    #[test]
+    fn lfsr_15_bit() {
+        let mut noise = noise(0x00);
+        clock(&mut noise);
+        assert_eq!(noise.lfsr, 0x3fff);
+        // The period is 32767 clocks
+        let start = noise.lfsr;
+        let period = (1..=32767).find(|_| {
+            clock(&mut noise);
+            noise.lfsr == start
+        });
+        assert_eq!(period, Some(32767));
+    }
+
+    #[test]
+    fn lfsr_7_bit() {
+        let mut noise = noise(0x08);
+        clock(&mut noise);
+        // The feedback goes into bit 6 too
+        assert_eq!(noise.lfsr, 0x3fbf);
+        for _ in 0..20 {
+            clock(&mut noise);
+        }
+        // The lower 7 bits repeat every 127 clocks
+        let start = noise.lfsr & 0x7f;
+        let period = (1..=127).find(|_| {
+            clock(&mut noise);
+            noise.lfsr & 0x7f == start
+        });
+        assert_eq!(period, Some(127));
+    }
+
+    #[test]
+    fn output_follows_bit_0() {
+        let mut noise = noise(0x00);
+        assert_eq!(noise.output(), 0);
+        for _ in 0..15 {
+            clock(&mut noise);
+        }
+        // 15 clocks shifted the first 0 into bit 0
+        assert_eq!(noise.lfsr & 1, 0);
+        assert_eq!(noise.output(), 15);
+    }
+}
@@ -0,0 +1,281 @@
+//! Square channels, channel 1 (NR10-NR14) with a frequency sweep and channel 2 (NR21-NR24)
+//!
+//! NRx1 bits 7-6 select the duty cycle and bits 5-0 the length. NRx3 and bits 2-0 of NRx4 are the
+//! 11 bit frequency, the duty step advances every (2048 - frequency) * 4 T-cycles.
+//!
+//! NR10 bits 6-4 are the sweep period in 128 Hz frame sequencer steps, bit 3 decreases the
+//! frequency instead of increasing it and bits 2-0 are the shift. Every period the frequency
+//! changes by frequency >> shift, the channel is disabled when it goes above 2047.
+
+use super::envelope::Envelope;
+use super::length::Length;
+
+// Waveforms of the 4 duty cycles, 12.5%, 25%, 50% and 75%, played from bit 0 up
+const DUTY_WAVEFORMS: [u8; 4] = [0b1000_0000, 0b1000_0001, 0b1110_0001, 0b0111_1110];
+
+struct Sweep {
+    register: u8, // NR10
+    enabled: bool,
+    shadow: u16, // Copy of the frequency the sweep works on
+    timer: u8,
+    negated: bool, // A decreasing sweep was computed since the last trigger
+}
+
+impl Sweep {
+    fn period(&self) -> u8 {
+        (self.register >> 4) & 0b111
+    }
+
+    fn shift(&self) -> u8 {
+        self.register & 0b111
+    }
+
+    fn decrease(&self) -> bool {
+        self.register & 0b1000 != 0
+    }
+
+    // Next frequency, above 2047 on overflow
+    fn next_frequency(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift();
+        if self.decrease() {
+            self.negated = true;
+            self.shadow - delta
+        } else {
+            self.shadow + delta
+        }
+    }
+
+    // The timer treats a period of 0 as 8
+    fn reload_timer(&mut self) {
+        self.timer = if self.period() == 0 { 8 } else { self.period() };
+    }
+}
+
+pub struct Square {
+    pub enabled: bool,
+    pub length: Length,
+    envelope: Envelope,
+    sweep: Option<Sweep>,
+    duty: u8,
+    position: u8, // Step in the duty waveform
+    frequency: u16,
+    timer: u16, // T-cycles until the next duty step
+}
+
+impl Square {
+    /// # Arguments
+    ///
+    /// * `sweep` - Whether the channel has a frequency sweep, only channel 1 does
+    ///
+    pub fn new(sweep: bool) -> Self {
+        Self {
+            enabled: false,
+            length: Length::new(64),
+            envelope: Envelope::new(),
+            sweep: sweep.then_some(Sweep { register: 0, enabled: false, shadow: 0, timer: 0, negated: false }),
+            duty: 0,
+            position: 0,
+            frequency: 0,
+            timer: 0,
+        }
+    }
+
+    /// Reset everything but the length counter, when the APU is switched off
+    pub fn power_off(&mut self) {
+        let length = std::mem::replace(&mut self.length, Length::new(64));
+        *self = Self::new(self.sweep.is_some());
+        self.length = length;
+        self.length.disable();
+    }
+
+    /// Write NR10
+    pub fn write_sweep(&mut self, value: u8) {
+        let Some(sweep) = self.sweep.as_mut() else {
+            return;
+        };
+        sweep.register = value;
+        // Leaving the decreasing mode after it was used disables the channel
+        if sweep.negated && !sweep.decrease() {
+            self.enabled = false;
+        }
+    }
+
+    /// Write NRx1
+    pub fn write_duty_length(&mut self, value: u8) {
+        self.duty = value >> 6;
+        self.length.load(value & 0x3f);
+    }
+
+    /// Write NRx2
+    pub fn write_envelope(&mut self, value: u8) {
+        self.envelope.write(value);
+        if !self.envelope.dac_enabled() {
+            self.enabled = false;
+        }
+    }
+
+    /// Write NRx3
+    pub fn write_frequency_low(&mut self, value: u8) {
+        self.frequency = (self.frequency & 0x700) | value as u16;
+    }
+
+    /// Write NRx4
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Value written
+    /// * `extra_clock` - The next frame sequencer step does not clock the length
+    ///
+    pub fn write_control(&mut self, value: u8, extra_clock: bool) {
+        self.frequency = (self.frequency & 0xff) | ((value as u16 & 0b111) << 8);
+        let trigger = value & 0x80 != 0;
+        if self.length.write(value & 0x40 != 0, trigger, extra_clock) {
+            self.enabled = false;
+        }
+        if trigger {
+            self.trigger();
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.envelope.dac_enabled();
+        self.timer = (2048 - self.frequency) * 4;
+        self.envelope.trigger();
+        if let Some(sweep) = self.sweep.as_mut() {
+            sweep.shadow = self.frequency;
+            sweep.negated = false;
+            sweep.reload_timer();
+            sweep.enabled = sweep.period() != 0 || sweep.shift() != 0;
+            // The overflow check runs right away when there is a shift
+            if sweep.shift() != 0 && sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+
+    /// Advance by a T-cycle
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency) * 4;
+            self.position = (self.position + 1) % 8;
+        }
+    }
+
+    /// Digital output, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled {
+            return 0;
+        }
+        (DUTY_WAVEFORMS[self.duty as usize] >> self.position & 1) * self.envelope.volume()
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.envelope.dac_enabled()
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+
+    pub fn clock_envelope(&mut self) {
+        self.envelope.clock();
+    }
+
+    /// Clock the frequency sweep from the frame sequencer
+    pub fn clock_sweep(&mut self) {
+        let Some(sweep) = self.sweep.as_mut() else {
+            return;
+        };
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.reload_timer();
+        if !sweep.enabled || sweep.period() == 0 {
+            return;
+        }
+        let frequency = sweep.next_frequency();
+        if frequency > 2047 {
+            self.enabled = false;
+        } else if sweep.shift() != 0 {
+            sweep.shadow = frequency;
+            self.frequency = frequency;
+            // The new frequency is checked for overflow again, without being used
+            if sweep.next_frequency() > 2047 {
+                self.enabled = false;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Channel 1 with its DAC on, about to be triggered at `frequency` with the NR10 `sweep`
+    fn square(sweep: u8, frequency: u16) -> Square {
+        let mut square = Square::new(true);
+        square.write_envelope(0xf0);
+        square.write_sweep(sweep);
+        square.write_frequency_low(frequency as u8);
+        square
+    }
+
+    fn trigger(square: &mut Square, frequency: u16) {
+        square.write_control(0x80 | (frequency >> 8) as u8, false);
+    }
+
+    #[test]
+    fn sweep_overflow_disables_on_trigger() {
+        let mut square = square(0x11, 0x7ff);
+        trigger(&mut square, 0x7ff);
+        assert!(!square.enabled);
+        let mut square = self::square(0x11, 0x500);
+        trigger(&mut square, 0x500);
+        assert!(square.enabled);
+        // Without a shift there is no check on trigger
+        let mut square = self::square(0x10, 0x7ff);
+        trigger(&mut square, 0x7ff);
+        assert!(square.enabled);
+    }
+
+    #[test]
+    fn sweep_updates_and_checks_frequency() {
+        let mut square = square(0x11, 0x400);
+        trigger(&mut square, 0x400);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x600);
+        // The second check sees 0x600 + 0x300 overflow
+        assert!(!square.enabled);
+    }
+
+    #[test]
+    fn leaving_negate_mode_disables() {
+        let mut square = square(0x19, 0x400);
+        trigger(&mut square, 0x400);
+        square.clock_sweep();
+        assert_eq!(square.frequency, 0x200);
+        assert!(square.enabled);
+        square.write_sweep(0x11);
+        assert!(!square.enabled);
+    }
+
+    #[test]
+    fn duty_waveform() {
+        let mut square = square(0x00, 0x7ff);
+        square.write_duty_length(0x80);
+        trigger(&mut square, 0x7ff);
+        // 50% duty, stepping every 4 T-cycles
+        let mut output = Vec::new();
+        for _ in 0..8 {
+            output.push(square.output());
+            for _ in 0..4 {
+                square.tick();
+            }
+        }
+        assert_eq!(output, [15, 0, 0, 0, 0, 15, 15, 15]);
+    }
+}
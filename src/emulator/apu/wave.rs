@@ -0,0 +1,204 @@
+//! Wave channel, channel 3 (NR30-NR34) playing the 32 4-bit samples of wave RAM (0xFF30-0xFF3F)
+//!
+//! NR30 bit 7 switches the DAC on, NR31 is the length, NR32 bits 6-5 the volume (mute, 100%, 50%
+//! and 25%). NR33 and bits 2-0 of NR34 are the frequency, the next sample is played every
+//! (2048 - frequency) * 2 T-cycles. Samples are played from the upper nibble of 0xFF30.
+//!
+//! While the channel plays, the CPU sees the byte the channel is reading instead of the one it
+//! addresses. On CGB that byte is always accessible, on DMG only on the cycle the channel reads
+//! it, other reads return 0xFF and other writes are ignored.
+
+use super::length::Length;
+
+pub struct Wave {
+    pub enabled: bool,
+    pub length: Length,
+    dac_enabled: bool,
+    volume: u8, // NR32 bits 6-5
+    frequency: u16,
+    timer: u16, // T-cycles until the next sample
+    position: u8, // Sample being played, 0-31
+    sample: u8,
+    ram: [u8; 16],
+    just_read: bool, // The channel read wave RAM on the last T-cycle
+    cgb: bool, // CGB hardware, wave RAM stays accessible while playing
+}
+
+impl Wave {
+    pub fn new() -> Self {
+        Self {
+            enabled: false,
+            length: Length::new(256),
+            dac_enabled: false,
+            volume: 0,
+            frequency: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            ram: [0; 16],
+            just_read: false,
+            cgb: false,
+        }
+    }
+
+    /// Reset everything but the length counter and wave RAM, when the APU is switched off
+    pub fn power_off(&mut self) {
+        let length = std::mem::replace(&mut self.length, Length::new(256));
+        let ram = self.ram;
+        let cgb = self.cgb;
+        *self = Self::new();
+        self.length = length;
+        self.length.disable();
+        self.ram = ram;
+        self.cgb = cgb;
+    }
+
+    /// Select the wave RAM access behaviour of CGB hardware instead of DMG
+    pub fn set_cgb(&mut self, cgb: bool) {
+        self.cgb = cgb;
+    }
+
+    /// Write NR30
+    pub fn write_dac(&mut self, value: u8) {
+        self.dac_enabled = value & 0x80 != 0;
+        if !self.dac_enabled {
+            self.enabled = false;
+        }
+    }
+
+    /// Write NR32
+    pub fn write_volume(&mut self, value: u8) {
+        self.volume = (value >> 5) & 0b11;
+    }
+
+    /// Write NR33
+    pub fn write_frequency_low(&mut self, value: u8) {
+        self.frequency = (self.frequency & 0x700) | value as u16;
+    }
+
+    /// Write NR34
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Value written
+    /// * `extra_clock` - The next frame sequencer step does not clock the length
+    ///
+    pub fn write_control(&mut self, value: u8, extra_clock: bool) {
+        self.frequency = (self.frequency & 0xff) | ((value as u16 & 0b111) << 8);
+        let trigger = value & 0x80 != 0;
+        if self.length.write(value & 0x40 != 0, trigger, extra_clock) {
+            self.enabled = false;
+        }
+        if trigger {
+            self.enabled = self.dac_enabled;
+            self.position = 0;
+            self.just_read = false;
+            self.timer = (2048 - self.frequency) * 2;
+        }
+    }
+
+    // Byte accessed by the CPU, None when the DMG channel is playing and not reading right now
+    fn ram_index(&self, offset: u8) -> Option<usize> {
+        match (self.enabled, self.cgb || self.just_read) {
+            (false, _) => Some(offset as usize),
+            (true, true) => Some(self.position as usize / 2),
+            (true, false) => None,
+        }
+    }
+
+    pub fn read_ram(&self, offset: u8) -> u8 {
+        self.ram_index(offset).map_or(0xff, |index| self.ram[index])
+    }
+
+    pub fn write_ram(&mut self, offset: u8, value: u8) {
+        if let Some(index) = self.ram_index(offset) {
+            self.ram[index] = value;
+        }
+    }
+
+    /// Advance by a T-cycle
+    pub fn tick(&mut self) {
+        self.timer = self.timer.saturating_sub(1);
+        self.just_read = self.timer == 0;
+        if self.timer == 0 {
+            self.timer = (2048 - self.frequency) * 2;
+            self.position = (self.position + 1) % 32;
+            let byte = self.ram[self.position as usize / 2];
+            self.sample = if self.position.is_multiple_of(2) { byte >> 4 } else { byte & 0x0f };
+        }
+    }
+
+    /// Digital output, 0-15
+    pub fn output(&self) -> u8 {
+        if !self.enabled || self.volume == 0 {
+            return 0;
+        }
+        self.sample >> (self.volume - 1)
+    }
+
+    pub fn dac_enabled(&self) -> bool {
+        self.dac_enabled
+    }
+
+    pub fn clock_length(&mut self) {
+        if self.length.clock() {
+            self.enabled = false;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Playing channel with wave RAM holding its offset in every byte, reading a byte every 4 T-cycles
+    fn wave(cgb: bool) -> Wave {
+        let mut wave = Wave::new();
+        wave.set_cgb(cgb);
+        for offset in 0..16 {
+            wave.write_ram(offset, offset);
+        }
+        wave.write_dac(0x80);
+        wave.write_frequency_low(0xfe);
+        wave.write_control(0x87, false);
+        wave
+    }
+
+    #[test]
+    fn dmg_wave_ram_only_accessible_when_read() {
+        let mut wave = wave(false);
+        wave.tick();
+        assert_eq!(wave.read_ram(0x05), 0xff);
+        wave.write_ram(0x05, 0xaa);
+        wave.tick();
+        wave.tick();
+        wave.tick();
+        // The channel just moved to sample 1, still in byte 0
+        assert_eq!(wave.read_ram(0x05), 0x00);
+        wave.write_ram(0x05, 0x12);
+        assert_eq!(wave.ram[0x00], 0x12);
+        assert_eq!(wave.ram[0x05], 0x05);
+        wave.tick();
+        assert_eq!(wave.read_ram(0x05), 0xff);
+    }
+
+    #[test]
+    fn cgb_wave_ram_follows_channel() {
+        let mut wave = wave(true);
+        for _ in 0..12 {
+            wave.tick();
+        }
+        // Sample 3 is in byte 1
+        assert_eq!(wave.read_ram(0x05), 0x01);
+        wave.write_ram(0x05, 0x34);
+        assert_eq!(wave.ram[0x01], 0x34);
+    }
+
+    #[test]
+    fn wave_ram_addressed_when_stopped() {
+        let mut wave = wave(false);
+        wave.write_dac(0x00);
+        wave.write_ram(0x05, 0x56);
+        assert_eq!(wave.read_ram(0x05), 0x56);
+    }
+}
@@ -0,0 +1,59 @@
+use super::mmu::MMU;
+
+/// Memory bus seen by the CPU. Every memory access made by an instruction goes through this
+/// trait, so the CPU can run against the MMU or any other memory. Test memories, tracing and
+/// watchpoints are implemented by wrapping another bus and forwarding to it
+pub trait Bus {
+    /// Read a byte from a 16 bit address
+    fn read(&mut self, address: u16) -> u8;
+
+    /// Write a byte to a 16 bit address
+    fn write(&mut self, address: u16, value: u8);
+
+    /// Advance the hardware behind the bus by a number of T-cycles, called by the CPU at the start
+    /// of every machine cycle, before the memory access made during that cycle
+    fn tick(&mut self, _cycles: u8) {}
+
+    /// Switch between normal and double speed if a switch was armed through KEY1
+    /// Returns whether the speed was switched
+    fn switch_speed(&mut self) -> bool {
+        false
+    }
+}
+
+impl Bus for MMU {
+    fn read(&mut self, address: u16) -> u8 {
+        self.read_memory(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.write_memory(address, value)
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        MMU::tick(self, cycles)
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        MMU::switch_speed(self)
+    }
+}
+
+// Allows a bus to be lent to a wrapper without giving up ownership
+impl<B: Bus + ?Sized> Bus for &mut B {
+    fn read(&mut self, address: u16) -> u8 {
+        (**self).read(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        (**self).write(address, value)
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        (**self).tick(cycles)
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        (**self).switch_speed()
+    }
+}
@@ -0,0 +1,351 @@
+//! Cartridge ROM and header (0x0100-0x014F) parsing and validation
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+// Logo checked by the boot ROM, stored at 0x0104-0x0133
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
+    0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
+    0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
+];
+const HEADER_END: usize = 0x150;
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    // The file is too small to hold a header
+    TooSmall(usize),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+    // The logo does not match, the boot ROM would lock up
+    InvalidLogo,
+    // The boot ROM would lock up on a bad header checksum
+    HeaderChecksum { expected: u8, computed: u8 },
+    // Not checked by the hardware, but points to a bad dump
+    GlobalChecksum { expected: u16, computed: u16 },
+    // The file size does not match the ROM size in the header
+    SizeMismatch { header: usize, actual: usize },
+    // No mapper is implemented for the cartridge type
+    UnsupportedMapper(u8),
+}
+
+impl CartridgeError {
+    /// Whether real hardware would refuse to boot this cartridge
+    pub fn is_fatal(&self) -> bool {
+        !matches!(self, CartridgeError::GlobalChecksum { .. } | CartridgeError::SizeMismatch { .. })
+    }
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CartridgeError::Io(err) => write!(f, "Error reading ROM: {}", err),
+            CartridgeError::TooSmall(size) => write!(f, "ROM is too small to hold a header ({} bytes)", size),
+            CartridgeError::UnknownRomSize(code) => write!(f, "Unknown ROM size code 0x{:02X}", code),
+            CartridgeError::UnknownRamSize(code) => write!(f, "Unknown RAM size code 0x{:02X}", code),
+            CartridgeError::InvalidLogo => write!(f, "Nintendo logo does not match"),
+            CartridgeError::HeaderChecksum { expected, computed } => {
+                write!(f, "Header checksum mismatch: header says 0x{:02X}, computed 0x{:02X}", expected, computed)
+            }
+            CartridgeError::GlobalChecksum { expected, computed } => {
+                write!(f, "Global checksum mismatch: header says 0x{:04X}, computed 0x{:04X}", expected, computed)
+            }
+            CartridgeError::SizeMismatch { header, actual } => {
+                write!(f, "ROM size mismatch: header says {} bytes, file has {} bytes", header, actual)
+            }
+            CartridgeError::UnsupportedMapper(cartridge_type) => match cartridge_type_name(*cartridge_type) {
+                "UNKNOWN" => write!(f, "Unknown cartridge type 0x{:02X}, the header may be corrupted", cartridge_type),
+                name => write!(f, "Cartridge type 0x{:02X} ({}) is not supported", cartridge_type, name),
+            },
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(err: io::Error) -> Self {
+        CartridgeError::Io(err)
+    }
+}
+
+/// CGB support flag at 0x0143
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CgbSupport {
+    None,
+    Enhanced, // 0x80, works on DMG too
+    Only, // 0xC0
+}
+
+/// Publisher code, the new two character code at 0x0144 is used when the old code at 0x014B is 0x33
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Licensee {
+    Old(u8),
+    New(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub title: String,
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: u8,
+    pub rom_size: usize, // Bytes
+    pub ram_size: usize, // Bytes
+    pub licensee: Licensee,
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+}
+
+impl Header {
+    /// Parse the header of a ROM that is at least 0x150 bytes long
+    fn parse(rom: &[u8]) -> Result<Self, CartridgeError> {
+        let cgb = match rom[0x143] {
+            0xc0 => CgbSupport::Only,
+            0x80 => CgbSupport::Enhanced,
+            _ => CgbSupport::None,
+        };
+        // On CGB cartridges the end of the title area holds the manufacturer code and the CGB flag
+        let title_end = if cgb == CgbSupport::None { 0x144 } else { 0x13f };
+        let title = rom[0x134..title_end]
+            .iter()
+            .take_while(|&&byte| byte != 0)
+            .map(|&byte| if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '?' })
+            .collect::<String>()
+            .trim_end()
+            .to_string();
+        let rom_size = match rom[0x148] {
+            code @ 0x00..=0x08 => 0x8000 << code,
+            0x52 => 72 * 0x4000,
+            0x53 => 80 * 0x4000,
+            0x54 => 96 * 0x4000,
+            code => return Err(CartridgeError::UnknownRomSize(code)),
+        };
+        let ram_size = match rom[0x149] {
+            0x00 => 0,
+            0x01 => 0x800,
+            0x02 => 0x2000,
+            0x03 => 0x8000,
+            0x04 => 0x20000,
+            0x05 => 0x10000,
+            code => return Err(CartridgeError::UnknownRamSize(code)),
+        };
+        let licensee = match rom[0x14b] {
+            0x33 => Licensee::New(String::from_utf8_lossy(&rom[0x144..0x146]).into_owned()),
+            code => Licensee::Old(code),
+        };
+        Ok(Self {
+            title,
+            cgb,
+            sgb: rom[0x146] == 0x03,
+            cartridge_type: rom[0x147],
+            rom_size,
+            ram_size,
+            licensee,
+            version: rom[0x14c],
+            header_checksum: rom[0x14d],
+            global_checksum: (rom[0x14e] as u16) << 8 | rom[0x14f] as u16,
+        })
+    }
+
+    /// Whether the cartridge keeps its RAM, or other state, when switched off
+    pub fn has_battery(&self) -> bool {
+        matches!(self.cartridge_type, 0x03 | 0x06 | 0x09 | 0x0d | 0x0f | 0x10 | 0x13 | 0x1b | 0x1e | 0x20 | 0x22 | 0xfc..=0xff)
+    }
+
+    /// Name of the cartridge type (mapper and extra hardware)
+    pub fn cartridge_type_name(&self) -> &'static str {
+        cartridge_type_name(self.cartridge_type)
+    }
+}
+
+// Name of a cartridge type byte
+fn cartridge_type_name(cartridge_type: u8) -> &'static str {
+    match cartridge_type {
+        0x00 => "ROM ONLY",
+        0x01 => "MBC1",
+        0x02 => "MBC1+RAM",
+        0x03 => "MBC1+RAM+BATTERY",
+        0x05 => "MBC2",
+        0x06 => "MBC2+BATTERY",
+        0x08 => "ROM+RAM",
+        0x09 => "ROM+RAM+BATTERY",
+        0x0b => "MMM01",
+        0x0c => "MMM01+RAM",
+        0x0d => "MMM01+RAM+BATTERY",
+        0x0f => "MBC3+TIMER+BATTERY",
+        0x10 => "MBC3+TIMER+RAM+BATTERY",
+        0x11 => "MBC3",
+        0x12 => "MBC3+RAM",
+        0x13 => "MBC3+RAM+BATTERY",
+        0x19 => "MBC5",
+        0x1a => "MBC5+RAM",
+        0x1b => "MBC5+RAM+BATTERY",
+        0x1c => "MBC5+RUMBLE",
+        0x1d => "MBC5+RUMBLE+RAM",
+        0x1e => "MBC5+RUMBLE+RAM+BATTERY",
+        0x20 => "MBC6",
+        0x22 => "MBC7+SENSOR+RUMBLE+RAM+BATTERY",
+        0xfc => "POCKET CAMERA",
+        0xfd => "BANDAI TAMA5",
+        0xfe => "HuC3",
+        0xff => "HuC1+RAM+BATTERY",
+        _ => "UNKNOWN",
+    }
+}
+
+#[derive(Debug)]
+pub struct Cartridge {
+    pub header: Header,
+    pub rom: Vec<u8>,
+}
+
+impl Cartridge {
+    /// Read and parse a ROM file
+    pub fn load(path: &str) -> Result<Self, CartridgeError> {
+        Self::from_bytes(fs::read(path)?)
+    }
+
+    /// Parse a ROM image. Only problems that make the header unreadable are errors here, use
+    /// `verify` to check the checksums and logo
+    pub fn from_bytes(rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        if rom.len() < HEADER_END {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+        let header = Header::parse(&rom)?;
+        Ok(Self { header, rom })
+    }
+
+    /// Check the logo, checksums and ROM size, returning every problem found
+    pub fn verify(&self) -> Vec<CartridgeError> {
+        let mut problems = Vec::new();
+        if self.rom[0x104..0x134] != NINTENDO_LOGO {
+            problems.push(CartridgeError::InvalidLogo);
+        }
+        let computed = self.compute_header_checksum();
+        if computed != self.header.header_checksum {
+            problems.push(CartridgeError::HeaderChecksum { expected: self.header.header_checksum, computed });
+        }
+        let computed = self.compute_global_checksum();
+        if computed != self.header.global_checksum {
+            problems.push(CartridgeError::GlobalChecksum { expected: self.header.global_checksum, computed });
+        }
+        if self.rom.len() != self.header.rom_size {
+            problems.push(CartridgeError::SizeMismatch { header: self.header.rom_size, actual: self.rom.len() });
+        }
+        problems
+    }
+
+    // x = x - byte - 1 over 0x0134-0x014C
+    fn compute_header_checksum(&self) -> u8 {
+        self.rom[0x134..0x14d].iter().fold(0u8, |sum, &byte| sum.wrapping_sub(byte).wrapping_sub(1))
+    }
+
+    // Sum of every byte of the ROM, except the global checksum itself
+    fn compute_global_checksum(&self) -> u16 {
+        self.rom
+            .iter()
+            .enumerate()
+            .filter(|&(address, _)| address != 0x14e && address != 0x14f)
+            .fold(0u16, |sum, (_, &byte)| sum.wrapping_add(byte as u16))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Valid 32 KiB ROM with an empty header, so the header checksum is 0 - 25 * 1
+    fn rom() -> Vec<u8> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x14d] = 0xe7;
+        set_global_checksum(&mut rom);
+        rom
+    }
+
+    fn set_global_checksum(rom: &mut [u8]) {
+        let sum = rom.iter().fold(0u16, |sum, &byte| sum.wrapping_add(byte as u16))
+            - rom[0x14e] as u16 - rom[0x14f] as u16;
+        rom[0x14e..0x150].copy_from_slice(&sum.to_be_bytes());
+    }
+
+    #[test]
+    fn valid_rom_has_no_problems() {
+        let cartridge = Cartridge::from_bytes(rom()).unwrap();
+        assert!(cartridge.verify().is_empty());
+        assert_eq!(cartridge.header.rom_size, 0x8000);
+        assert_eq!(cartridge.header.ram_size, 0);
+        assert_eq!(cartridge.header.cartridge_type_name(), "ROM ONLY");
+    }
+
+    #[test]
+    fn header_fields() {
+        let mut rom = rom();
+        rom[0x134..0x144].copy_from_slice(b"GAME TITLE\0\0ABCD");
+        rom[0x143] = 0x80;
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x146] = 0x03;
+        rom[0x147] = 0x13;
+        rom[0x148] = 0x05;
+        rom[0x149] = 0x03;
+        rom[0x14b] = 0x33;
+        let header = Cartridge::from_bytes(rom).unwrap().header;
+        // The manufacturer code and CGB flag are not part of the title on CGB cartridges
+        assert_eq!(header.title, "GAME TITLE");
+        assert_eq!(header.cgb, CgbSupport::Enhanced);
+        assert!(header.sgb);
+        assert!(header.has_battery());
+        assert_eq!(header.rom_size, 0x100000);
+        assert_eq!(header.ram_size, 0x8000);
+        assert_eq!(header.licensee, Licensee::New("01".to_string()));
+    }
+
+    #[test]
+    fn unreadable_header() {
+        assert!(matches!(Cartridge::from_bytes(vec![0; 0x14f]), Err(CartridgeError::TooSmall(0x14f))));
+        let mut rom = rom();
+        rom[0x148] = 0x09;
+        assert!(matches!(Cartridge::from_bytes(rom), Err(CartridgeError::UnknownRomSize(0x09))));
+        let mut rom = self::rom();
+        rom[0x149] = 0x06;
+        assert!(matches!(Cartridge::from_bytes(rom), Err(CartridgeError::UnknownRamSize(0x06))));
+    }
+
+    #[test]
+    fn header_checksum_mismatch_is_fatal() {
+        let mut rom = rom();
+        rom[0x134] = b'A';
+        set_global_checksum(&mut rom);
+        let problems = Cartridge::from_bytes(rom).unwrap().verify();
+        assert!(matches!(problems[..], [CartridgeError::HeaderChecksum { expected: 0xe7, computed: 0xa6 }]));
+        assert!(problems[0].is_fatal());
+    }
+
+    #[test]
+    fn global_checksum_mismatch_is_not_fatal() {
+        let mut rom = rom();
+        rom[0x4000] = 0x01;
+        let problems = Cartridge::from_bytes(rom).unwrap().verify();
+        assert!(matches!(problems[..], [CartridgeError::GlobalChecksum { .. }]));
+        assert!(!problems[0].is_fatal());
+    }
+
+    #[test]
+    fn logo_and_size_problems() {
+        let mut rom = rom();
+        rom[0x104] = 0;
+        rom.truncate(0x4000);
+        set_global_checksum(&mut rom);
+        let problems = Cartridge::from_bytes(rom).unwrap().verify();
+        assert!(matches!(problems[..], [
+            CartridgeError::InvalidLogo,
+            CartridgeError::SizeMismatch { header: 0x8000, actual: 0x4000 },
+        ]));
+        assert!(problems[0].is_fatal());
+        assert!(!problems[1].is_fatal());
+    }
+}
@@ -0,0 +1,59 @@
+//! CGB only registers, they read 0xFF and ignore writes on DMG and in DMG mode
+
+#[derive(Debug)]
+pub struct CgbRegisters {
+    pub key1: u8, // KEY1 (0xFF4D), bit 7 current speed, bit 0 speed switch armed
+    pub vbk: u8, // VRAM bank
+    pub svbk: u8, // WRAM bank
+    pub hdma: [u8; 5], // HDMA1-HDMA5
+    pub rp: u8, // Infrared port
+    pub bcps: u8, // Background palette index
+    pub ocps: u8, // Object palette index
+    pub bg_palettes: [u8; 64],
+    pub obj_palettes: [u8; 64],
+    pub opri: u8, // Object priority mode
+}
+
+impl CgbRegisters {
+    pub fn new() -> Self {
+        Self {
+            key1: 0,
+            vbk: 0,
+            svbk: 0,
+            hdma: [0xff; 5],
+            rp: 0,
+            bcps: 0,
+            ocps: 0,
+            bg_palettes: [0xff; 64],
+            obj_palettes: [0xff; 64],
+            opri: 0,
+        }
+    }
+
+    // Palette index registers auto increment after a data write when bit 7 is set
+    fn palette_index(index: &mut u8) -> usize {
+        let current = (*index & 0x3f) as usize;
+        if *index & 0x80 != 0 {
+            *index = 0x80 | ((*index + 1) & 0x3f);
+        }
+        current
+    }
+
+    pub fn read_bcpd(&self) -> u8 {
+        self.bg_palettes[(self.bcps & 0x3f) as usize]
+    }
+
+    pub fn write_bcpd(&mut self, value: u8) {
+        let index = Self::palette_index(&mut self.bcps);
+        self.bg_palettes[index] = value;
+    }
+
+    pub fn read_ocpd(&self) -> u8 {
+        self.obj_palettes[(self.ocps & 0x3f) as usize]
+    }
+
+    pub fn write_ocpd(&mut self, value: u8) {
+        let index = Self::palette_index(&mut self.ocps);
+        self.obj_palettes[index] = value;
+    }
+}
@@ -1,4 +1,7 @@
-use super::mmu::MMU;
+use super::bus::Bus;
+use super::dassm;
+use super::trace::{self, trace};
+use super::interrupts::{Interrupt, IE_ADDRESS, IF_ADDRESS};
 
 
 
@@ -25,7 +28,7 @@ impl From<u8> for REGISTER8 {
             4 => REGISTER8::H,
             5 => REGISTER8::L,
             6 => REGISTER8::HL,
-            7 => REGISTER8::B,
+            7 => REGISTER8::A,
             _ => REGISTER8::UD
         }
     }
@@ -95,36 +98,127 @@ impl From<u8> for REGISTER16STK {
     }
 }
 
-#[derive(Debug)]
-pub struct Flags {
-    pub zero: u8, //Zero flag
-    pub n: u8, //Subtraction flag (BCD)
-    pub h: u8, //Half carry flag (BCD)
-    pub carry: u8, //Carry flag
-}
+// Bit of each flag in the F register, the lower nibble is always zero
+const FLAG_ZERO: u8 = 0b10000000;
+const FLAG_N: u8 = 0b01000000;
+const FLAG_H: u8 = 0b00100000;
+const FLAG_CARRY: u8 = 0b00010000;
+
+/// The F register, holding the flags in its upper nibble
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Flags(u8);
 
 impl Flags {
-    fn new() -> Self {
-        Flags {
-            zero: 0,
-            n: 0,
-            h: 0,
-            carry: 0,
+    /// Build the flags from an F register value, the lower nibble is dropped
+    pub fn from_bits(value: u8) -> Self {
+        Flags(value & 0xf0)
+    }
+
+    /// Return the F register value
+    pub fn bits(self) -> u8 {
+        self.0
+    }
+
+    fn set(&mut self, flag: u8, value: bool) {
+        if value {
+            self.0 |= flag;
+        } else {
+            self.0 &= !flag;
         }
     }
 
-    fn get_cond(&self, cond: u8) -> u16 {
+    //Zero flag
+    pub fn zero(self) -> bool {
+        self.0 & FLAG_ZERO != 0
+    }
+    //Subtraction flag (BCD)
+    pub fn n(self) -> bool {
+        self.0 & FLAG_N != 0
+    }
+    //Half carry flag (BCD)
+    pub fn h(self) -> bool {
+        self.0 & FLAG_H != 0
+    }
+    //Carry flag
+    pub fn carry(self) -> bool {
+        self.0 & FLAG_CARRY != 0
+    }
+
+    pub fn set_zero(&mut self, value: bool) {
+        self.set(FLAG_ZERO, value);
+    }
+    pub fn set_n(&mut self, value: bool) {
+        self.set(FLAG_N, value);
+    }
+    pub fn set_h(&mut self, value: bool) {
+        self.set(FLAG_H, value);
+    }
+    pub fn set_carry(&mut self, value: bool) {
+        self.set(FLAG_CARRY, value);
+    }
+
+    // Evaluate a condition code: NZ, Z, NC, C
+    fn get_cond(self, cond: u8) -> bool {
         match cond {
-            0 => { (self.n as u16) << 8 | self.zero as u16  }
-            1 => { self.zero as u16 }
-            2 => { (self.n as u16) << 8 | self.carry as u16 }
-            3 => { self.carry as u16 }
+            0 => { !self.zero() },
+            1 => { self.zero() },
+            2 => { !self.carry() },
+            3 => { self.carry() },
             _ => { panic!("Invalid Condition for Flags!") }
-            
         }
     }
 }
 
+// Number of T-cycles taken by every unprefixed opcode. Conditional jumps, calls and returns list
+// the cost of the branch not being taken, see branch_taken_cycles for the taken cost. The 0xCB
+// prefix itself is accounted for in CB_OPCODE_CYCLES, and invalid opcodes are 0
+#[rustfmt::skip]
+pub const OPCODE_CYCLES: [u8; 256] = [
+//  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xA  xB  xC  xD  xE  xF
+     4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4, // 0x
+     4, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 1x
+     8, 12,  8,  8,  4,  4,  8,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 2x
+     8, 12,  8,  8, 12, 12, 12,  4,  8,  8,  8,  8,  4,  4,  8,  4, // 3x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 4x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 5x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 6x
+     8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4, // 7x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 8x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 9x
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Ax
+     4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // Bx
+     8, 12, 12, 16, 12, 16,  8, 16,  8, 16, 12,  0, 12, 24,  8, 16, // Cx
+     8, 12, 12,  0, 12, 16,  8, 16,  8, 16, 12,  0, 12,  0,  8, 16, // Dx
+    12, 12,  8,  0,  0, 16,  8, 16, 16,  4, 16,  0,  0,  0,  8, 16, // Ex
+    12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16,  4,  0,  0,  8, 16, // Fx
+];
+
+// Number of T-cycles taken by every CB-prefixed opcode, including the fetch of the 0xCB prefix.
+// Register operands take 8 cycles, [HL] takes 16 (read-modify-write), except BIT which only reads
+// [HL] and takes 12
+pub const CB_OPCODE_CYCLES: [u8; 256] = {
+    let mut table = [8; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        if opcode & 0b111 == 0b110 {
+            table[opcode] = if opcode >> 6 == 0b01 { 12 } else { 16 };
+        }
+        opcode += 1;
+    }
+    table
+};
+
+// Number of T-cycles taken by a conditional JR/JP/CALL/RET when the branch is taken
+pub const fn branch_taken_cycles(opcode: u8) -> u8 {
+    match opcode {
+        0x20 | 0x28 | 0x30 | 0x38 => 12, //JR cond
+        0xc0 | 0xc8 | 0xd0 | 0xd8 => 20, //RET cond
+        0xc2 | 0xca | 0xd2 | 0xda => 16, //JP cond
+        0xc4 | 0xcc | 0xd4 | 0xdc => 24, //CALL cond
+        _ => OPCODE_CYCLES[opcode as usize],
+    }
+}
+
 #[derive(Debug)]
 pub struct CPU {
     pub a: u8, //Upper bits of AF, the Accumulator
@@ -137,45 +231,120 @@ pub struct CPU {
     pub instr: u8, //Current instruction
     pub sp: u16, //Stack pointer
     pub pc: u16, //Program counter
-    pub byte2: u8,
-    pub byte3: u8,
     pub flags: Flags, //Lower bits of AF, Flags register
     pub ime: u8, // IME (Interrupt) flag
+    pub ime_scheduled: bool, // EI enables IME only after the instruction following it
+    pub halted: bool, // Set by HALT until an interrupt is pending
+    pub halt_bug: bool, // Set when HALT fails to increment PC past the next byte
+    pub stopped: bool, // Set by STOP until a joypad input
+    pub locked: bool, // Set by an invalid opcode, the CPU hangs until it is reset
+    pub cycles: u64, // T-cycles elapsed since power on
 }
 
 impl CPU {
+    // Registers start with the values left by the DMG boot ROM, since it is not emulated
     pub fn new() -> Self {
+        let flags = Flags::from_bits(FLAG_ZERO | FLAG_H | FLAG_CARRY);
         CPU {
-            a: 0,
-            b: 0,
-            c: 0,
-            d: 0,
-            e: 0,
-            h: 0,
-            l: 0,
+            a: 0x01,
+            b: 0x00,
+            c: 0x13,
+            d: 0x00,
+            e: 0xd8,
+            h: 0x01,
+            l: 0x4d,
             instr: 0,
-            sp: 0,
+            sp: 0xfffe,
             pc: 0x100,
-            byte2: 0,
-            byte3: 0,
-            flags: Flags::new(),
+            flags,
             ime: 0,
+            ime_scheduled: false,
+            halted: false,
+            halt_bug: false,
+            stopped: false,
+            locked: false,
+            cycles: 0,
         }
     }
 
-    fn dump(&self) {
-        println!("Instruction: 0x{:02X}, PC: 0x{:04X}, SP: 0x{:04X}", self.instr, self.pc, self.sp);
-        println!("[Optional] Byte 2 0x{:02X}, Byte 3: 0x{:02X}", self.byte2, self.byte3);
+    /// Return the F register
+    pub fn f(&self) -> u8 {
+        self.flags.bits()
     }
-    pub fn fetch(&mut self,  mmu: &mut MMU) {
-        println!("==========================");
-        self.instr = mmu.read_memory(self.pc);
-        let arg1 = mmu.read_memory(self.pc + 1);
-        let arg2 = mmu.read_memory(self.pc + 2);
-        self.byte2 = arg1;
-        self.byte3 = arg2;
-        self.dump();
-        //println!("0x{:02X?}", &self.rom_bank_0[self.pc as usize..(self.pc + 10) as usize]);
+
+    // Log the instruction just fetched, PC points to its operands
+    fn dump<B: Bus>(&self, bus: &mut B) {
+        if !trace::enabled(trace::Subsystem::Cpu, trace::Level::Debug) {
+            return;
+        }
+        let address = self.pc.wrapping_sub(1);
+        let bytes = [self.instr, bus.read(self.pc), bus.read(self.pc.wrapping_add(1))];
+        trace!(Cpu, Debug, "Instruction: 0x{:02X}, PC: 0x{:04X}, SP: 0x{:04X}", self.instr, address, self.sp);
+        trace!(Cpu, Debug, "{}", dassm::decode(&bytes, address));
+    }
+
+    /// Read the opcode at PC and move PC past it. Operands are read by the instruction itself, one
+    /// machine cycle each
+    pub fn fetch<B: Bus>(&mut self,  bus: &mut B) {
+        self.instr = self.read_cycle(self.pc, bus);
+        if self.halt_bug {
+            // The byte after HALT is read twice: PC fails to increment after the opcode fetch, so
+            // the opcode is also read as the first operand
+            self.halt_bug = false;
+        } else {
+            self.pc = self.pc.wrapping_add(1);
+        }
+        self.dump(bus);
+    }
+
+    // Spend a machine cycle, ticking the rest of the hardware by 4 T-cycles
+    fn cycle<B: Bus>(&mut self, bus: &mut B) {
+        bus.tick(4);
+        self.cycles += 4;
+    }
+
+    // Spend a machine cycle reading memory. The hardware is ticked first, so the read sees the
+    // state at the end of the cycle
+    fn read_cycle<B: Bus>(&mut self, address: u16, bus: &mut B) -> u8 {
+        self.cycle(bus);
+        bus.read(address)
+    }
+
+    // Spend a machine cycle writing memory
+    fn write_cycle<B: Bus>(&mut self, address: u16, value: u8, bus: &mut B) {
+        self.cycle(bus);
+        bus.write(address, value);
+    }
+
+    // Read the 8 bit operand at PC
+    fn imm8<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let value = self.read_cycle(self.pc, bus);
+        self.pc = self.pc.wrapping_add(1);
+        value
+    }
+
+    // Read the 16 bit little endian operand at PC
+    fn imm16<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let low = self.imm8(bus);
+        let high = self.imm8(bus);
+        (high as u16) << 8 | low as u16
+    }
+
+    // Push a 16 bit value, high byte first
+    fn push<B: Bus>(&mut self, value: u16, bus: &mut B) {
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, (value >> 8) as u8, bus);
+        self.sp = self.sp.wrapping_sub(1);
+        self.write_cycle(self.sp, (value & 0xff) as u8, bus);
+    }
+
+    // Pop a 16 bit value, low byte first
+    fn pop<B: Bus>(&mut self, bus: &mut B) -> u16 {
+        let low = self.read_cycle(self.sp, bus);
+        self.sp = self.sp.wrapping_add(1);
+        let high = self.read_cycle(self.sp, bus);
+        self.sp = self.sp.wrapping_add(1);
+        (high as u16) << 8 | low as u16
     }
 
 
@@ -185,7 +354,7 @@ impl CPU {
     ///
     /// * `register_code` - The code of the register to access
     ///
-    fn get_r8_register(&mut self, register_code: REGISTER8, mmu: &mut MMU) -> u8 {
+    fn get_r8_register<B: Bus>(&mut self, register_code: REGISTER8, bus: &mut B) -> u8 {
         match register_code {
             REGISTER8::B => { self.b },
             REGISTER8::C => { self.c },
@@ -193,7 +362,7 @@ impl CPU {
             REGISTER8::E => { self.e },
             REGISTER8::H => { self.h },
             REGISTER8::L => { self.l },
-            REGISTER8::HL=> { let memory_address = self.get_r16_register(REGISTER16::HL); mmu.read_memory(memory_address) }, //[HL]
+            REGISTER8::HL=> { let memory_address = self.get_r16_register(REGISTER16::HL); self.read_cycle(memory_address, bus) }, //[HL]
             REGISTER8::A => { self.a },
             _ => { panic!("Cannot get register code: Unknown register code {:?}", register_code) }
 
@@ -207,7 +376,7 @@ impl CPU {
     /// * `register_code` - The register to change
     /// * `value` - New value of register
     ///
-    fn set_r8_register(&mut self, register_code: REGISTER8, value: u8, mmu: &mut MMU) {
+    fn set_r8_register<B: Bus>(&mut self, register_code: REGISTER8, value: u8, bus: &mut B) {
         match register_code {
             REGISTER8::B => { self.b = value },
             REGISTER8::C => { self.c = value },
@@ -215,7 +384,7 @@ impl CPU {
             REGISTER8::E => { self.e = value },
             REGISTER8::H => { self.h = value },
             REGISTER8::L => { self.l = value },
-            REGISTER8::HL => { let memory_address = self.get_r16_register(REGISTER16::HL); mmu.write_memory(memory_address, value) }, //[HL]
+            REGISTER8::HL => { let memory_address = self.get_r16_register(REGISTER16::HL); self.write_cycle(memory_address, value, bus) }, //[HL]
             REGISTER8::A => { self.a = value },
             _ => { panic!("Cannot get register code: Unknown register code {:?}", register_code) }
 
@@ -282,12 +451,7 @@ impl CPU {
             REGISTER16STK::BC => { (self.b as u16) << 8 | self.c as u16 },
             REGISTER16STK::DE => {  (self.d as u16) << 8 | self.e as u16 },
             REGISTER16STK::HL => { (self.h as u16) << 8 | self.l as u16 },
-            REGISTER16STK::AF => {
-                // We do this because in reality, the flags are just a single register F, and the bit shift
-                // corresponds to the flag position in the register byte
-                let f = self.flags.zero << 7 | self.flags.n << 6 | self.flags.h << 5 | self.flags.carry << 4;
-                (self.a as u16) << 8 | f as u16
-            },
+            REGISTER16STK::AF => { (self.a as u16) << 8 | self.f() as u16 },
             _ => { panic!("Cannot get register address: Unknown register code {:?}", register_code) }
         }
     }
@@ -302,292 +466,339 @@ impl CPU {
             REGISTER16STK::DE => {  self.d = high; self.e = low; },
             REGISTER16STK::HL => { self.h = high; self.l = low; },
             REGISTER16STK::AF => {
-                // We do this because in reality, the flags are just a single register F, and the bit shift
-                // corresponds to the flag position in the register byte
+                // The lower nibble of F does not exist, it always reads back as zero
                 self.a = high;
-                self.flags.zero = low & 0b10000000;
-                self.flags.n = low & 0b01000000;
-                self.flags.h = low & 0b00100000;
-                self.flags.carry = low & 0b00010000;
+                self.flags = Flags::from_bits(low);
             },
             _ => { panic!("Cannot get register address: Unknown register code {:?}", register_code) }
         }
     }
    
-    // Return the half-carry and carry of adding two u8s
-    fn check_carry_add_u8(&self, lhs: u8, rhs: u8) -> (bool, bool) {
-        let (_, carry) = lhs.overflowing_add(rhs);
-        let half_carry = ((lhs & 0xf).wrapping_add(rhs & 0xf)) & 0x10 == 0x10;
-        return (half_carry, carry);
-    }
-    // Return the half-carry and carry of adding two i8s
-    fn check_carry_add_i8(&self, lhs: i8, rhs: i8) -> (bool, bool) {
-        let (_, carry) = lhs.overflowing_add(rhs);
-        let half_carry = ((lhs & 0xf).wrapping_add(rhs & 0xf)) & 0x10 == 0x10;
-        return (half_carry, carry);
-    }
-    // Return the half-carry and carry of subtracting two u8s
-    fn check_carry_sub_u8(&self, lhs: u8, rhs: u8) -> (bool, bool) {
-        let (_, carry) = lhs.overflowing_sub(rhs);
-        let half_carry = ((lhs & 0xf).wrapping_sub(rhs & 0xf)) & 0x10 == 0x10; //NOTE: This might need a rework
-        return (half_carry, carry);
-    }
-
-    pub fn execute_cb(&mut self, mmu: &mut MMU) {
-        println!("CB-Prefix: 0x{:02X}",  self.instr);
-        let oct1 = (self.instr & 0b11000000) >> 6;
-        let oct2 = (self.instr & 0b00111000) >> 3;
-        let oct3 = self.instr & 0b00000111;
-        println!("Octets: 0b{:03b} 0b{:03b} 0b{:03b}", oct1, oct2, oct3);
+    // Return the half-carry (bit 3 to 4) and carry (bit 7 to 8) of lhs + rhs + carry_in
+    fn check_carry_add_u8(&self, lhs: u8, rhs: u8, carry_in: u8) -> (bool, bool) {
+        let half_carry = (lhs & 0xf) + (rhs & 0xf) + carry_in > 0xf;
+        let carry = lhs as u16 + rhs as u16 + carry_in as u16 > 0xff;
+        (half_carry, carry)
+    }
+    // Return the half-carry and carry of adding a signed immediate to SP. The flags are computed
+    // from the unsigned addition of the lower byte of SP and the immediate
+    fn check_carry_add_sp(&self, imm8: u8) -> (bool, bool) {
+        self.check_carry_add_u8((self.sp & 0xff) as u8, imm8, 0)
+    }
+    // Return the half-borrow (from bit 4) and borrow of lhs - rhs - carry_in
+    fn check_carry_sub_u8(&self, lhs: u8, rhs: u8, carry_in: u8) -> (bool, bool) {
+        let half_carry = (lhs & 0xf) < (rhs & 0xf) + carry_in;
+        let carry = (lhs as u16) < rhs as u16 + carry_in as u16;
+        (half_carry, carry)
+    }
+
+    /// Execute a CB-prefixed instruction, reading its opcode after the 0xCB prefix
+    fn execute_cb<B: Bus>(&mut self, bus: &mut B) {
+        let opcode = self.imm8(bus);
+        trace!(Cpu, Trace, "CB-Prefix: 0x{:02X}",  opcode);
+        let oct1 = (opcode & 0b11000000) >> 6;
+        let oct2 = (opcode & 0b00111000) >> 3;
+        let oct3 = opcode & 0b00000111;
+        trace!(Cpu, Trace, "Octets: 0b{:03b} 0b{:03b} 0b{:03b}", oct1, oct2, oct3);
 
         match (oct1, oct2, oct3) {
-            (0b00, opcode, r8) => { self.shift_rotate(opcode, r8, mmu)  },
-            (0b01, bit, r8) => { self.bit(bit, r8, mmu) },
-            (0b10, bit, r8) => { self.res(bit, r8, mmu) },
-            (0b11, bit, r8) => { self.set(bit, r8, mmu) },
-            _ => { println!("CB-Prefix Error: 0x{:02X} not implemented!", self.instr); std::process::exit(1); },
+            (0b00, opcode, r8) => { self.shift_rotate(opcode, r8, bus)  },
+            (0b01, bit, r8) => { self.bit(bit, r8, bus) },
+            (0b10, bit, r8) => { self.res(bit, r8, bus) },
+            (0b11, bit, r8) => { self.set(bit, r8, bus) },
+            _ => unreachable!("Every CB-prefixed opcode is defined"),
         }
     }
 
-    pub fn execute(&mut self, mmu: &mut MMU) {
-        // println!("{:b}",  self.instr);
+    /// Execute the fetched instruction, ticking the bus on every machine cycle. The cycles taken
+    /// are added to the running cycle counter
+    pub fn execute<B: Bus>(&mut self, bus: &mut B) {
         // Handle CB-prefixed instructions
         if self.instr == 0xcb {
-            // Move ahead from 0xcb
-            self.pc += 1;
-            self.execute_cb(mmu);
+            self.execute_cb(bus);
             return;
         }
         let oct1 = (self.instr & 0b11000000) >> 6;
         let oct2 = (self.instr & 0b00111000) >> 3;
         let oct3 = self.instr & 0b00000111;
-         println!("Octets: 0b{:03b} 0b{:03b} 0b{:03b}", oct1, oct2, oct3);
+        trace!(Cpu, Trace, "Octets: 0b{:03b} 0b{:03b} 0b{:03b}", oct1, oct2, oct3);
 
         match (oct1, oct2, oct3) {
-            (0b00, 0b000, 0b000) => { println!("NOOP"); self.noop() }, //noop
-            (0b00, 0b001, 0b000) => { println!("ld_u16_sp"); self.ld_u16_sp(mmu) }, //LD (u16), SP
-            (0b00, 0b010, 0b000) => { println!("STOP"); panic!("STOPPING!") }, //STOP
-            (0b00, 0b011, 0b000) => { println!("jr"); self.jr() }, //JR
-            (0b00, 0b100..=0b111, 0b000) => { println!("jr_cond"); self.jr_cond(oct2) }, //JR conditonal
-            (0b00,0b000|0b010|0b100|0b110, 0b001) => { println!("ld_r16_u16"); self.ld_r16_u16(oct2 >> 1) }, //LD r16, u16
-            (0b00,0b001|0b011|0b101|0b111, 0b001) => { println!("add_hl_r16"); self.add_hl_r16(oct2 >> 1) }, //ADD HL, r16
-            (0b00,0b000|0b010|0b100|0b110, 0b010) => { println!("ld_r16_addr_a"); self.ld_r16_addr_a(oct2 >> 1, mmu) }, //LD (r16), A
-            (0b00,0b001|0b011|0b101|0b111, 0b010) => { println!("ld_a_r16_addr"); self.ld_a_r16_addr(oct2 >> 1, mmu) }, //LD A, (r16)
-            (0b00,0b000|0b010|0b100|0b110, 0b011) => { println!("inc_r16"); self.inc_r16(oct2 >> 1, mmu) }, //INC r16
-            (0b00,0b001|0b011|0b101|0b111, 0b011) => { println!("dec_r16"); self.dec_r16(oct2 >> 1, mmu) }, //DEC r16
-            (0b00, r8, 0b100) => { println!("inc_r8"); self.inc_r8(r8, mmu) }, //INC r8
-            (0b00, r8, 0b101) => { println!("dec_r8"); self.dec_r8(r8, mmu) }, //DEC r8
-            (0b00, r8, 0b110) => { println!("ld_r8_n8"); self.ld_r8_n8(r8, mmu) }, //LD r8, u8
-            (0b00, opcode, 0b111) => { println!("special_opcodes"); self.special_opcodes(opcode, mmu) }, //Opcode grp 1
-            (0b01, 0b110, 0b110) => { println!("halt"); self.halt() }, //HALT
-            (0b01, dst_r8, src_r8) => { println!("ld_r8_r8"); self.ld_r8_r8(src_r8, dst_r8, mmu) }, //LD r8, r8
-            (0b10, op, r8) => { println!("alu_a_r8"); self.alu_a_r8(op, r8, mmu);  }, //ALU A, r8
-            (0b11, 0b000..=0b011, 0b000) => { println!("ret_cond"); self.ret_cond(oct2, mmu) }, //RET condition
-            (0b11, 0b100, 0b000) => { println!("ldh_i16_a"); self.ldh_i16_a(mmu) }, //LD (FF00 + u8), A
-            (0b11, 0b101, 0b000) => { println!("add_sp_i8"); self.add_sp_i8() }, //ADD SP, i8
-            (0b11, 0b110, 0b000) => { println!("ldh_a_i16"); self.ldh_a_i16(mmu) }, //LD A, (FF00 + u8)
-            (0b11, 0b111, 0b000) => { println!("ld_hl_sp_imm8"); self.ld_hl_sp_imm8() }, //LD HL, SP + i8
-            (0b11, 0b000|0b010|0b100|0b110, 0b001) => { println!("pop_r16"); self.pop_r16(oct2 >> 1, mmu) }, //POP r16
-            (0b11, 0b001, 0b001) => { println!("ret"); self.ret(mmu) }, // RET
-            (0b11, 0b011, 0b001) => { println!("reti"); self.reti(mmu) }, // RETI
-            (0b11, 0b101, 0b001) => { println!("jp_hl"); self.jp_hl() }, // JP HL
-            (0b11, 0b111, 0b001) => { println!("ld_sp_hl"); self.ld_sp_hl() }, // LD SP, HL
-            (0b11, 0b000..=0b011, 0b010) => { println!("jp_cond"); self.jp_cond(oct2) }, //JP
-            (0b11, 0b100, 0b010) => { println!("ldh_c_a"); self.ldh_c_a(mmu) }, //LD (FF00 + C), A
-            (0b11, 0b101, 0b010) => { println!("ld_n16_a"); self.ld_n16_a(mmu) }, //LD (u16), A
-            (0b11, 0b110, 0b010) => { println!("ldh_a_c"); self.ldh_a_c(mmu) }, //LD A, (FF00 + C)
-            (0b11, 0b111, 0b010) => { println!("ld_a_n16"); self.ld_a_n16(mmu) }, //LD A, (u16)
-            (0b11, 0b000, 0b011) => { println!("jp_u16"); self.jp_u16() }, //JP u16
-            (0b11, 0b001, 0b011) => {  }, //CB prefix
-            (0b11, 0b110, 0b011) => { println!("di"); self.di() }, //DI
-            (0b11, 0b111, 0b011) => { println!("ei"); self.ei() }, //EI
-            (0b11, 0b000..=0b011, 0b100) => { println!("call_cond"); self.call_cond(oct2, mmu) }, //CALL condition
-            (0b11, 0b000|0b010|0b100|0b110, 0b101) => { println!("push_r16"); self.push_r16(oct2 >> 1, mmu) }, //PUSH r16
-            (0b11, 0b001, 0b101) => { println!("call"); self.call(mmu) }, //CALL u16
-            (0b11, opcode, 0b110) => { println!("alu_a_u8"); self.alu_a_u8(opcode) }, //ALU a, u8
-            (0b11, tgt, 0b111) => { println!("rst"); self.rst(tgt, mmu) }, //RST
-            _ => { println!("Error: 0x{:02X} not implemented!", self.instr); std::process::exit(1); },
+            (0b00, 0b000, 0b000) => { trace!(Cpu, Trace, "NOOP"); self.noop() }, //noop
+            (0b00, 0b001, 0b000) => { trace!(Cpu, Trace, "ld_u16_sp"); self.ld_u16_sp(bus) }, //LD (u16), SP
+            (0b00, 0b010, 0b000) => { trace!(Cpu, Trace, "STOP"); self.stop(bus) }, //STOP
+            (0b00, 0b011, 0b000) => { trace!(Cpu, Trace, "jr"); self.jr(bus) }, //JR
+            (0b00, 0b100..=0b111, 0b000) => { trace!(Cpu, Trace, "jr_cond"); self.jr_cond(oct2, bus) }, //JR conditonal
+            (0b00,0b000|0b010|0b100|0b110, 0b001) => { trace!(Cpu, Trace, "ld_r16_u16"); self.ld_r16_u16(oct2 >> 1, bus) }, //LD r16, u16
+            (0b00,0b001|0b011|0b101|0b111, 0b001) => { trace!(Cpu, Trace, "add_hl_r16"); self.add_hl_r16(oct2 >> 1, bus) }, //ADD HL, r16
+            (0b00,0b000|0b010|0b100|0b110, 0b010) => { trace!(Cpu, Trace, "ld_r16_addr_a"); self.ld_r16_addr_a(oct2 >> 1, bus) }, //LD (r16), A
+            (0b00,0b001|0b011|0b101|0b111, 0b010) => { trace!(Cpu, Trace, "ld_a_r16_addr"); self.ld_a_r16_addr(oct2 >> 1, bus) }, //LD A, (r16)
+            (0b00,0b000|0b010|0b100|0b110, 0b011) => { trace!(Cpu, Trace, "inc_r16"); self.inc_r16(oct2 >> 1, bus) }, //INC r16
+            (0b00,0b001|0b011|0b101|0b111, 0b011) => { trace!(Cpu, Trace, "dec_r16"); self.dec_r16(oct2 >> 1, bus) }, //DEC r16
+            (0b00, r8, 0b100) => { trace!(Cpu, Trace, "inc_r8"); self.inc_r8(r8, bus) }, //INC r8
+            (0b00, r8, 0b101) => { trace!(Cpu, Trace, "dec_r8"); self.dec_r8(r8, bus) }, //DEC r8
+            (0b00, r8, 0b110) => { trace!(Cpu, Trace, "ld_r8_n8"); self.ld_r8_n8(r8, bus) }, //LD r8, u8
+            (0b00, opcode, 0b111) => { trace!(Cpu, Trace, "special_opcodes"); self.special_opcodes(opcode, bus) }, //Opcode grp 1
+            (0b01, 0b110, 0b110) => { trace!(Cpu, Trace, "halt"); self.halt(bus) }, //HALT
+            (0b01, dst_r8, src_r8) => { trace!(Cpu, Trace, "ld_r8_r8"); self.ld_r8_r8(src_r8, dst_r8, bus) }, //LD r8, r8
+            (0b10, op, r8) => { trace!(Cpu, Trace, "alu_a_r8"); self.alu_a_r8(op, r8, bus);  }, //ALU A, r8
+            (0b11, 0b000..=0b011, 0b000) => { trace!(Cpu, Trace, "ret_cond"); self.ret_cond(oct2, bus) }, //RET condition
+            (0b11, 0b100, 0b000) => { trace!(Cpu, Trace, "ldh_i16_a"); self.ldh_i16_a(bus) }, //LD (FF00 + u8), A
+            (0b11, 0b101, 0b000) => { trace!(Cpu, Trace, "add_sp_i8"); self.add_sp_i8(bus) }, //ADD SP, i8
+            (0b11, 0b110, 0b000) => { trace!(Cpu, Trace, "ldh_a_i16"); self.ldh_a_i16(bus) }, //LD A, (FF00 + u8)
+            (0b11, 0b111, 0b000) => { trace!(Cpu, Trace, "ld_hl_sp_imm8"); self.ld_hl_sp_imm8(bus) }, //LD HL, SP + i8
+            (0b11, 0b000|0b010|0b100|0b110, 0b001) => { trace!(Cpu, Trace, "pop_r16"); self.pop_r16(oct2 >> 1, bus) }, //POP r16
+            (0b11, 0b001, 0b001) => { trace!(Cpu, Trace, "ret"); self.ret(bus) }, // RET
+            (0b11, 0b011, 0b001) => { trace!(Cpu, Trace, "reti"); self.reti(bus) }, // RETI
+            (0b11, 0b101, 0b001) => { trace!(Cpu, Trace, "jp_hl"); self.jp_hl() }, // JP HL
+            (0b11, 0b111, 0b001) => { trace!(Cpu, Trace, "ld_sp_hl"); self.ld_sp_hl(bus) }, // LD SP, HL
+            (0b11, 0b000..=0b011, 0b010) => { trace!(Cpu, Trace, "jp_cond"); self.jp_cond(oct2, bus) }, //JP
+            (0b11, 0b100, 0b010) => { trace!(Cpu, Trace, "ldh_c_a"); self.ldh_c_a(bus) }, //LD (FF00 + C), A
+            (0b11, 0b101, 0b010) => { trace!(Cpu, Trace, "ld_n16_a"); self.ld_n16_a(bus) }, //LD (u16), A
+            (0b11, 0b110, 0b010) => { trace!(Cpu, Trace, "ldh_a_c"); self.ldh_a_c(bus) }, //LD A, (FF00 + C)
+            (0b11, 0b111, 0b010) => { trace!(Cpu, Trace, "ld_a_n16"); self.ld_a_n16(bus) }, //LD A, (u16)
+            (0b11, 0b000, 0b011) => { trace!(Cpu, Trace, "jp_u16"); self.jp_u16(bus) }, //JP u16
+            (0b11, 0b110, 0b011) => { trace!(Cpu, Trace, "di"); self.di() }, //DI
+            (0b11, 0b111, 0b011) => { trace!(Cpu, Trace, "ei"); self.ei() }, //EI
+            (0b11, 0b000..=0b011, 0b100) => { trace!(Cpu, Trace, "call_cond"); self.call_cond(oct2, bus) }, //CALL condition
+            (0b11, 0b000|0b010|0b100|0b110, 0b101) => { trace!(Cpu, Trace, "push_r16"); self.push_r16(oct2 >> 1, bus) }, //PUSH r16
+            (0b11, 0b001, 0b101) => { trace!(Cpu, Trace, "call"); self.call(bus) }, //CALL u16
+            (0b11, opcode, 0b110) => { trace!(Cpu, Trace, "alu_a_u8"); self.alu_a_u8(opcode, bus) }, //ALU a, u8
+            (0b11, tgt, 0b111) => { trace!(Cpu, Trace, "rst"); self.rst(tgt, bus) }, //RST
+            _ => { trace!(Cpu, Error, "Error: 0x{:02X} is not a valid opcode!", self.instr); self.locked = true },
         }
     }
 
-    // Move PC by inc, usually 1
-    fn next(&mut self, inc: u16) {
-        self.pc += inc;
+    /// Service a pending interrupt, or fetch and execute a single instruction. The bus is ticked
+    /// on every machine cycle, so the rest of the hardware sees memory accesses when they happen
+    /// Returns the number of T-cycles taken
+    pub fn step<B: Bus>(&mut self, bus: &mut B) -> u8 {
+        let start = self.cycles;
+        self.advance(bus);
+        (self.cycles - start) as u8
     }
 
-    fn noop(&mut self) {
-        self.next(1);
+    fn advance<B: Bus>(&mut self, bus: &mut B) {
+        if self.locked {
+            return self.cycle(bus);
+        }
+        if self.stopped {
+            // Only a joypad input can exit STOP mode
+            if bus.read(IF_ADDRESS) & Interrupt::Joypad.mask() == 0 {
+                return self.cycle(bus);
+            }
+            self.stopped = false;
+        }
+        if self.halted {
+            // Wake up as soon as an interrupt is pending, it is only serviced if IME is set
+            if self.pending_interrupts(bus) == 0 {
+                return self.cycle(bus);
+            }
+            self.halted = false;
+        }
+        if self.handle_interrupts(bus) {
+            return;
+        }
+        // EI takes effect once the instruction after it has executed
+        let enable_ime = self.ime_scheduled;
+        self.fetch(bus);
+        self.execute(bus);
+        if enable_ime && self.ime_scheduled {
+            self.ime = 1;
+            self.ime_scheduled = false;
+        }
     }
 
+    /// Whether the next `step` fetches and executes the instruction at PC, rather than idling while
+    /// locked up, stopped or halted, or dispatching an interrupt. Follows the checks of `advance`
+    pub fn executes_instruction<B: Bus>(&self, bus: &mut B) -> bool {
+        let pending = self.pending_interrupts(bus);
+        if self.locked
+            || (self.stopped && bus.read(IF_ADDRESS) & Interrupt::Joypad.mask() == 0)
+            || (self.halted && pending == 0)
+        {
+            return false;
+        }
+        self.ime == 0 || pending == 0
+    }
+
+    // Interrupts that are both enabled and requested, regardless of IME
+    fn pending_interrupts<B: Bus>(&self, bus: &mut B) -> u8 {
+        bus.read(IE_ADDRESS) & bus.read(IF_ADDRESS) & 0x1f
+    }
+
+    /// Dispatch the highest priority interrupt that is both enabled (IE) and requested (IF)
+    /// if IME is set. The IF bit is cleared, IME is disabled, PC is pushed and the CPU jumps to the
+    /// interrupt vector
+    /// Returns whether an interrupt was serviced
+    fn handle_interrupts<B: Bus>(&mut self, bus: &mut B) -> bool {
+        if self.ime == 0 {
+            return false;
+        }
+        let requested = bus.read(IF_ADDRESS);
+        let Some(interrupt) = Interrupt::highest_priority(self.pending_interrupts(bus)) else {
+            return false;
+        };
+        bus.write(IF_ADDRESS, requested & !interrupt.mask());
+        self.ime = 0;
+        self.ime_scheduled = false;
+        // 2 wait states, 2 cycles to push PC and 1 to jump
+        self.cycle(bus);
+        self.cycle(bus);
+        self.push(self.pc, bus);
+        self.pc = interrupt.vector();
+        self.cycle(bus);
+        true
+    }
+
+    fn noop(&mut self) {}
+
     // Set nth bit to zero in r8
-    fn res(&mut self, bit: u8, r8: u8, mmu: &mut MMU) {
-        let mut reg = self.get_r8_register(r8.into(), mmu);
-        reg = !(1 << bit) & reg;
-        self.set_r8_register(r8.into(), reg, mmu);
+    fn res<B: Bus>(&mut self, bit: u8, r8: u8, bus: &mut B) {
+        let mut reg = self.get_r8_register(r8.into(), bus);
+        reg &= !(1 << bit);
+        self.set_r8_register(r8.into(), reg, bus);
     }
 
     // Set nth bit in r8
-    fn set(&mut self, bit: u8, r8: u8, mmu: &mut MMU) {
-        let mut reg = self.get_r8_register(r8.into(), mmu);
-        reg = (1 << bit) | reg;
-        self.set_r8_register(r8.into(), reg, mmu);
+    fn set<B: Bus>(&mut self, bit: u8, r8: u8, bus: &mut B) {
+        let mut reg = self.get_r8_register(r8.into(), bus);
+        reg |= 1 << bit ;
+        self.set_r8_register(r8.into(), reg, bus);
     }
 
     // Test for nth bit value in r8
-    fn bit(&mut self, bit: u8, r8: u8, mmu: &mut MMU) {
-        let reg = self.get_r8_register(r8.into(), mmu);
+    fn bit<B: Bus>(&mut self, bit: u8, r8: u8, bus: &mut B) {
+        let reg = self.get_r8_register(r8.into(), bus);
         // Check nth bit of reg, and if it is zero, set zero flag
-        if ((1 << bit) & reg) >> bit == 0 {
-            self.flags.zero = 1;
-        }
-        self.flags.n = 0;
-        self.flags.h = 1;
+        self.flags.set_zero(((1 << bit) & reg) == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(true);
     }
 
-    fn shift_rotate(&mut self, opcode: u8, r8: u8, mmu: &mut MMU) {
+    fn shift_rotate<B: Bus>(&mut self, opcode: u8, r8: u8, bus: &mut B) {
         match opcode {
-            0b000 => { self.rlc_r8(r8, mmu) },
-            0b001 => { self.rrc_r8(r8, mmu) },
-            0b010 => { self.rl_r8(r8, mmu) },
-            0b011 => { self.rr_r8(r8, mmu) },
-            0b100 => { self.sla_r8(r8, mmu) },
-            0b101 => { self.sra_r8(r8, mmu) },
-            0b110 => { self.swap_r8(r8, mmu) },
-            0b111 => { self.srl_r8(r8, mmu) },
+            0b000 => { self.rlc_r8(r8, bus) },
+            0b001 => { self.rrc_r8(r8, bus) },
+            0b010 => { self.rl_r8(r8, bus) },
+            0b011 => { self.rr_r8(r8, bus) },
+            0b100 => { self.sla_r8(r8, bus) },
+            0b101 => { self.sra_r8(r8, bus) },
+            0b110 => { self.swap_r8(r8, bus) },
+            0b111 => { self.srl_r8(r8, bus) },
             _ => { panic!("Invalid opcode for shift_rotate: {}", opcode) }
         }
     }
 
 
     // Swap upper 4 bits of r8 with lower 4 bits
-    fn swap_r8(&mut self, r8: u8, mmu: &mut MMU) {
-        let mut reg = self.get_r8_register(r8.into(), mmu);
-        let high = (reg >> 4) as u8;
-        let low = (reg & 0x0f) as u8;
+    fn swap_r8<B: Bus>(&mut self, r8: u8, bus: &mut B) {
+        let mut reg = self.get_r8_register(r8.into(), bus);
+        let high = reg >> 4 ;
+        let low = reg & 0x0f ;
         reg = (low << 4) | high;
-        if reg == 0 {
-            self.flags.zero = reg;
-        }
-        self.flags.n = 0;
-        self.flags.h = 0;
-        self.flags.carry = 0;
-        self.set_r8_register(r8.into(), reg, mmu);
+        self.flags.set_zero(reg == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.flags.set_carry(false);
+        self.set_r8_register(r8.into(), reg, bus);
     }
 
     // Shift r8 right logically
-    fn srl_r8(&mut self, r8: u8, mmu: &mut MMU) {
-        let mut reg = self.get_r8_register(r8.into(), mmu);
-        self.flags.carry = reg & 0b1;
-        reg  = reg >> 1;
-        if reg == 0 {
-            self.flags.zero = 1;
-        }
-        self.flags.n = 0;
-        self.flags.h = 0;
-        self.set_r8_register(r8.into(), reg, mmu);
+    fn srl_r8<B: Bus>(&mut self, r8: u8, bus: &mut B) {
+        let mut reg = self.get_r8_register(r8.into(), bus);
+        self.flags.set_carry(reg & 0b1 != 0);
+        reg >>= 1;
+        self.flags.set_zero(reg == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.set_r8_register(r8.into(), reg, bus);
     }
 
     // Shift r8 right 
-    fn sra_r8(&mut self, r8: u8, mmu: &mut MMU) {
-        let mut reg = self.get_r8_register(r8.into(), mmu);
-        self.flags.carry = reg & 0b1;
+    fn sra_r8<B: Bus>(&mut self, r8: u8, bus: &mut B) {
+        let mut reg = self.get_r8_register(r8.into(), bus);
+        self.flags.set_carry(reg & 0b1 != 0);
         // Think of this as signed division by 2
         let bit = reg >> 7;
         reg  = (reg >> 1) | (bit << 7);
-        if reg == 0 {
-            self.flags.zero = 1;
-        }
-        self.flags.n = 0;
-        self.flags.h = 0;
-        self.set_r8_register(r8.into(), reg, mmu);
+        self.flags.set_zero(reg == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.set_r8_register(r8.into(), reg, bus);
     }
 
     // Rotate register r8 right through carry flag (wrapping)
-    fn rr_r8(&mut self, r8: u8, mmu: &mut MMU) {
-        let mut reg = self.get_r8_register(r8.into(), mmu);
-        let carry = self.flags.carry;
-        self.flags.carry = reg & 0b1;
+    fn rr_r8<B: Bus>(&mut self, r8: u8, bus: &mut B) {
+        let mut reg = self.get_r8_register(r8.into(), bus);
+        let carry = self.flags.carry() as u8;
+        self.flags.set_carry(reg & 0b1 != 0);
         reg  = (reg >> 1) | (carry << 7);
-        if reg == 0 {
-            self.flags.zero = 1;
-        }
-        self.flags.n = 0;
-        self.flags.h = 0;
-        self.set_r8_register(r8.into(), reg, mmu);
+        self.flags.set_zero(reg == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.set_r8_register(r8.into(), reg, bus);
     }
 
     // Rotate register r8 right
-    fn rrc_r8(&mut self, r8: u8, mmu: &mut MMU) {
-        let mut reg = self.get_r8_register(r8.into(), mmu);
-        self.flags.carry = reg & 0b1;
+    fn rrc_r8<B: Bus>(&mut self, r8: u8, bus: &mut B) {
+        let mut reg = self.get_r8_register(r8.into(), bus);
+        self.flags.set_carry(reg & 0b1 != 0);
         // Move bit 0 to bit 7, since bit carry is bit 0
-        reg  = (reg >> 1) | (self.flags.carry << 7);
-        if reg == 0 {
-            self.flags.zero = 1;
-        }
-        self.flags.n = 0;
-        self.flags.h = 0;
-        self.set_r8_register(r8.into(), reg, mmu);
+        reg  = (reg >> 1) | ((self.flags.carry() as u8) << 7);
+        self.flags.set_zero(reg == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.set_r8_register(r8.into(), reg, bus);
     }
 
     // Shift r8 left
-    fn sla_r8(&mut self, r8: u8, mmu: &mut MMU) {
-        let mut reg = self.get_r8_register(r8.into(), mmu);
-        self.flags.carry = reg >> 7;
-        reg = reg << 1;
-        if reg == 0 {
-            self.flags.zero = 1;
-        }
-        self.flags.n = 0;
-        self.flags.h = 0;
-        self.set_r8_register(r8.into(), reg, mmu);
+    fn sla_r8<B: Bus>(&mut self, r8: u8, bus: &mut B) {
+        let mut reg = self.get_r8_register(r8.into(), bus);
+        self.flags.set_carry(reg >> 7 != 0);
+        reg <<= 1;
+        self.flags.set_zero(reg == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.set_r8_register(r8.into(), reg, bus);
     }
 
     // Rotate register r8 left through carry flag (wrapping)
-    fn rl_r8(&mut self, r8: u8, mmu: &mut MMU) {
-        let mut reg = self.get_r8_register(r8.into(), mmu);
-        let carry = self.flags.carry;
-        self.flags.carry = reg >> 7;
+    fn rl_r8<B: Bus>(&mut self, r8: u8, bus: &mut B) {
+        let mut reg = self.get_r8_register(r8.into(), bus);
+        let carry = self.flags.carry() as u8;
+        self.flags.set_carry(reg >> 7 != 0);
         reg = (reg << 1) | carry;
-        if reg == 0 {
-            self.flags.zero = 1;
-        }
-        self.flags.n = 0;
-        self.flags.h = 0;
-        self.set_r8_register(r8.into(), reg, mmu);
+        self.flags.set_zero(reg == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.set_r8_register(r8.into(), reg, bus);
     }
 
     // Rotate register r8 left
-    fn rlc_r8(&mut self, r8: u8, mmu: &mut MMU) {
-        let mut reg = self.get_r8_register(r8.into(), mmu);
-        self.flags.carry = reg >> 7;
+    fn rlc_r8<B: Bus>(&mut self, r8: u8, bus: &mut B) {
+        let mut reg = self.get_r8_register(r8.into(), bus);
+        self.flags.set_carry(reg >> 7 != 0);
         // Move bit 7 to bit 0, since carry is now bit7
-        reg = (reg << 1) | self.flags.carry;
-        if reg == 0 {
-            self.flags.zero = 1;
-        }
-        self.flags.n = 0;
-        self.flags.h = 0;
-        self.set_r8_register(r8.into(), reg, mmu);
+        reg = (reg << 1) | self.flags.carry() as u8;
+        self.flags.set_zero(reg == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.set_r8_register(r8.into(), reg, bus);
     }
 
-    fn rst(&mut self, tgt: u8, mmu: &mut MMU) {
+    fn rst<B: Bus>(&mut self, tgt: u8, bus: &mut B) {
         // Target address 0x00exp000
         let address = (tgt << 3) as u16;
-        self.pc += 1;
-        let high = (self.pc >> 8) as u8;
-        let low = (self.pc & 0xff) as u8;
-        self.sp -= 1;
-        mmu.write_memory(self.sp, high);
-        self.sp -= 1;
-        mmu.write_memory(self.sp, low);
+        self.cycle(bus);
+        self.push(self.pc, bus);
         // JP u16
         self.pc = address;
     }
 
     //ALU A u8 -> Similar to ALU A r8, but instead of register, use next byte
-    fn alu_a_u8(&mut self, opcode: u8) {
-        let value = self.byte2;
+    fn alu_a_u8<B: Bus>(&mut self, opcode: u8, bus: &mut B) {
+        let value = self.imm8(bus);
         match opcode {
             0b000 => { self.add_a_u8(value) },
             0b001 => { self.adc_a_u8(value) },
@@ -599,61 +810,61 @@ impl CPU {
             0b111 => { self.cp_a_u8(value) },
             _ => { panic!("Invalid ALU A R8 Operation: opcode: {}, value: {}", opcode, value)}
         }
-        self.pc += 1;
-        self.next(1);
     }
 
-    // CALL if cond met
-    fn call_cond(&mut self, cond: u8, mmu: &mut MMU) {
-        if self.flags.get_cond(cond) != 0 {
-            self.call(mmu)
+    // CALL if cond met. The address is read either way
+    fn call_cond<B: Bus>(&mut self, cond: u8, bus: &mut B) {
+        let address = self.imm16(bus);
+        if self.flags.get_cond(cond) {
+            self.call_address(address, bus);
         }
     }
 
     // Save next address onto stack so that RET can pop it later
-    fn call(&mut self, mmu: &mut MMU) {
-        let address = (self.byte3 as u16) << 8 | self.byte2 as u16; 
-        self.pc += 3;
+    fn call<B: Bus>(&mut self, bus: &mut B) {
+        let address = self.imm16(bus);
+        self.call_address(address, bus);
+    }
+
+    fn call_address<B: Bus>(&mut self, address: u16, bus: &mut B) {
         // PC has already moved onto the next address
-        let high = (self.pc >> 8) as u8;
-        let low = (self.pc & 0xff) as u8;
-        self.sp -= 1;
-        mmu.write_memory(self.sp, high);
-        self.sp -= 1;
-        mmu.write_memory(self.sp, low);
+        self.cycle(bus);
+        self.push(self.pc, bus);
         // JP u16
         self.pc = address;
     }
 
-    // enable interrupts
+    // enable interrupts after the next instruction
     fn ei(&mut self) {
-        self.ime = 1;
-        self.next(1);
+        self.ime_scheduled = true;
     }
-    // disable interrupts, ime flag controls that
+    // disable interrupts, ime flag controls that. This also cancels a pending EI
     fn di(&mut self) {
         self.ime = 0;
-        self.next(1);
+        self.ime_scheduled = false;
     }
 
     // Jump to address u16
-    fn jp_u16(&mut self) {
-        let address = (self.byte3 as u16) << 8 | self.byte2 as u16; 
+    fn jp_u16<B: Bus>(&mut self, bus: &mut B) {
+        let address = self.imm16(bus);
+        self.cycle(bus);
         self.pc = address;
     }
 
-    // Jump based on condition
-    fn jp_cond(&mut self, cond: u8) {
+    // Jump based on condition. The address is read either way
+    fn jp_cond<B: Bus>(&mut self, cond: u8, bus: &mut B) {
+        let address = self.imm16(bus);
         // If condition true, JUMP
-        if self.flags.get_cond(cond) != 0 {
-            self.jp_u16();
+        if self.flags.get_cond(cond) {
+            self.cycle(bus);
+            self.pc = address;
         }
     }
 
     // Load value of HL into SP
-    fn ld_sp_hl(&mut self) {
+    fn ld_sp_hl<B: Bus>(&mut self, bus: &mut B) {
         self.sp = self.get_r16_register(REGISTER16::HL);
-        self.next(1);
+        self.cycle(bus);
     }
     // Jump to address to HL
     fn jp_hl(&mut self) {
@@ -661,428 +872,557 @@ impl CPU {
     }
 
     // Enable interrupts and RETURN
-    fn reti(&mut self, mmu: &mut MMU) {
-        // Enable interrupt
-        self.ei();
-        self.ret(mmu);
+    fn reti<B: Bus>(&mut self, bus: &mut B) {
+        // Enable interrupt, unlike EI this is not delayed
+        self.ime = 1;
+        self.ret(bus);
     }
 
     // RETURN
-    fn ret(&mut self, mmu: &mut MMU) {
-        let low = mmu.read_memory(self.sp);
-        self.sp += 1;
-        let high = mmu.read_memory(self.sp);
-        self.sp += 1;
-        let value = (((high as u16) << 8) as u16) | (low as u16);
-        self.pc = value;
-    }
-    // RETURN based on condition
-    fn ret_cond(&mut self, ret_code: u8, mmu: &mut MMU) {
+    fn ret<B: Bus>(&mut self, bus: &mut B) {
+        self.pc = self.pop(bus);
+        self.cycle(bus);
+    }
+    // RETURN based on condition, evaluating the condition takes a cycle
+    fn ret_cond<B: Bus>(&mut self, ret_code: u8, bus: &mut B) {
+        self.cycle(bus);
         // If condition true, RET
-        if self.flags.get_cond(ret_code) != 0 {
-            self.ret(mmu);
+        if self.flags.get_cond(ret_code) {
+            self.ret(bus);
         }
-        self.next(1);
     }
 
     // Push to stack
-    fn push_r16(&mut self, r16: u8, mmu: &mut MMU) {
+    fn push_r16<B: Bus>(&mut self, r16: u8, bus: &mut B) {
         let value = self.get_r16stk_register(r16.into());
-        let high = (value >> 8) as u8;
-        let low = (value & 0xff) as u8;
-        self.sp -= 1;
-        mmu.write_memory(self.sp, high);
-        self.sp -= 1;
-        mmu.write_memory(self.sp, low);
-        self.next(1);
+        self.cycle(bus);
+        self.push(value, bus);
     }
 
     // Load value in reg A from [n16]
-    fn ld_a_n16(&mut self, mmu: &mut MMU) {
-        let address = (self.byte3 as u16) << 8 | self.byte2 as u16; 
-        let value = mmu.read_memory(address);
-        self.set_r8_register(REGISTER8::A, value, mmu);
-        self.pc += 2;
-        self.next(1);
+    fn ld_a_n16<B: Bus>(&mut self, bus: &mut B) {
+        let address = self.imm16(bus);
+        self.a = self.read_cycle(address, bus);
     }
     
     // Load [0xff00 + c] into reg A
-    fn ldh_a_c(&mut self, mmu: &mut MMU) {
-        let address = 0xff00 + self.get_r8_register(REGISTER8::C, mmu) as u16;
-        let value = mmu.read_memory(address);
-        self.set_r8_register(REGISTER8::A, value, mmu);
-        self.next(1);
+    fn ldh_a_c<B: Bus>(&mut self, bus: &mut B) {
+        let address = 0xff00 + self.c as u16;
+        self.a = self.read_cycle(address, bus);
     }
 
-    // Load reg A value into memory address byte3 << 8|byte2
-    fn ld_n16_a(&mut self, mmu: &mut MMU) {
-        let value = self.get_r8_register(REGISTER8::A, mmu);
-        let address = (self.byte3 as u16) << 8 | self.byte2 as u16; 
-        mmu.write_memory(address, value);
-        self.pc += 2;
-        self.next(1);
+    // Load reg A value into memory address n16
+    fn ld_n16_a<B: Bus>(&mut self, bus: &mut B) {
+        let address = self.imm16(bus);
+        self.write_cycle(address, self.a, bus);
     }
     // Load value in register A into $ff00 + C
-    fn ldh_c_a(&mut self, mmu: &mut MMU) {
-        let value = self.get_r8_register(REGISTER8::A, mmu);
-        let address = self.get_r8_register(REGISTER8::C, mmu);
-        mmu.write_memory(0xff00 + address as u16, value);
-        self.next(1);
+    fn ldh_c_a<B: Bus>(&mut self, bus: &mut B) {
+        self.write_cycle(0xff00 + self.c as u16, self.a, bus);
     }
 
     // POP address from stack and save to register
-    fn pop_r16(&mut self, r16: u8, mmu: &mut MMU) {
-        let low = mmu.read_memory(self.sp);
-        self.sp += 1;
-        let high = mmu.read_memory(self.sp);
-        self.sp += 1;
-        let value = (((high as u16) << 8) as u16) | (low as u16);
+    fn pop_r16<B: Bus>(&mut self, r16: u8, bus: &mut B) {
+        let value = self.pop(bus);
         self.set_r16stk_register(r16.into(), value);
-        self.next(1);
-
-    }
-
-    fn ld_hl_sp_imm8(&mut self) {
-        let imm8 = self.byte2;
-        //NOTE: Might be bugged
-        let (half_carry, carry) = self.check_carry_add_i8(self.sp as i8, imm8 as i8);
-        let value = (self.sp as i16).wrapping_add(imm8 as i16);
-        self.set_r16_register(REGISTER16::HL, value as u16);
-        self.flags.zero = 0;
-        self.flags.n = 0;
-        self.flags.h = half_carry as u8;
-        self.flags.carry = carry as u8;
-        self.pc += 1;
-        self.next(1);
-
-    }
-    // Load value in address ff00 + n8 if in range, then save value in register A
-    fn ldh_a_i16(&mut self, mmu: &mut MMU) {
-        let address = self.byte2 as u16 + 0xFF00;
-        if address < 0xffff && address > 0xff00 {
-            let value = mmu.read_memory(address);
-            self.set_r8_register(REGISTER8::A, value, mmu);
-        }
-        self.pc += 1;
-        self.next(1);
+    }
+
+    fn ld_hl_sp_imm8<B: Bus>(&mut self, bus: &mut B) {
+        let imm8 = self.imm8(bus);
+        let (half_carry, carry) = self.check_carry_add_sp(imm8);
+        let value = self.sp.wrapping_add_signed(imm8 as i8 as i16);
+        self.set_r16_register(REGISTER16::HL, value);
+        self.flags.set_zero(false);
+        self.flags.set_n(false);
+        self.flags.set_h(half_carry);
+        self.flags.set_carry(carry);
+        self.cycle(bus);
+    }
+    // Load value in address ff00 + n8, then save value in register A
+    fn ldh_a_i16<B: Bus>(&mut self, bus: &mut B) {
+        let address = self.imm8(bus) as u16 + 0xFF00;
+        self.a = self.read_cycle(address, bus);
     }
 
     // Add immediate i8 value to stack pointer, and set hc/carry flags 
     // appropriately
     // ADD SP i8
-    fn add_sp_i8(&mut self) {
-        let imm8 = self.byte2;
-        let (half_carry, carry) = self.check_carry_add_i8(self.sp as i8, imm8 as i8);
-        let value = (self.sp as i16).wrapping_add(imm8 as i16);
-        self.set_r16_register(REGISTER16::SP, value as u16);
-        self.flags.zero = 0;
-        self.flags.n = 0;
-        self.flags.h = half_carry as u8;
-        self.flags.carry = carry as u8;
-        self.pc += 1;
-        self.next(1);
+    fn add_sp_i8<B: Bus>(&mut self, bus: &mut B) {
+        let imm8 = self.imm8(bus);
+        let (half_carry, carry) = self.check_carry_add_sp(imm8);
+        let value = self.sp.wrapping_add_signed(imm8 as i8 as i16);
+        self.set_r16_register(REGISTER16::SP, value);
+        self.flags.set_zero(false);
+        self.flags.set_n(false);
+        self.flags.set_h(half_carry);
+        self.flags.set_carry(carry);
+        // The low and high bytes of SP are added in separate cycles
+        self.cycle(bus);
+        self.cycle(bus);
     }
 
     // Load value from dst_r8 into src_r8. When called as LD r1 r2, this method
     // is called as ld_r8_r8(r2, r1) (Notice the inversion)
-    fn ld_r8_r8(&mut self, src_r8: u8, dst_r8: u8, mmu: &mut MMU) {
-        let value = self.get_r8_register(src_r8.into(), mmu);
-        self.set_r8_register(dst_r8.into(), value, mmu);
-        self.next(1);
+    fn ld_r8_r8<B: Bus>(&mut self, src_r8: u8, dst_r8: u8, bus: &mut B) {
+        let value = self.get_r8_register(src_r8.into(), bus);
+        self.set_r8_register(dst_r8.into(), value, bus);
 
     }
 
-    // If n8 + ff00 is in range, store value of register A into memory location n8 + ff00
+    // Store value of register A into memory location n8 + ff00
     // LDH [n16], A OR LDH [$FF00 + n8], A
-    fn ldh_i16_a(&mut self, mmu: &mut MMU) {
-        let address: u16 = 0xFF00 + self.byte2 as u16;
-        if address < 0xffff && address > 0xff00 {
-            let value = self.get_r8_register(REGISTER8::A, mmu);
-            mmu.write_memory(address, value);
-        }
-        self.pc += 1;
-        self.next(1);
+    fn ldh_i16_a<B: Bus>(&mut self, bus: &mut B) {
+        let address: u16 = 0xFF00 + self.imm8(bus) as u16;
+        self.write_cycle(address, self.a, bus);
     }
 
-    //TODO - Respond to interrupt
-    fn halt(&mut self) {
-        panic!("HALT")
+    // Stop executing instructions until an interrupt is pending. If IME is not set and an
+    // interrupt is already pending, the CPU does not halt and the HALT bug triggers instead
+    fn halt<B: Bus>(&mut self, bus: &mut B) {
+        if self.ime == 0 && self.pending_interrupts(bus) != 0 {
+            self.halt_bug = true;
+        } else {
+            self.halted = true;
+        }
+    }
 
+    // On CGB, STOP performs a speed switch if one was armed through KEY1. Otherwise the CPU and
+    // the LCD stop until a joypad input. STOP also resets DIV
+    fn stop<B: Bus>(&mut self, bus: &mut B) {
+        // STOP is followed by a padding byte, which is skipped without being read
+        self.pc = self.pc.wrapping_add(1);
+        bus.write(0xff04, 0);
+        if !bus.switch_speed() {
+            self.stopped = true;
+        }
     }
 
-    //TODO
-    fn special_opcodes(&mut self, opcode: u8, mmu: &mut MMU) {
+    fn special_opcodes<B: Bus>(&mut self, opcode: u8, bus: &mut B) {
         match opcode {
-            0 => { self.rlca(mmu) },
-            1 => { self.rrca(mmu) },
-            2 => { self.rla(mmu) },
-            3 => { self.rra(mmu) },
+            0 => { self.rlca(bus) },
+            1 => { self.rrca(bus) },
+            2 => { self.rla(bus) },
+            3 => { self.rra(bus) },
             4 => { self.daa() },
-            5 => { self.cpl(mmu) },
+            5 => { self.cpl(bus) },
             6 => { self.scf() },
             7 => { self.ccf() },
             _ => { panic!("Invalid opcode for special group: {:02X}", opcode);
             }
         }
-        self.next(1);
     }
 
-    fn rlca(&mut self, mmu: &mut MMU) {
+    // The accumulator rotates behave like their CB counterparts, except that Z is always cleared
+    fn rlca<B: Bus>(&mut self, bus: &mut B) {
         let reg_code_a = 7;
-        self.rlc_r8(reg_code_a.into(), mmu);
+        self.rlc_r8(reg_code_a, bus);
+        self.flags.set_zero(false);
     }
 
-    fn rrca(&mut self, mmu: &mut MMU) {
+    fn rrca<B: Bus>(&mut self, bus: &mut B) {
         let reg_code_a = 7;
-        self.rrc_r8(reg_code_a.into(), mmu);
+        self.rrc_r8(reg_code_a, bus);
+        self.flags.set_zero(false);
     }
 
-    fn rla(&mut self, mmu: &mut MMU) {
+    fn rla<B: Bus>(&mut self, bus: &mut B) {
         let reg_code_a = 7;
-        self.rl_r8(reg_code_a.into(), mmu);
+        self.rl_r8(reg_code_a, bus);
+        self.flags.set_zero(false);
     }
 
-    fn rra(&mut self, mmu: &mut MMU) {
+    fn rra<B: Bus>(&mut self, bus: &mut B) {
         let reg_code_a = 7;
-        self.rr_r8(reg_code_a.into(), mmu);
+        self.rr_r8(reg_code_a, bus);
+        self.flags.set_zero(false);
     }
 
+    // Decimal adjust A after a BCD addition or subtraction, using N, H and C to know which
+    // operation was performed and which digits overflowed
     fn daa(&mut self) {
-        panic!("TODO")
+        let mut adjust = 0;
+        if !self.flags.n() {
+            if self.flags.carry() || self.a > 0x99 {
+                adjust |= 0x60;
+                self.flags.set_carry(true);
+            }
+            if self.flags.h() || (self.a & 0x0f) > 0x09 {
+                adjust |= 0x06;
+            }
+            self.a = self.a.wrapping_add(adjust);
+        } else {
+            if self.flags.carry() {
+                adjust |= 0x60;
+            }
+            if self.flags.h() {
+                adjust |= 0x06;
+            }
+            self.a = self.a.wrapping_sub(adjust);
+        }
+        self.flags.set_zero(self.a == 0);
+        self.flags.set_h(false);
     }
-    fn cpl(&mut self, mmu: &mut MMU) {
+    // Complement A
+    fn cpl<B: Bus>(&mut self, bus: &mut B) {
         let reg_code_a = 7;
-        let mut reg = self.get_r8_register(reg_code_a.into(), mmu);
+        let mut reg = self.get_r8_register(reg_code_a.into(), bus);
         reg = !reg;
-        self.set_r8_register(reg_code_a.into(), reg, mmu);
+        self.set_r8_register(reg_code_a.into(), reg, bus);
+        self.flags.set_n(true);
+        self.flags.set_h(true);
     }
+    // Set carry flag
     fn scf(&mut self) {
-        panic!("TODO")
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.flags.set_carry(true);
     }
+    // Complement carry flag
     fn ccf(&mut self) {
-        panic!("TODO")
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.flags.set_carry(!self.flags.carry());
     }
 
 
 
 
     //Increment value in register r8 by 1
-    fn inc_r8(&mut self, register_lookup: u8, mmu: &mut MMU) {
-        let register = self.get_r8_register(register_lookup.into(), mmu);
+    fn inc_r8<B: Bus>(&mut self, register_lookup: u8, bus: &mut B) {
+        let register = self.get_r8_register(register_lookup.into(), bus);
         let sum = register.wrapping_add(1);
-        if sum == 0 { self.flags.zero = 0 }
-        self.flags.n = 0;
-        let (half_carry, _) = self.check_carry_add_u8(register, 1);
-        if half_carry { self.flags.h = 1 }
-        self.set_r8_register(register_lookup.into(), sum, mmu);
-        self.next(1);
+        self.flags.set_zero(sum == 0);
+        self.flags.set_n(false);
+        let (half_carry, _) = self.check_carry_add_u8(register, 1, 0);
+        self.flags.set_h(half_carry);
+        self.set_r8_register(register_lookup.into(), sum, bus);
     }
 
     //Decrement value in register r8 by 1
-    fn dec_r8(&mut self, register_lookup: u8, mmu: &mut MMU) {
-        let register = self.get_r8_register(register_lookup.into(), mmu);
+    fn dec_r8<B: Bus>(&mut self, register_lookup: u8, bus: &mut B) {
+        let register = self.get_r8_register(register_lookup.into(), bus);
         let sum = register.wrapping_sub(1);
-        if sum == 0 { self.flags.zero = 0 }
-        self.flags.n = 1;
-        let (half_carry, _) = self.check_carry_sub_u8(register, 1);
-        if half_carry{ self.flags.h = 1 }
-        self.set_r8_register(register_lookup.into(), sum, mmu);
-        self.next(1);
+        self.flags.set_zero(sum == 0);
+        self.flags.set_n(true);
+        let (half_carry, _) = self.check_carry_sub_u8(register, 1, 0);
+        self.flags.set_h(half_carry);
+        self.set_r8_register(register_lookup.into(), sum, bus);
     }
 
     //Increment value in register r16 by 1
-    fn inc_r16(&mut self, register_lookup: u8, mmu: &mut MMU) {
+    fn inc_r16<B: Bus>(&mut self, register_lookup: u8, bus: &mut B) {
         let mut register = self.get_r16_register(register_lookup.into());
         register = register.wrapping_add(1);
         self.set_r16_register(register_lookup.into(), register);
-        self.next(1);
+        self.cycle(bus);
     }
 
     //Decrement value in register r16 by 1
-    fn dec_r16(&mut self, register_lookup: u8, mmu: &mut MMU) {
+    fn dec_r16<B: Bus>(&mut self, register_lookup: u8, bus: &mut B) {
         let mut register = self.get_r16_register(register_lookup.into());
         register = register.wrapping_sub(1);
         self.set_r16_register(register_lookup.into(), register);
-        self.next(1);
+        self.cycle(bus);
     }
 
     //Load value  n8 into register r8
-    fn ld_r8_n8(&mut self, r8: u8, mmu: &mut MMU) {
-        self.set_r8_register(r8.into(), self.byte2, mmu);
-        self.pc += 1;
-        self.next(1);
+    fn ld_r8_n8<B: Bus>(&mut self, r8: u8, bus: &mut B) {
+        let value = self.imm8(bus);
+        self.set_r8_register(r8.into(), value, bus);
     }
 
     //Load value pointed in memory by r16 register pair into register A
-    fn ld_a_r16_addr(&mut self, register_lookup: u8, mmu: &mut MMU) {
+    fn ld_a_r16_addr<B: Bus>(&mut self, register_lookup: u8, bus: &mut B) {
         let memory_address = self.get_r16mem_register(register_lookup.into());
-        self.a = mmu.read_memory(memory_address);
-        self.next(1);
+        self.a = self.read_cycle(memory_address, bus);
     }
     // Load the 8 bit value in register A to the memory address pointed by the register from the
     // table
-    fn ld_r16_addr_a(&mut self, register_lookup: u8, mmu: &mut MMU) {
+    fn ld_r16_addr_a<B: Bus>(&mut self, register_lookup: u8, bus: &mut B) {
         let memory_address = self.get_r16mem_register(register_lookup.into());
-        mmu.write_memory(memory_address, self.a);
-        self.next(1);
+        self.write_cycle(memory_address, self.a, bus);
     }
 
     //Add register r16 value to HL
-    fn add_hl_r16(&mut self, register_lookup: u8) {
+    fn add_hl_r16<B: Bus>(&mut self, register_lookup: u8, bus: &mut B) {
         let r16 = self.get_r16_register(register_lookup.into());
         let hl = self.get_r16_register(REGISTER16::HL); //0b101 == HL register pair
         let (sum, overflow_high) = r16.overflowing_add(hl);
 
         //Additions reset the n flag
-        self.flags.n = 0;
+        self.flags.set_n(false);
         //Check 11th to 12th bit overflow
-        if (r16 & 0xfff).overflowing_add(hl & 0xfff).1 as u8 == 1 { self.flags.h = 1 }
+        self.flags.set_h((r16 & 0xfff) + (hl & 0xfff) > 0xfff);
         //Check 15th to 16th bit overflow
-        if overflow_high { self.flags.carry = 1 }
+        self.flags.set_carry(overflow_high);
 
         self.set_r16_register(REGISTER16::HL, sum);
-        self.next(1);
+        self.cycle(bus);
     }
 
     //Load value u16 into register r16
-    fn ld_r16_u16(&mut self, register_lookup: u8) {
-        let address = (self.byte3 as u16) << 8 | self.byte2 as u16; 
-        self.set_r16_register(register_lookup.into(), address);
-        self.pc += 2;
-        self.next(1);
-    }
-
-    //Conditional Jump
-    fn jr_cond(&mut self, condition: u8) {
-
-        let should_execute = match condition {
-            0b100 => { self.flags.zero != 0 },
-            0b101 => { self.flags.zero == 0},
-            0b110 => { self.flags.carry != 0 },
-            0b111 => { self.flags.carry == 0 },
-            _c => { panic!("Unknown condition {}", _c) }
-        };
-        if should_execute {
-            let offset: i8 = self.byte2 as i8;
-            self.pc = self.pc.wrapping_add_signed(offset.into());
-        } else {
-            // Skip byte2 which contains jump address
-            self.pc += 1;
-            // Fetch next instruction
-            self.next(1);
+    fn ld_r16_u16<B: Bus>(&mut self, register_lookup: u8, bus: &mut B) {
+        let value = self.imm16(bus);
+        self.set_r16_register(register_lookup.into(), value);
+    }
+
+    //Conditional Jump. The offset is read either way
+    fn jr_cond<B: Bus>(&mut self, condition: u8, bus: &mut B) {
+        let offset = self.imm8(bus) as i8;
+        // Conditions 0b100..=0b111 map to NZ, Z, NC, C
+        if self.flags.get_cond(condition - 0b100) {
+            self.jr_offset(offset, bus);
         }
     }
 
     //Unconditional relative jump
-    fn jr(&mut self) {
-        let offset: i8 = self.byte2 as i8;
+    fn jr<B: Bus>(&mut self, bus: &mut B) {
+        let offset = self.imm8(bus) as i8;
+        self.jr_offset(offset, bus);
+    }
+
+    // The offset is relative to the address of the next instruction, where PC already is
+    fn jr_offset<B: Bus>(&mut self, offset: i8, bus: &mut B) {
         self.pc = self.pc.wrapping_add_signed(offset.into());
+        self.cycle(bus);
     }
 
     //Store SP lower at address u16, and SP upper at address u16 + 1
-    fn ld_u16_sp(&mut self, mmu: &mut MMU) {
-        let address: u16 = (self.byte3 as u16) << 8 | self.byte2 as u16;
-        mmu.write_memory(address, (self.sp & 0xff) as u8);
-        mmu.write_memory(address + 1, (self.sp >> 8) as u8);
-        self.pc += 2;
-        self.next(1);
+    fn ld_u16_sp<B: Bus>(&mut self, bus: &mut B) {
+        let address = self.imm16(bus);
+        self.write_cycle(address, (self.sp & 0xff) as u8, bus);
+        self.write_cycle(address.wrapping_add(1), (self.sp >> 8) as u8, bus);
     }
 
     //All math based operations are processed here
-    fn alu_a_r8(&mut self, opcode: u8, r8: u8, mmu: &mut MMU) {
-        let value = self.get_r8_register(r8.into(), mmu);
+    fn alu_a_r8<B: Bus>(&mut self, opcode: u8, r8: u8, bus: &mut B) {
+        let value = self.get_r8_register(r8.into(), bus);
         match opcode {
-            0b000 => { println!("add_a_r8"); self.add_a_u8(value) },
-            0b001 => { println!("adc_a_r8"); self.adc_a_u8(value) },
-            0b010 => { println!("sub_a_r8"); self.sub_a_u8(value) },
-            0b011 => { println!("sbc_a_r8"); self.sbc_a_u8(value) },
-            0b100 => { println!("and_a_r8"); self.and_a_u8(value) },
-            0b101 => { println!("xor_a_r8"); self.xor_a_u8(value) },
-            0b110 => { println!("or_a_r8"); self.or_a_u8(value) },
-            0b111 => { println!("cp_a_r8"); self.cp_a_u8(value) },
+            0b000 => { trace!(Cpu, Trace, "add_a_r8"); self.add_a_u8(value) },
+            0b001 => { trace!(Cpu, Trace, "adc_a_r8"); self.adc_a_u8(value) },
+            0b010 => { trace!(Cpu, Trace, "sub_a_r8"); self.sub_a_u8(value) },
+            0b011 => { trace!(Cpu, Trace, "sbc_a_r8"); self.sbc_a_u8(value) },
+            0b100 => { trace!(Cpu, Trace, "and_a_r8"); self.and_a_u8(value) },
+            0b101 => { trace!(Cpu, Trace, "xor_a_r8"); self.xor_a_u8(value) },
+            0b110 => { trace!(Cpu, Trace, "or_a_r8"); self.or_a_u8(value) },
+            0b111 => { trace!(Cpu, Trace, "cp_a_r8"); self.cp_a_u8(value) },
             _ => { panic!("Invalid ALU A R8 Operation: opcode: {}, register: {}", opcode, r8)}
         }
-        self.next(1);
     }
 
     //Add the value to the a register
     fn add_a_u8(&mut self, value: u8) {
-        let (half_carry, carry) = self.check_carry_add_u8(self.a, value);
+        let (half_carry, carry) = self.check_carry_add_u8(self.a, value, 0);
         self.a = self.a.wrapping_add(value);
-        if half_carry { self.flags.h = 1 }
-        if carry { self.flags.carry = 1 }
-        if self.a == 0 { self.flags.zero = 0 }
-        self.flags.n = 0;
+        self.flags.set_h(half_carry);
+        self.flags.set_carry(carry);
+        self.flags.set_zero(self.a == 0);
+        self.flags.set_n(false);
     }
     //Add the value to the a register, along with the value of the carry flag
     fn adc_a_u8(&mut self, value: u8) {
-        let sum = value.wrapping_add(self.flags.carry);
-        let (half_carry, carry) = self.check_carry_add_u8(self.a, sum);
-        self.a = self.a.wrapping_add(sum);
+        let carry_in = self.flags.carry() as u8;
+        let (half_carry, carry) = self.check_carry_add_u8(self.a, value, carry_in);
+        self.a = self.a.wrapping_add(value).wrapping_add(carry_in);
 
-        if half_carry { self.flags.h = 1 }
-        if carry { self.flags.carry = 1 }
-        if self.a == 0 { self.flags.zero = 0 }
-        self.flags.n = 0;
+        self.flags.set_h(half_carry);
+        self.flags.set_carry(carry);
+        self.flags.set_zero(self.a == 0);
+        self.flags.set_n(false);
 
     }
     //Sub the value from the a register
     fn sub_a_u8(&mut self, value: u8) {
-        let (half_carry, carry) = self.check_carry_sub_u8(self.a, value);
+        let (half_carry, carry) = self.check_carry_sub_u8(self.a, value, 0);
         self.a = self.a.wrapping_sub(value);
 
-        if half_carry { self.flags.h = 1 }
-        if carry { self.flags.carry = 1 }
-        if self.a == 0 { self.flags.zero = 0 }
-        self.flags.n = 1;
+        self.flags.set_h(half_carry);
+        self.flags.set_carry(carry);
+        self.flags.set_zero(self.a == 0);
+        self.flags.set_n(true);
     }
     //Sub the value from the a register along with the value of the carry flag
     fn sbc_a_u8(&mut self, value: u8) {
-        let sum = value.wrapping_add(self.flags.carry);
-        let (half_carry, carry) = self.check_carry_sub_u8(self.a, sum);
-        self.a = self.a.wrapping_sub(sum);
+        let carry_in = self.flags.carry() as u8;
+        let (half_carry, carry) = self.check_carry_sub_u8(self.a, value, carry_in);
+        self.a = self.a.wrapping_sub(value).wrapping_sub(carry_in);
 
-        if half_carry { self.flags.h = 1 }
-        if carry { self.flags.carry = 1 }
-        if self.a == 0 { self.flags.zero = 0 }
-        self.flags.n = 1;
+        self.flags.set_h(half_carry);
+        self.flags.set_carry(carry);
+        self.flags.set_zero(self.a == 0);
+        self.flags.set_n(true);
     }
 
     //Bitwise AND between value and A
     fn and_a_u8(&mut self, value: u8) {
-        self.a = self.a & value;
-        if self.a == 0 { self.flags.zero = 0 }
-        self.flags.n = 0;
-        self.flags.h = 1;
-        self.flags.carry = 0;
+        self.a &= value;
+        self.flags.set_zero(self.a == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(true);
+        self.flags.set_carry(false);
     }
 
     //Bitwise XOR between value and A
     fn xor_a_u8(&mut self, value: u8) {
-        self.a = self.a ^ value;
-        if self.a == 0 { self.flags.zero = 0 }
-        self.flags.n = 0;
-        self.flags.h = 0;
-        self.flags.carry = 0;
+        self.a ^= value;
+        self.flags.set_zero(self.a == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.flags.set_carry(false);
     }
 
     //Bitwise OR between value and A
     fn or_a_u8(&mut self, value: u8) {
-        self.a = self.a | value;
-        if self.a == 0 { self.flags.zero = 0 }
-        self.flags.n = 0;
-        self.flags.h = 0;
-        self.flags.carry = 0;
+        self.a |= value;
+        self.flags.set_zero(self.a == 0);
+        self.flags.set_n(false);
+        self.flags.set_h(false);
+        self.flags.set_carry(false);
     }
 
     //Subtract value from A, but don't store the result, only set flags
     fn cp_a_u8(&mut self, value: u8) {
-        let (half_carry, carry) = self.check_carry_sub_u8(self.a, value);
+        let (half_carry, carry) = self.check_carry_sub_u8(self.a, value, 0);
         let tmp = self.a.wrapping_sub(value);
 
-        if half_carry { self.flags.h = 1 }
-        if carry { self.flags.carry = 1 }
-        if tmp == 0 { self.flags.zero = 0 }
-        self.flags.n = 1;
+        self.flags.set_h(half_carry);
+        self.flags.set_carry(carry);
+        self.flags.set_zero(tmp == 0);
+        self.flags.set_n(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Flat memory counting the T-cycles it was ticked for
+    struct CountingBus {
+        memory: Vec<u8>,
+        ticked: u64,
+    }
+
+    impl Bus for CountingBus {
+        fn read(&mut self, address: u16) -> u8 {
+            self.memory[address as usize]
+        }
+
+        fn write(&mut self, address: u16, value: u8) {
+            self.memory[address as usize] = value;
+        }
+
+        fn tick(&mut self, cycles: u8) {
+            self.ticked += cycles as u64;
+        }
+    }
+
+    // Run one instruction from 0xC000 with the given flags, returning the cycles taken and the
+    // cycles ticked on the bus
+    fn run(bytes: &[u8], flags: u8) -> (u64, u64) {
+        let mut cpu = CPU::new();
+        cpu.pc = 0xc000;
+        cpu.sp = 0xd000;
+        cpu.flags = Flags::from_bits(flags);
+        let mut bus = CountingBus { memory: vec![0; 0x10000], ticked: 0 };
+        bus.memory[0xc000..0xc000 + bytes.len()].copy_from_slice(bytes);
+        cpu.step(&mut bus);
+        (cpu.cycles, bus.ticked)
+    }
+
+    #[test]
+    fn opcode_cycles_match_table() {
+        for opcode in 0..=0xffu8 {
+            if OPCODE_CYCLES[opcode as usize] == 0 || opcode == 0xcb {
+                continue;
+            }
+            // All flags clear takes the NZ and NC branches, all flags set takes Z and C
+            for flags in [0x00, 0xf0] {
+                let taken = match opcode {
+                    0x20 | 0x30 | 0xc0 | 0xd0 | 0xc2 | 0xd2 | 0xc4 | 0xd4 => flags == 0x00,
+                    0x28 | 0x38 | 0xc8 | 0xd8 | 0xca | 0xda | 0xcc | 0xdc => flags == 0xf0,
+                    _ => false,
+                };
+                let expected = if taken { branch_taken_cycles(opcode) } else { OPCODE_CYCLES[opcode as usize] };
+                let (cycles, ticked) = run(&[opcode, 0x00, 0x00], flags);
+                assert_eq!(cycles, expected as u64, "opcode 0x{:02X} flags 0x{:02X}", opcode, flags);
+                assert_eq!(ticked, cycles, "opcode 0x{:02X} ticked", opcode);
+            }
+        }
+    }
+
+    #[test]
+    fn cb_opcode_cycles_match_table() {
+        for opcode in 0..=0xffu8 {
+            let (cycles, ticked) = run(&[0xcb, opcode], 0);
+            assert_eq!(cycles, CB_OPCODE_CYCLES[opcode as usize] as u64, "opcode 0xCB 0x{:02X}", opcode);
+            assert_eq!(ticked, cycles, "opcode 0xCB 0x{:02X} ticked", opcode);
+        }
+    }
+
+    #[test]
+    fn interrupt_dispatch_takes_five_cycles() {
+        let mut cpu = CPU::new();
+        cpu.ime = 1;
+        cpu.sp = 0xd000;
+        let mut bus = CountingBus { memory: vec![0; 0x10000], ticked: 0 };
+        bus.memory[IE_ADDRESS as usize] = Interrupt::Timer.mask();
+        bus.memory[IF_ADDRESS as usize] = Interrupt::Timer.mask();
+        assert_eq!(cpu.step(&mut bus), 20);
+        assert_eq!(bus.ticked, 20);
+        assert_eq!(cpu.pc, Interrupt::Timer.vector());
+        assert_eq!(bus.memory[IF_ADDRESS as usize], 0);
+        assert_eq!((bus.memory[0xcfff], bus.memory[0xcffe]), (0x01, 0x00));
+    }
+
+    #[test]
+    fn executes_instruction_unless_idle_or_dispatching() {
+        let mut cpu = CPU::new();
+        let mut bus = CountingBus { memory: vec![0; 0x10000], ticked: 0 };
+        assert!(cpu.executes_instruction(&mut bus));
+        bus.memory[IE_ADDRESS as usize] = Interrupt::Timer.mask();
+        bus.memory[IF_ADDRESS as usize] = Interrupt::Timer.mask();
+        // Pending but not serviced without IME
+        assert!(cpu.executes_instruction(&mut bus));
+        cpu.ime = 1;
+        assert!(!cpu.executes_instruction(&mut bus));
+        // Woken up from HALT without IME, the next instruction runs in the same step
+        cpu.ime = 0;
+        cpu.halted = true;
+        assert!(cpu.executes_instruction(&mut bus));
+        bus.memory[IF_ADDRESS as usize] = 0;
+        assert!(!cpu.executes_instruction(&mut bus));
+        cpu.halted = false;
+        cpu.locked = true;
+        assert!(!cpu.executes_instruction(&mut bus));
+    }
+
+    #[test]
+    fn halt_bug_reads_next_byte_twice() {
+        let mut cpu = CPU::new();
+        cpu.pc = 0xc000;
+        let mut bus = CountingBus { memory: vec![0; 0x10000], ticked: 0 };
+        bus.memory[IE_ADDRESS as usize] = Interrupt::Timer.mask();
+        bus.memory[IF_ADDRESS as usize] = Interrupt::Timer.mask();
+        // HALT, then INC A
+        bus.memory[0xc000..0xc002].copy_from_slice(&[0x76, 0x3c]);
+        cpu.step(&mut bus);
+        assert!(!cpu.halted);
+        let a = cpu.a;
+        cpu.step(&mut bus);
+        cpu.step(&mut bus);
+        assert_eq!(cpu.a, a.wrapping_add(2));
+        assert_eq!(cpu.pc, 0xc002);
+    }
+
+    #[test]
+    fn invalid_opcode_locks_up() {
+        let mut cpu = CPU::new();
+        cpu.pc = 0xc000;
+        let mut bus = CountingBus { memory: vec![0; 0x10000], ticked: 0 };
+        bus.memory[0xc000] = 0xd3;
+        cpu.step(&mut bus);
+        assert!(cpu.locked);
+        assert_eq!(cpu.step(&mut bus), 4);
+        assert_eq!(cpu.pc, 0xc001);
     }
 }
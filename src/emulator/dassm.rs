@@ -0,0 +1,246 @@
+//! SM83 disassembler
+//!
+//! Instructions are decoded with the same octet split as `CPU::execute`, but by a separate match,
+//! their cycle counts come from the CPU's own timing tables. The tests below check every opcode's
+//! length and timing.
+
+use std::fmt;
+
+use super::cpu::{branch_taken_cycles, CB_OPCODE_CYCLES, OPCODE_CYCLES};
+
+const R8: [&str; 8] = ["B", "C", "D", "E", "H", "L", "[HL]", "A"];
+const R16: [&str; 4] = ["BC", "DE", "HL", "SP"];
+const R16MEM: [&str; 4] = ["BC", "DE", "HL+", "HL-"];
+const R16STK: [&str; 4] = ["BC", "DE", "HL", "AF"];
+const COND: [&str; 4] = ["NZ", "Z", "NC", "C"];
+const ALU: [&str; 8] = ["ADD A,", "ADC A,", "SUB A,", "SBC A,", "AND A,", "XOR A,", "OR A,", "CP A,"];
+const SPECIAL: [&str; 8] = ["RLCA", "RRCA", "RLA", "RRA", "DAA", "CPL", "SCF", "CCF"];
+const SHIFT_ROTATE: [&str; 8] = ["RLC", "RRC", "RL", "RR", "SLA", "SRA", "SWAP", "SRL"];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub bytes: Vec<u8>,
+    pub mnemonic: String,
+    pub length: u8,
+    pub cycles: u8, // T-cycles, or the cost of not branching for conditional instructions
+    pub branch_cycles: Option<u8>, // T-cycles when a conditional instruction branches
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+        let cycles = match self.branch_cycles {
+            Some(taken) => format!("{}/{}", taken, self.cycles),
+            None => self.cycles.to_string(),
+        };
+        write!(f, "{:04X}: {:<9} {:<20} ; {}", self.address, bytes.join(" "), self.mnemonic, cycles)
+    }
+}
+
+/// Decode the instruction at the start of `bytes`
+/// Operands missing at the end of `bytes` are read as 0
+///
+/// # Arguments
+///
+/// * `bytes` - Memory starting at the instruction
+/// * `address` - Address of the instruction, used for relative jump targets
+///
+pub fn decode(bytes: &[u8], address: u16) -> Instruction {
+    let byte = |index: usize| bytes.get(index).copied().unwrap_or(0);
+    let opcode = byte(0);
+    let n8 = byte(1);
+    let n16 = (byte(2) as u16) << 8 | byte(1) as u16;
+    // JR targets are relative to the next instruction
+    let jr_target = address.wrapping_add(2).wrapping_add_signed(n8 as i8 as i16);
+    let e8 = n8 as i8;
+
+    let oct1 = (opcode & 0b11000000) >> 6;
+    let oct2 = (opcode & 0b00111000) >> 3;
+    let oct3 = opcode & 0b00000111;
+    let r16 = (oct2 >> 1) as usize;
+
+    // (mnemonic, length)
+    let (mnemonic, length) = match (oct1, oct2, oct3) {
+        (0b00, 0b000, 0b000) => ("NOP".to_string(), 1),
+        (0b00, 0b001, 0b000) => (format!("LD [${:04X}], SP", n16), 3),
+        (0b00, 0b010, 0b000) => ("STOP".to_string(), 2),
+        (0b00, 0b011, 0b000) => (format!("JR ${:04X}", jr_target), 2),
+        (0b00, cond, 0b000) => (format!("JR {}, ${:04X}", COND[(cond - 0b100) as usize], jr_target), 2),
+        (0b00, 0b000|0b010|0b100|0b110, 0b001) => (format!("LD {}, ${:04X}", R16[r16], n16), 3),
+        (0b00, _, 0b001) => (format!("ADD HL, {}", R16[r16]), 1),
+        (0b00, 0b000|0b010|0b100|0b110, 0b010) => (format!("LD [{}], A", R16MEM[r16]), 1),
+        (0b00, _, 0b010) => (format!("LD A, [{}]", R16MEM[r16]), 1),
+        (0b00, 0b000|0b010|0b100|0b110, 0b011) => (format!("INC {}", R16[r16]), 1),
+        (0b00, _, 0b011) => (format!("DEC {}", R16[r16]), 1),
+        (0b00, r8, 0b100) => (format!("INC {}", R8[r8 as usize]), 1),
+        (0b00, r8, 0b101) => (format!("DEC {}", R8[r8 as usize]), 1),
+        (0b00, r8, 0b110) => (format!("LD {}, ${:02X}", R8[r8 as usize], n8), 2),
+        (0b00, op, _) => (SPECIAL[op as usize].to_string(), 1),
+        (0b01, 0b110, 0b110) => ("HALT".to_string(), 1),
+        (0b01, dst_r8, src_r8) => (format!("LD {}, {}", R8[dst_r8 as usize], R8[src_r8 as usize]), 1),
+        (0b10, op, r8) => (format!("{} {}", ALU[op as usize], R8[r8 as usize]), 1),
+        (0b11, 0b000..=0b011, 0b000) => (format!("RET {}", COND[oct2 as usize]), 1),
+        (0b11, 0b100, 0b000) => (format!("LDH [$FF{:02X}], A", n8), 2),
+        (0b11, 0b101, 0b000) => (format!("ADD SP, {}", e8), 2),
+        (0b11, 0b110, 0b000) => (format!("LDH A, [$FF{:02X}]", n8), 2),
+        (0b11, 0b111, 0b000) => (format!("LD HL, SP{:+}", e8), 2),
+        (0b11, 0b000|0b010|0b100|0b110, 0b001) => (format!("POP {}", R16STK[r16]), 1),
+        (0b11, 0b001, 0b001) => ("RET".to_string(), 1),
+        (0b11, 0b011, 0b001) => ("RETI".to_string(), 1),
+        (0b11, 0b101, 0b001) => ("JP HL".to_string(), 1),
+        (0b11, 0b111, 0b001) => ("LD SP, HL".to_string(), 1),
+        (0b11, 0b000..=0b011, 0b010) => (format!("JP {}, ${:04X}", COND[oct2 as usize], n16), 3),
+        (0b11, 0b100, 0b010) => ("LDH [C], A".to_string(), 1),
+        (0b11, 0b101, 0b010) => (format!("LD [${:04X}], A", n16), 3),
+        (0b11, 0b110, 0b010) => ("LDH A, [C]".to_string(), 1),
+        (0b11, 0b111, 0b010) => (format!("LD A, [${:04X}]", n16), 3),
+        (0b11, 0b000, 0b011) => (format!("JP ${:04X}", n16), 3),
+        (0b11, 0b001, 0b011) => return decode_cb(byte(1), address),
+        (0b11, 0b110, 0b011) => ("DI".to_string(), 1),
+        (0b11, 0b111, 0b011) => ("EI".to_string(), 1),
+        (0b11, 0b000..=0b011, 0b100) => (format!("CALL {}, ${:04X}", COND[oct2 as usize], n16), 3),
+        (0b11, 0b000|0b010|0b100|0b110, 0b101) => (format!("PUSH {}", R16STK[r16]), 1),
+        (0b11, 0b001, 0b101) => (format!("CALL ${:04X}", n16), 3),
+        (0b11, op, 0b110) => (format!("{} ${:02X}", ALU[op as usize], n8), 2),
+        (0b11, tgt, 0b111) => (format!("RST ${:02X}", tgt << 3), 1),
+        // Not a valid opcode, show it as data
+        _ => (format!("DB ${:02X}", opcode), 1),
+    };
+
+    let taken = branch_taken_cycles(opcode);
+    let cycles = OPCODE_CYCLES[opcode as usize];
+    Instruction {
+        address,
+        bytes: (0..length).map(byte).collect(),
+        mnemonic,
+        length: length as u8,
+        cycles,
+        branch_cycles: (taken != cycles).then_some(taken),
+    }
+}
+
+// Decode a CB-prefixed instruction, `opcode` being the byte following 0xCB
+fn decode_cb(opcode: u8, address: u16) -> Instruction {
+    let oct1 = (opcode & 0b11000000) >> 6;
+    let oct2 = (opcode & 0b00111000) >> 3;
+    let r8 = R8[(opcode & 0b00000111) as usize];
+    let mnemonic = match oct1 {
+        0b00 => format!("{} {}", SHIFT_ROTATE[oct2 as usize], r8),
+        0b01 => format!("BIT {}, {}", oct2, r8),
+        0b10 => format!("RES {}, {}", oct2, r8),
+        _ => format!("SET {}, {}", oct2, r8),
+    };
+    Instruction {
+        address,
+        bytes: vec![0xcb, opcode],
+        mnemonic,
+        length: 2,
+        cycles: CB_OPCODE_CYCLES[opcode as usize],
+        branch_cycles: None,
+    }
+}
+
+/// Disassemble a block of memory, one instruction after the other
+///
+/// # Arguments
+///
+/// * `bytes` - Memory to disassemble
+/// * `address` - Address of the first byte
+///
+pub fn disassemble(bytes: &[u8], address: u16) -> Vec<Instruction> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < bytes.len() {
+        let instruction = decode(&bytes[offset..], address.wrapping_add(offset as u16));
+        offset += instruction.length as usize;
+        instructions.push(instruction);
+    }
+    instructions
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Length of every opcode, invalid opcodes are shown as a single DB byte
+    const LENGTHS: [u8; 256] = [
+        1, 3, 1, 1, 1, 1, 2, 1, 3, 1, 1, 1, 1, 1, 2, 1, // 0x00
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x10
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x20
+        2, 3, 1, 1, 1, 1, 2, 1, 2, 1, 1, 1, 1, 1, 2, 1, // 0x30
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x40
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x50
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x60
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x70
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x80
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0x90
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xA0
+        1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 1, // 0xB0
+        1, 1, 3, 3, 3, 1, 2, 1, 1, 1, 3, 2, 3, 3, 2, 1, // 0xC0
+        1, 1, 3, 1, 3, 1, 2, 1, 1, 1, 3, 1, 3, 1, 2, 1, // 0xD0
+        2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 0xE0
+        2, 1, 1, 1, 1, 1, 2, 1, 2, 1, 3, 1, 1, 1, 2, 1, // 0xF0
+    ];
+
+    fn mnemonic(bytes: &[u8], address: u16) -> String {
+        decode(bytes, address).mnemonic
+    }
+
+    #[test]
+    fn every_opcode_has_its_length_and_cycles() {
+        for opcode in 0..=255u8 {
+            let instruction = decode(&[opcode, 0x00, 0x00], 0);
+            assert_eq!(instruction.length, LENGTHS[opcode as usize], "opcode 0x{:02X}", opcode);
+            assert_eq!(instruction.bytes.len(), instruction.length as usize, "opcode 0x{:02X}", opcode);
+            // The CB prefix has no timing of its own, it is counted in CB_OPCODE_CYCLES
+            let invalid = OPCODE_CYCLES[opcode as usize] == 0 && opcode != 0xcb;
+            assert_eq!(instruction.mnemonic.starts_with("DB "), invalid, "opcode 0x{:02X}", opcode);
+            if opcode != 0xcb {
+                assert_eq!(instruction.cycles, OPCODE_CYCLES[opcode as usize], "opcode 0x{:02X}", opcode);
+            }
+            // Only the conditional jumps, calls and returns have two timings
+            let conditional = matches!(opcode, 0x20 | 0x28 | 0x30 | 0x38 | 0xc0 | 0xc2 | 0xc4 | 0xc8 | 0xca | 0xcc
+                | 0xd0 | 0xd2 | 0xd4 | 0xd8 | 0xda | 0xdc);
+            assert_eq!(instruction.branch_cycles.is_some(), conditional, "opcode 0x{:02X}", opcode);
+        }
+        for opcode in 0..=255u8 {
+            let instruction = decode(&[0xcb, opcode], 0);
+            assert_eq!(instruction.length, 2);
+            assert_eq!(instruction.cycles, CB_OPCODE_CYCLES[opcode as usize], "opcode 0xCB 0x{:02X}", opcode);
+        }
+    }
+
+    #[test]
+    fn known_mnemonics() {
+        assert_eq!(mnemonic(&[0x00], 0), "NOP");
+        assert_eq!(mnemonic(&[0x01, 0x34, 0x12], 0), "LD BC, $1234");
+        assert_eq!(mnemonic(&[0x18, 0xfe], 0x0150), "JR $0150");
+        assert_eq!(mnemonic(&[0x20, 0x05], 0x0150), "JR NZ, $0157");
+        assert_eq!(mnemonic(&[0x22], 0), "LD [HL+], A");
+        assert_eq!(mnemonic(&[0x76], 0), "HALT");
+        assert_eq!(mnemonic(&[0xae], 0), "XOR A, [HL]");
+        assert_eq!(mnemonic(&[0xe0, 0x44], 0), "LDH [$FF44], A");
+        assert_eq!(mnemonic(&[0xf8, 0xfe], 0), "LD HL, SP-2");
+        assert_eq!(mnemonic(&[0xf1], 0), "POP AF");
+        assert_eq!(mnemonic(&[0xff], 0), "RST $38");
+        assert_eq!(mnemonic(&[0xcb, 0x7c], 0), "BIT 7, H");
+        assert_eq!(mnemonic(&[0xcb, 0x37], 0), "SWAP A");
+        assert_eq!(mnemonic(&[0xd3], 0), "DB $D3");
+    }
+
+    #[test]
+    fn conditional_timing() {
+        let instruction = decode(&[0xc2, 0x00, 0x01], 0);
+        assert_eq!((instruction.cycles, instruction.branch_cycles), (12, Some(16)));
+        assert_eq!(instruction.to_string(), "0000: C2 00 01  JP NZ, $0100         ; 16/12");
+    }
+
+    #[test]
+    fn disassemble_follows_lengths() {
+        let instructions = disassemble(&[0x3e, 0x01, 0xc3, 0x50, 0x01, 0xcb, 0x11], 0x0100);
+        let addresses: Vec<u16> = instructions.iter().map(|instruction| instruction.address).collect();
+        assert_eq!(addresses, [0x0100, 0x0102, 0x0105]);
+        assert_eq!(instructions[2].mnemonic, "RL C");
+    }
+}
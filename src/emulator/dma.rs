@@ -0,0 +1,51 @@
+//! OAM DMA (0xFF46)
+//!
+//! Writing the upper byte of a source address copies 160 bytes from it to OAM, one byte every
+//! M-cycle. While the copy runs, the CPU can only access HRAM.
+
+const LENGTH: u16 = 0xa0;
+const CYCLES_PER_BYTE: u8 = 4;
+
+#[derive(Debug)]
+pub struct Dma {
+    source: u8, // Last value written, read back from the register
+    progress: Option<u16>, // Bytes copied by the running transfer
+    cycles: u8, // T-cycles towards the next byte
+}
+
+impl Dma {
+    pub fn new() -> Self {
+        Self { source: 0xff, progress: None, cycles: 0 }
+    }
+
+    pub fn read(&self) -> u8 {
+        self.source
+    }
+
+    pub fn write(&mut self, value: u8) {
+        self.source = value;
+        self.progress = Some(0);
+        self.cycles = 0;
+    }
+
+    pub fn active(&self) -> bool {
+        self.progress.is_some()
+    }
+
+    /// Advance the transfer by a number of T-cycles
+    /// Returns the (source, OAM offset) pairs of the bytes to copy
+    pub fn tick(&mut self, cycles: u8) -> Vec<(u16, usize)> {
+        let mut copies = Vec::new();
+        let Some(mut progress) = self.progress else {
+            return copies;
+        };
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_BYTE && progress < LENGTH {
+            self.cycles -= CYCLES_PER_BYTE;
+            copies.push(((self.source as u16) << 8 | progress, progress as usize));
+            progress += 1;
+        }
+        self.progress = (progress < LENGTH).then_some(progress);
+        copies
+    }
+}
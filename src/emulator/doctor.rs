@@ -0,0 +1,214 @@
+//! CPU state log in the Gameboy Doctor format (https://github.com/robert/gameboy-doctor)
+//!
+//! One line is written before every instruction:
+//! `A:00 F:11 B:22 C:33 D:44 E:55 H:66 L:77 SP:8888 PC:9999 PCMEM:AA,BB,CC,DD`
+//! The log can also be compared live against a reference log from another emulator, stopping at
+//! the first line that differs.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Lines, Write};
+
+use super::cpu::CPU;
+use super::mmu::MMU;
+
+/// First line where the log differs from the reference log
+#[derive(Debug)]
+pub struct Divergence {
+    pub line: usize,
+    pub previous: Option<String>,
+    pub expected: String,
+    pub actual: String,
+}
+
+impl fmt::Display for Divergence {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "Divergence from the reference log at line {}", self.line)?;
+        if let Some(previous) = &self.previous {
+            writeln!(f, "  previous: {}", previous)?;
+        }
+        writeln!(f, "  expected: {}", self.expected)?;
+        writeln!(f, "  actual:   {}", self.actual)?;
+        // Point out which registers differ
+        let fields: Vec<&str> = self
+            .expected
+            .split_whitespace()
+            .zip(self.actual.split_whitespace())
+            .filter(|(expected, actual)| expected != actual)
+            .filter_map(|(expected, _)| expected.split(':').next())
+            .collect();
+        write!(f, "  differs:  {}", fields.join(", "))
+    }
+}
+
+/// Reason the comparison with the reference log stopped
+#[derive(Debug)]
+pub enum DoctorError {
+    Divergence(Divergence),
+    // The reference log could not be read at this line
+    Reference { line: usize, error: io::Error },
+}
+
+impl fmt::Display for DoctorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DoctorError::Divergence(divergence) => write!(f, "{}", divergence),
+            DoctorError::Reference { line, error } => write!(f, "Cannot read line {} of the reference log: {}", line, error),
+        }
+    }
+}
+
+impl std::error::Error for DoctorError {}
+
+pub struct Doctor {
+    output: Option<BufWriter<File>>,
+    reference: Option<Lines<BufReader<File>>>,
+    previous: Option<String>,
+    line: usize,
+}
+
+impl Doctor {
+    /// Create a doctor log
+    ///
+    /// # Arguments
+    ///
+    /// * `output` - File to write the log to
+    /// * `reference` - Reference log to compare against
+    ///
+    pub fn new(output: Option<&str>, reference: Option<&str>) -> io::Result<Self> {
+        let output = match output {
+            Some(path) => Some(BufWriter::new(File::create(path)?)),
+            None => None,
+        };
+        let reference = match reference {
+            Some(path) => Some(BufReader::new(File::open(path)?).lines()),
+            None => None,
+        };
+        Ok(Self { output, reference, previous: None, line: 0 })
+    }
+
+    /// Format the CPU state about to execute the instruction at PC
+    pub fn format(cpu: &CPU, mmu: &MMU) -> String {
+        let pcmem: Vec<String> = (0..4)
+            .map(|offset| format!("{:02X}", mmu.read_memory(cpu.pc.wrapping_add(offset))))
+            .collect();
+        format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X} SP:{:04X} PC:{:04X} PCMEM:{}",
+            cpu.a, cpu.f(), cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l, cpu.sp, cpu.pc, pcmem.join(",")
+        )
+    }
+
+    /// Log the CPU state, and compare it to the next line of the reference log if there is one
+    /// Returns an error if the state does not match the reference or the reference cannot be read
+    pub fn log(&mut self, cpu: &CPU, mmu: &MMU) -> Result<(), DoctorError> {
+        let state = Self::format(cpu, mmu);
+        self.line += 1;
+        if let Some(output) = &mut self.output {
+            // A failed write only truncates the log, it should not stop the emulator
+            let _ = writeln!(output, "{}", state);
+        }
+        // Once the reference is exhausted, there is nothing left to compare against
+        match self.reference.as_mut().and_then(|reference| reference.next()) {
+            Some(Ok(expected)) => {
+                let expected = expected.trim().to_string();
+                if expected != state {
+                    return Err(DoctorError::Divergence(Divergence {
+                        line: self.line,
+                        previous: self.previous.take(),
+                        expected,
+                        actual: state,
+                    }));
+                }
+            }
+            Some(Err(error)) => return Err(DoctorError::Reference { line: self.line, error }),
+            None => {}
+        }
+        if self.reference.is_some() {
+            self.previous = Some(state);
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) {
+        if let Some(output) = &mut self.output {
+            let _ = output.flush();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::cpu::Flags;
+    use std::env;
+    use std::fs;
+
+    // CPU at 0xC000 in work RAM, with distinct registers and 4 bytes of code
+    fn state() -> (CPU, MMU) {
+        let mut cpu = CPU::new();
+        (cpu.a, cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l) = (0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07);
+        cpu.flags = Flags::from_bits(0xb0);
+        cpu.sp = 0xfffe;
+        cpu.pc = 0xc000;
+        let mut mmu = MMU::new();
+        for (offset, byte) in [0x3e, 0x12, 0xc3, 0x50].into_iter().enumerate() {
+            mmu.write_memory(0xc000 + offset as u16, byte);
+        }
+        (cpu, mmu)
+    }
+
+    #[test]
+    fn line_format() {
+        let (cpu, mmu) = state();
+        assert_eq!(
+            Doctor::format(&cpu, &mmu),
+            "A:01 F:B0 B:02 C:03 D:04 E:05 H:06 L:07 SP:FFFE PC:C000 PCMEM:3E,12,C3,50"
+        );
+    }
+
+    #[test]
+    fn writes_log_and_reports_divergence() {
+        let dir = env::temp_dir();
+        let output = dir.join(format!("doctor-{}.log", std::process::id()));
+        let reference = dir.join(format!("doctor-reference-{}.log", std::process::id()));
+        let (mut cpu, mmu) = state();
+        let first = Doctor::format(&cpu, &mmu);
+        fs::write(&reference, format!("{}\n{}\n", first, first.replace("A:01", "A:02"))).unwrap();
+
+        let mut doctor = Doctor::new(output.to_str(), reference.to_str()).unwrap();
+        assert!(doctor.log(&cpu, &mmu).is_ok());
+        cpu.b = 0x22;
+        let Err(DoctorError::Divergence(divergence)) = doctor.log(&cpu, &mmu) else {
+            panic!("expected a divergence");
+        };
+        assert_eq!(divergence.line, 2);
+        assert_eq!(divergence.previous.as_deref(), Some(first.as_str()));
+        assert!(divergence.to_string().ends_with("differs:  A, B"));
+        // Past the end of the reference there is nothing to compare against
+        assert!(doctor.log(&cpu, &mmu).is_ok());
+        doctor.flush();
+
+        let log = fs::read_to_string(&output).unwrap();
+        assert_eq!(log.lines().count(), 3);
+        assert_eq!(log.lines().next(), Some(first.as_str()));
+        fs::remove_file(output).unwrap();
+        fs::remove_file(reference).unwrap();
+    }
+
+    #[test]
+    fn reports_unreadable_reference() {
+        let reference = env::temp_dir().join(format!("doctor-invalid-{}.log", std::process::id()));
+        let (cpu, mmu) = state();
+        // The second line is not valid UTF-8
+        let mut contents = format!("{}\n", Doctor::format(&cpu, &mmu)).into_bytes();
+        contents.extend([0xff, 0xfe, b'\n']);
+        fs::write(&reference, contents).unwrap();
+
+        let mut doctor = Doctor::new(None, reference.to_str()).unwrap();
+        assert!(doctor.log(&cpu, &mmu).is_ok());
+        let err = doctor.log(&cpu, &mmu).unwrap_err();
+        assert!(matches!(err, DoctorError::Reference { line: 2, .. }));
+        assert!(err.to_string().starts_with("Cannot read line 2 of the reference log: "));
+        fs::remove_file(reference).unwrap();
+    }
+}
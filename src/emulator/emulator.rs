@@ -1,3 +1,4 @@
+use super::bus::Bus;
 use super::cpu;
 use super::ppu;
 use super::mmu;
@@ -8,6 +9,33 @@ pub struct Emulator {
     pub mmu: mmu::MMU,
 }
 
+// Bus seen by the CPU, ticking the PPU along with the memory mapped hardware so writes made in
+// the middle of a line are seen by the PPU at the right dot
+struct SystemBus<'a> {
+    mmu: &'a mut mmu::MMU,
+    ppu: &'a mut ppu::PPU,
+}
+
+impl Bus for SystemBus<'_> {
+    fn read(&mut self, address: u16) -> u8 {
+        self.mmu.read_memory(address)
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.mmu.write_memory(address, value)
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.mmu.tick(cycles);
+        let cycles = self.mmu.normal_speed_cycles(cycles);
+        self.ppu.tick(self.mmu, cycles);
+    }
+
+    fn switch_speed(&mut self) -> bool {
+        self.mmu.switch_speed()
+    }
+}
+
 impl Emulator {
     pub fn new() -> Self {
         let mmu = mmu::MMU::new();
@@ -15,5 +43,12 @@ impl Emulator {
         let ppu = ppu::PPU::new();
         Self { cpu, ppu, mmu }
     }
-}
 
+    /// Run a single CPU instruction, the PPU and the rest of the hardware advance on every machine
+    /// cycle of it
+    /// Returns the number of T-cycles it took, the total is kept in `cpu.cycles`
+    pub fn step(&mut self) -> u8 {
+        let mut bus = SystemBus { mmu: &mut self.mmu, ppu: &mut self.ppu };
+        self.cpu.step(&mut bus)
+    }
+}
@@ -0,0 +1,262 @@
+//! Pixel FIFO renderer
+//!
+//! Draws a line one dot at a time like the hardware does during mode 3. The background fetcher
+//! reads the tile number, the two bytes of tile data and pushes 8 pixels into the background FIFO,
+//! every step but the push taking 2 dots. A pixel is shifted out of the FIFOs every dot, so
+//! registers written in the middle of a line only affect the pixels after the write.
+//!
+//! Mode 3 lasts at least 172 dots and gets longer when:
+//! * SCX % 8 pixels are shifted out and discarded at the start of the line
+//! * the window starts, the background FIFO is cleared and the fetcher restarts (6 dots)
+//! * an object is reached, the background fetcher finishes its tile and the object is fetched
+//!   (6 to 11 dots)
+
+use std::collections::VecDeque;
+
+use super::lcd::*;
+use super::mmu::MMU;
+use super::ppu::{Object, OBJ_BEHIND_BG, OBJ_PALETTE_1, PPU, SCREEN_WIDTH};
+
+// Dots spent on the first fetch of a line, whose pixels are thrown away
+const DUMMY_FETCH_DOTS: u8 = 6;
+// Dots spent fetching the tile data of an object, once the background fetcher is done
+const OBJECT_FETCH_DOTS: u8 = 6;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FetchStep {
+    Tile,
+    DataLow,
+    DataHigh,
+    Push,
+}
+
+/// Pixel in the object FIFO
+#[derive(Debug, Clone, Copy, Default)]
+struct ObjectPixel {
+    color: u8, // 0 is transparent
+    palette_1: bool, // OBP1 instead of OBP0, the register is read when the pixel is output
+    behind_bg: bool,
+}
+
+pub struct Fifo {
+    bg_fifo: VecDeque<u8>, // Color indexes
+    obj_fifo: VecDeque<ObjectPixel>,
+    step: FetchStep,
+    step_dots: u8, // Dots spent on the current fetcher step
+    fetcher_x: u8, // Tile column fetched next, relative to SCX or to the left of the window
+    tile: u8,
+    data_low: u8,
+    data_high: u8,
+    delay: u8, // Dots left in the dummy fetch
+    discard: u8, // Pixels left to throw away for the fine scroll
+    x: usize, // Next pixel of the line to output
+    objects: Vec<Object>, // Objects on the line, in drawing order
+    next_object: usize,
+    object_fetch: Option<u8>, // Dots left in the current object fetch
+    in_window: bool,
+    window_drawn: bool, // The window showed up on the current line
+    window_triggered: bool, // LY matched WY during this frame
+    window_line: u8,
+}
+
+impl Fifo {
+    pub fn new() -> Self {
+        Self {
+            bg_fifo: VecDeque::with_capacity(16),
+            obj_fifo: VecDeque::with_capacity(8),
+            step: FetchStep::Tile,
+            step_dots: 0,
+            fetcher_x: 0,
+            tile: 0,
+            data_low: 0,
+            data_high: 0,
+            delay: 0,
+            discard: 0,
+            x: 0,
+            objects: Vec::with_capacity(10),
+            next_object: 0,
+            object_fetch: None,
+            in_window: false,
+            window_drawn: false,
+            window_triggered: false,
+            window_line: 0,
+        }
+    }
+
+    /// Reset the window state at the start of a frame
+    pub fn start_frame(&mut self) {
+        self.window_triggered = false;
+        self.window_line = 0;
+    }
+
+    /// Prepare drawing a line at the start of mode 3
+    ///
+    /// # Arguments
+    ///
+    /// * `mmu` - Memory holding the LCD registers and OAM
+    ///
+    pub fn start_line(&mut self, mmu: &MMU) {
+        let lcd = &mmu.lcd;
+        if lcd.ly == lcd.wy {
+            self.window_triggered = true;
+        }
+        self.bg_fifo.clear();
+        self.obj_fifo.clear();
+        self.step = FetchStep::Tile;
+        self.step_dots = 0;
+        self.fetcher_x = 0;
+        self.delay = DUMMY_FETCH_DOTS;
+        self.discard = lcd.scx % 8;
+        self.x = 0;
+        self.objects = PPU::scan_objects(mmu);
+        self.next_object = 0;
+        self.object_fetch = None;
+        self.in_window = false;
+        self.window_drawn = false;
+    }
+
+    /// Run mode 3 for one dot, returns true once the whole line is drawn
+    ///
+    /// # Arguments
+    ///
+    /// * `mmu` - Memory holding the LCD registers, VRAM and OAM
+    /// * `line` - Shades of the line being drawn
+    ///
+    pub fn dot(&mut self, mmu: &MMU, line: &mut [u8]) -> bool {
+        if self.delay > 0 {
+            self.delay -= 1;
+            return false;
+        }
+        let lcdc = mmu.lcd.lcdc;
+
+        // The window takes over once its left edge is reached, WX is 7 more than the first column
+        if !self.in_window
+            && lcdc & LCDC_WINDOW_ENABLE != 0
+            && self.window_triggered
+            && self.discard == 0
+            && self.x + 7 >= mmu.lcd.wx as usize
+        {
+            self.in_window = true;
+            self.window_drawn = true;
+            self.bg_fifo.clear();
+            self.step = FetchStep::Tile;
+            self.step_dots = 0;
+            self.fetcher_x = 0;
+        }
+
+        // Shifting stops while the object at the current pixel is fetched
+        if self.object_fetch.is_none() && self.discard == 0 && lcdc & LCDC_OBJ_ENABLE != 0 {
+            if let Some(object) = self.objects.get(self.next_object) {
+                if object.x <= self.x as i16 {
+                    self.object_fetch = Some(OBJECT_FETCH_DOTS);
+                }
+            }
+        }
+        if let Some(dots) = self.object_fetch {
+            // The background fetcher finishes its tile before the object is fetched
+            if self.step != FetchStep::Push || self.bg_fifo.is_empty() {
+                self.fetch(mmu);
+            } else if dots > 1 {
+                self.object_fetch = Some(dots - 1);
+            } else {
+                self.object_fetch = None;
+                self.push_object(mmu);
+            }
+            return false;
+        }
+
+        self.fetch(mmu);
+        let Some(bg_color) = self.bg_fifo.pop_front() else {
+            return false;
+        };
+        if self.discard > 0 {
+            self.discard -= 1;
+            return false;
+        }
+        let object = self.obj_fifo.pop_front().unwrap_or_default();
+        // The background and window are blank when LCDC bit 0 is cleared
+        let bg_color = if lcdc & LCDC_BG_ENABLE != 0 { bg_color } else { 0 };
+        line[self.x] = if object.color != 0 && lcdc & LCDC_OBJ_ENABLE != 0 && !(object.behind_bg && bg_color != 0) {
+            PPU::shade(if object.palette_1 { mmu.lcd.obp1 } else { mmu.lcd.obp0 }, object.color)
+        } else {
+            PPU::shade(mmu.lcd.bgp, bg_color)
+        };
+        self.x += 1;
+        if self.x == SCREEN_WIDTH {
+            if self.window_drawn {
+                self.window_line += 1;
+            }
+            return true;
+        }
+        false
+    }
+
+    // Advance the background fetcher by a dot
+    fn fetch(&mut self, mmu: &MMU) {
+        let lcd = &mmu.lcd;
+        self.step_dots += 1;
+        match self.step {
+            FetchStep::Tile if self.step_dots == 2 => {
+                let (map, column, row) = if self.in_window {
+                    (if lcd.lcdc & LCDC_WINDOW_MAP != 0 { 0x9c00 } else { 0x9800 }, self.fetcher_x, self.window_line)
+                } else {
+                    let map = if lcd.lcdc & LCDC_BG_MAP != 0 { 0x9c00 } else { 0x9800 };
+                    (map, (lcd.scx / 8).wrapping_add(self.fetcher_x) % 32, lcd.scy.wrapping_add(lcd.ly))
+                };
+                self.tile = mmu.vram[map - 0x8000 + (row as usize / 8) * 32 + column as usize % 32];
+                self.next_step(FetchStep::DataLow);
+            }
+            FetchStep::DataLow if self.step_dots == 2 => {
+                self.data_low = mmu.vram[self.tile_row(mmu)];
+                self.next_step(FetchStep::DataHigh);
+            }
+            FetchStep::DataHigh if self.step_dots == 2 => {
+                self.data_high = mmu.vram[self.tile_row(mmu) + 1];
+                self.next_step(FetchStep::Push);
+            }
+            // Pixels are only pushed into an empty FIFO
+            FetchStep::Push if self.bg_fifo.is_empty() => {
+                for bit in (0..8).rev() {
+                    self.bg_fifo.push_back(((self.data_high >> bit) & 1) << 1 | (self.data_low >> bit) & 1);
+                }
+                self.fetcher_x = self.fetcher_x.wrapping_add(1);
+                self.next_step(FetchStep::Tile);
+            }
+            _ => {}
+        }
+    }
+
+    fn next_step(&mut self, step: FetchStep) {
+        self.step = step;
+        self.step_dots = 0;
+    }
+
+    // Offset in VRAM of the row of the fetched background or window tile
+    fn tile_row(&self, mmu: &MMU) -> usize {
+        let lcd = &mmu.lcd;
+        let y = if self.in_window { self.window_line } else { lcd.scy.wrapping_add(lcd.ly) };
+        (PPU::bg_tile_address(lcd.lcdc, self.tile) - 0x8000) as usize + (y % 8) as usize * 2
+    }
+
+    // Fetch the object at the current pixel and mix it into the object FIFO, pixels of objects
+    // fetched earlier stay on top
+    fn push_object(&mut self, mmu: &MMU) {
+        let object = self.objects[self.next_object];
+        self.next_object += 1;
+        let colors = PPU::object_row(mmu, &object);
+        while self.obj_fifo.len() < 8 {
+            self.obj_fifo.push_back(ObjectPixel::default());
+        }
+        // Pixels left of the screen were never shifted in
+        let hidden = (self.x as i16 - object.x) as usize;
+        for (pixel, &color) in self.obj_fifo.iter_mut().zip(colors.iter().skip(hidden)) {
+            if pixel.color == 0 && color != 0 {
+                *pixel = ObjectPixel {
+                    color,
+                    palette_1: object.flags & OBJ_PALETTE_1 != 0,
+                    behind_bg: object.flags & OBJ_BEHIND_BG != 0,
+                };
+            }
+        }
+    }
+}
@@ -0,0 +1,76 @@
+// Address of the IF (interrupt flag) register
+pub const IF_ADDRESS: u16 = 0xff0f;
+// Address of the IE (interrupt enable) register
+pub const IE_ADDRESS: u16 = 0xffff;
+
+/// Interrupt sources, the value is the bit used in the IE and IF registers. A lower bit has a
+/// higher priority when several interrupts are pending at once
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    VBlank = 0,
+    LcdStat = 1,
+    Timer = 2,
+    Serial = 3,
+    Joypad = 4,
+}
+
+impl Interrupt {
+    /// Return the highest priority interrupt set in `pending`, if any
+    ///
+    /// # Arguments
+    ///
+    /// * `pending` - IE & IF
+    ///
+    pub fn highest_priority(pending: u8) -> Option<Interrupt> {
+        match pending & 0x1f {
+            0 => None,
+            bits => match bits.trailing_zeros() {
+                0 => Some(Interrupt::VBlank),
+                1 => Some(Interrupt::LcdStat),
+                2 => Some(Interrupt::Timer),
+                3 => Some(Interrupt::Serial),
+                _ => Some(Interrupt::Joypad),
+            },
+        }
+    }
+
+    // Bit of the interrupt in IE/IF
+    pub fn mask(self) -> u8 {
+        1 << self as u8
+    }
+
+    // Address the CPU jumps to when servicing the interrupt, 0x40 to 0x60
+    pub fn vector(self) -> u16 {
+        0x40 + 8 * self as u16
+    }
+}
+
+/// Storage for the IE and IF registers
+#[derive(Debug)]
+pub struct Interrupts {
+    pub enable: u8, //IE, 0xFFFF
+    pub flag: u8, //IF, 0xFF0F
+}
+
+impl Interrupts {
+    pub fn new() -> Self {
+        Self {
+            enable: 0,
+            flag: 0,
+        }
+    }
+
+    /// Raise an interrupt by setting its bit in IF
+    pub fn request(&mut self, interrupt: Interrupt) {
+        self.flag |= interrupt.mask();
+    }
+
+    // The upper 3 bits of IF are not connected and always read as 1
+    pub fn read_flag(&self) -> u8 {
+        self.flag | 0xe0
+    }
+
+    pub fn write_flag(&mut self, value: u8) {
+        self.flag = value & 0x1f;
+    }
+}
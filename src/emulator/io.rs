@@ -0,0 +1,134 @@
+//! I/O registers at 0xFF00-0xFF7F
+//!
+//! Every address is decoded into the register it belongs to, and `MMU` routes it to the component
+//! owning its state. Unused bits are not stored by the components, they are set here on every read.
+
+/// An I/O register, as decoded from its address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoRegister {
+    P1, // Joypad
+    SB, // Serial data
+    SC, // Serial control
+    DIV,
+    TIMA,
+    TMA,
+    TAC,
+    IF,
+    Audio(u8), // NR10-NR52, offset from 0xFF10
+    WaveRam(u8), // Offset from 0xFF30
+    LCDC,
+    STAT,
+    SCY,
+    SCX,
+    LY,
+    LYC,
+    DMA,
+    BGP,
+    OBP0,
+    OBP1,
+    WY,
+    WX,
+    KEY1,
+    VBK,
+    Boot, // Boot ROM disable
+    HDMA(u8), // HDMA1-HDMA5, offset from 0xFF51
+    RP,
+    BCPS,
+    BCPD,
+    OCPS,
+    OCPD,
+    OPRI,
+    SVBK,
+    Unmapped,
+}
+
+impl IoRegister {
+    pub fn from_address(address: u16) -> Self {
+        match address {
+            0xff00 => IoRegister::P1,
+            0xff01 => IoRegister::SB,
+            0xff02 => IoRegister::SC,
+            0xff04 => IoRegister::DIV,
+            0xff05 => IoRegister::TIMA,
+            0xff06 => IoRegister::TMA,
+            0xff07 => IoRegister::TAC,
+            0xff0f => IoRegister::IF,
+            0xff10..=0xff26 => IoRegister::Audio((address - 0xff10) as u8),
+            0xff30..=0xff3f => IoRegister::WaveRam((address - 0xff30) as u8),
+            0xff40 => IoRegister::LCDC,
+            0xff41 => IoRegister::STAT,
+            0xff42 => IoRegister::SCY,
+            0xff43 => IoRegister::SCX,
+            0xff44 => IoRegister::LY,
+            0xff45 => IoRegister::LYC,
+            0xff46 => IoRegister::DMA,
+            0xff47 => IoRegister::BGP,
+            0xff48 => IoRegister::OBP0,
+            0xff49 => IoRegister::OBP1,
+            0xff4a => IoRegister::WY,
+            0xff4b => IoRegister::WX,
+            0xff4d => IoRegister::KEY1,
+            0xff4f => IoRegister::VBK,
+            0xff50 => IoRegister::Boot,
+            0xff51..=0xff55 => IoRegister::HDMA((address - 0xff51) as u8),
+            0xff56 => IoRegister::RP,
+            0xff68 => IoRegister::BCPS,
+            0xff69 => IoRegister::BCPD,
+            0xff6a => IoRegister::OCPS,
+            0xff6b => IoRegister::OCPD,
+            0xff6c => IoRegister::OPRI,
+            0xff70 => IoRegister::SVBK,
+            _ => IoRegister::Unmapped,
+        }
+    }
+
+    /// Bits that are not connected and always read as 1
+    pub fn read_mask(self) -> u8 {
+        match self {
+            IoRegister::P1 => 0xc0,
+            IoRegister::SC => 0x7e,
+            IoRegister::TAC => 0xf8,
+            IoRegister::IF => 0xe0,
+            IoRegister::Audio(offset) => AUDIO_READ_MASKS[offset as usize],
+            IoRegister::STAT => 0x80,
+            IoRegister::SB
+            | IoRegister::DIV
+            | IoRegister::TIMA
+            | IoRegister::TMA
+            | IoRegister::WaveRam(_)
+            | IoRegister::LCDC
+            | IoRegister::SCY
+            | IoRegister::SCX
+            | IoRegister::LY
+            | IoRegister::LYC
+            | IoRegister::DMA
+            | IoRegister::BGP
+            | IoRegister::OBP0
+            | IoRegister::OBP1
+            | IoRegister::WY
+            | IoRegister::WX => 0x00,
+            // CGB registers read 0xFF outside CGB mode, in CGB mode their owners set the unused bits
+            IoRegister::KEY1
+            | IoRegister::VBK
+            | IoRegister::HDMA(_)
+            | IoRegister::RP
+            | IoRegister::BCPS
+            | IoRegister::BCPD
+            | IoRegister::OCPS
+            | IoRegister::OCPD
+            | IoRegister::OPRI
+            | IoRegister::SVBK => 0x00,
+            IoRegister::Boot | IoRegister::Unmapped => 0xff,
+        }
+    }
+}
+
+// NR10-NR52, write only bits read as 1 as well as the unused ones
+#[rustfmt::skip]
+const AUDIO_READ_MASKS: [u8; 0x17] = [
+    0x80, 0x3f, 0x00, 0xff, 0xbf, // NR10-NR14
+    0xff, 0x3f, 0x00, 0xff, 0xbf, // Unused, NR21-NR24
+    0x7f, 0xff, 0x9f, 0xff, 0xbf, // NR30-NR34
+    0xff, 0xff, 0x00, 0x00, 0xbf, // Unused, NR41-NR44
+    0x00, 0x00, 0x70,             // NR50-NR52
+];
@@ -0,0 +1,86 @@
+//! Joypad, P1/JOYP (0xFF00)
+//!
+//! The eight keys are wired as a 2x4 matrix. Clearing bit 4 of P1 selects the direction keys,
+//! clearing bit 5 the action buttons, and the lower nibble reads the selected keys, with 0 meaning
+//! pressed. The joypad interrupt is raised when one of the lower four lines goes from high to low.
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Button {
+    Right,
+    Left,
+    Up,
+    Down,
+    A,
+    B,
+    Select,
+    Start,
+}
+
+impl Button {
+    pub const ALL: [Button; 8] = [
+        Button::Right,
+        Button::Left,
+        Button::Up,
+        Button::Down,
+        Button::A,
+        Button::B,
+        Button::Select,
+        Button::Start,
+    ];
+
+    // Bit of the button in `Joypad::pressed`, directions in the lower nibble, actions in the upper
+    fn mask(self) -> u8 {
+        1 << self as u8
+    }
+}
+
+const SELECT_DIRECTIONS: u8 = 0x10;
+const SELECT_ACTIONS: u8 = 0x20;
+
+#[derive(Debug)]
+pub struct Joypad {
+    select: u8, // Bits 4-5 of P1
+    pressed: u8, // One bit per button, set when pressed
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Self { select: 0x30, pressed: 0 }
+    }
+
+    // Lower four lines of P1, low when a selected key is pressed
+    fn lines(&self) -> u8 {
+        let mut pressed = 0;
+        if self.select & SELECT_DIRECTIONS == 0 {
+            pressed |= self.pressed & 0x0f;
+        }
+        if self.select & SELECT_ACTIONS == 0 {
+            pressed |= self.pressed >> 4;
+        }
+        !pressed & 0x0f
+    }
+
+    pub fn read(&self) -> u8 {
+        self.select | self.lines()
+    }
+
+    /// Select the keys to read
+    /// Returns true if a line went low and the joypad interrupt should be raised
+    pub fn write(&mut self, value: u8) -> bool {
+        let lines = self.lines();
+        self.select = value & 0x30;
+        lines & !self.lines() != 0
+    }
+
+    /// Press or release a button
+    /// Returns true if a line went low and the joypad interrupt should be raised
+    pub fn set_button(&mut self, button: Button, pressed: bool) -> bool {
+        let lines = self.lines();
+        if pressed {
+            self.pressed |= button.mask();
+        } else {
+            self.pressed &= !button.mask();
+        }
+        lines & !self.lines() != 0
+    }
+}
@@ -0,0 +1,66 @@
+//! LCD registers, LCDC (0xFF40) to WX (0xFF4B) except DMA
+//!
+//! The registers are owned by the memory bus, the PPU reads them and updates LY and the mode.
+
+use super::ppu::Mode;
+
+// LCDC bits
+pub const LCDC_ENABLE: u8 = 0x80;
+pub const LCDC_WINDOW_MAP: u8 = 0x40; // Window tile map at 0x9C00 instead of 0x9800
+pub const LCDC_WINDOW_ENABLE: u8 = 0x20;
+pub const LCDC_TILE_DATA: u8 = 0x10; // Tiles at 0x8000 with unsigned indexes, instead of 0x9000 signed
+pub const LCDC_BG_MAP: u8 = 0x08; // Background tile map at 0x9C00 instead of 0x9800
+pub const LCDC_OBJ_SIZE: u8 = 0x04; // 8x16 objects instead of 8x8
+pub const LCDC_OBJ_ENABLE: u8 = 0x02;
+pub const LCDC_BG_ENABLE: u8 = 0x01; // Background and window, they are white when cleared
+
+// STAT interrupt selection bits
+pub const STAT_LYC: u8 = 0x40;
+pub const STAT_OAM: u8 = 0x20;
+pub const STAT_VBLANK: u8 = 0x10;
+pub const STAT_HBLANK: u8 = 0x08;
+
+#[derive(Debug)]
+pub struct Lcd {
+    pub lcdc: u8,
+    pub stat: u8, // Interrupt selection bits 3-6, the mode and LY=LYC bits are computed
+    pub scy: u8,
+    pub scx: u8,
+    pub ly: u8,
+    pub lyc: u8,
+    pub bgp: u8,
+    pub obp0: u8,
+    pub obp1: u8,
+    pub wy: u8,
+    pub wx: u8,
+    pub mode: Mode, // Blocks VRAM and OAM access while the PPU uses them
+}
+
+impl Lcd {
+    pub fn new() -> Self {
+        // Values after the DMG boot ROM
+        Self {
+            lcdc: 0x91,
+            stat: 0x00,
+            scy: 0,
+            scx: 0,
+            ly: 0,
+            lyc: 0,
+            bgp: 0xfc,
+            obp0: 0,
+            obp1: 0,
+            wy: 0,
+            wx: 0,
+            mode: Mode::HBlank,
+        }
+    }
+
+    pub fn read_stat(&self) -> u8 {
+        let coincidence = if self.ly == self.lyc { 0b100 } else { 0 };
+        self.stat | coincidence | self.mode as u8
+    }
+
+    pub fn write_stat(&mut self, value: u8) {
+        self.stat = value & 0x78;
+    }
+}
@@ -0,0 +1,111 @@
+//! Hudson HuC1, up to 1 MiB of ROM, 32 KiB of RAM and an infrared port
+//!
+//! Registers, selected by the address written to:
+//! * 0x0000-0x1FFF: 0x0E maps the infrared port at 0xA000-0xBFFF, any other value maps RAM
+//! * 0x2000-0x3FFF: ROM bank, 6 bits
+//! * 0x4000-0x5FFF: RAM bank, 2 bits
+//!
+//! Nothing is connected to the infrared port, the receiver never sees any light.
+
+use super::{bank_offset, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const IR_MODE: u8 = 0x0e;
+// Value read from the infrared port, bit 0 is set when light is received
+const IR_NO_LIGHT: u8 = 0xc0;
+
+pub struct HuC1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ir_mode: bool,
+    rom_bank: u8,
+    ram_bank: u8,
+}
+
+impl HuC1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        Self { rom, ram: vec![0; ram_size], ir_mode: false, rom_bank: 1, ram_bank: 0 }
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        Some(bank_offset(self.ram_bank as usize, RAM_BANK_SIZE, address as usize - 0xa000, self.ram.len()))
+    }
+}
+
+impl Mapper for HuC1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let (bank, offset) = match address {
+            0x0000..=0x3fff => (0, address as usize),
+            _ => (self.rom_bank as usize, address as usize - 0x4000),
+        };
+        self.rom[bank_offset(bank, ROM_BANK_SIZE, offset, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1fff => self.ir_mode = value & 0x0f == IR_MODE,
+            0x2000..=0x3fff => self.rom_bank = value & 0x3f,
+            0x4000..=0x5fff => self.ram_bank = value & 0b11,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.ir_mode {
+            return IR_NO_LIGHT;
+        }
+        self.ram_offset(address).map_or(0xff, |offset| self.ram[offset])
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        // Writes to the infrared port switch the LED, which nobody is looking at
+        if self.ir_mode {
+            return;
+        }
+        if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = value;
+        }
+    }
+
+    fn save_data(&self, _timestamp: u64) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mapper::banked_rom;
+
+    #[test]
+    fn rom_and_ram_banking() {
+        let mut huc = HuC1::new(banked_rom(64), 0x8000);
+        assert_eq!(huc.read_rom(0x4000), 1);
+        huc.write_rom(0x2000, 0x3f);
+        assert_eq!(huc.read_rom(0x4000), 0x3f);
+        // There is no bank 0 check
+        huc.write_rom(0x2000, 0x00);
+        assert_eq!(huc.read_rom(0x4000), 0);
+        // RAM needs no enable
+        huc.write_rom(0x4000, 0x03);
+        huc.write_ram(0xa000, 0x12);
+        assert_eq!(huc.save_data(0)[3 * RAM_BANK_SIZE], 0x12);
+    }
+
+    #[test]
+    fn infrared_port_replaces_ram() {
+        let mut huc = HuC1::new(banked_rom(2), 0x2000);
+        huc.write_rom(0x0000, IR_MODE);
+        assert_eq!(huc.read_ram(0xa000), IR_NO_LIGHT);
+        huc.write_ram(0xa000, 0x01);
+        huc.write_rom(0x0000, 0x00);
+        assert_eq!(huc.read_ram(0xa000), 0x00);
+    }
+}
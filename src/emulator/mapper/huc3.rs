@@ -0,0 +1,309 @@
+//! Hudson HuC3, up to 2 MiB of ROM, 128 KiB of RAM, a real time clock and an infrared port
+//!
+//! Registers, selected by the address written to:
+//! * 0x0000-0x1FFF: What is mapped at 0xA000-0xBFFF, see `Mode`
+//! * 0x2000-0x3FFF: ROM bank, 7 bits
+//! * 0x4000-0x5FFF: RAM bank, 4 bits
+//!
+//! The clock is driven through commands, written as a command in the upper nibble and an argument
+//! in the lower nibble. It has 256 half bytes of memory, the time is copied from and to its first
+//! bytes: the minute of the day in 0x00-0x02 and the day counter in 0x03-0x05, lower nibble first.
+
+use super::{bank_offset, unix_time, Mapper, CYCLES_PER_SECOND, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+// Minutes and days followed by the UNIX timestamp of the save, little endian, after the RAM
+const RTC_FOOTER_SIZE: usize = 16;
+const IR_NO_LIGHT: u8 = 0xc0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    RamReadOnly,
+    Ram,
+    RtcCommand, // Writes send a command to the clock
+    RtcResult, // Reads return the result of the last command
+    RtcReady, // Reads return whether the clock is ready for a command
+    Infrared,
+}
+
+impl Mode {
+    fn from_value(value: u8) -> Self {
+        match value & 0x0f {
+            0x0a => Mode::Ram,
+            0x0b => Mode::RtcCommand,
+            0x0c => Mode::RtcResult,
+            0x0d => Mode::RtcReady,
+            0x0e => Mode::Infrared,
+            _ => Mode::RamReadOnly,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Rtc {
+    memory: [u8; 256], // Half bytes
+    address: u8,
+    command: u8, // Last command
+    result: u8, // Half byte read by the last command
+    minutes: u64, // Minute of the day
+    days: u64,
+    sub_cycles: u64, // T-cycles since the last minute
+}
+
+impl Rtc {
+    fn new() -> Self {
+        Self { memory: [0; 256], address: 0, command: 0, result: 0, minutes: 0, days: 0, sub_cycles: 0 }
+    }
+
+    fn advance(&mut self, minutes: u64) {
+        let total = self.minutes + minutes;
+        self.minutes = total % MINUTES_PER_DAY;
+        self.days = (self.days + total / MINUTES_PER_DAY) & 0xfff;
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        const CYCLES_PER_MINUTE: u64 = CYCLES_PER_SECOND as u64 * 60;
+        self.sub_cycles += cycles as u64;
+        if self.sub_cycles >= CYCLES_PER_MINUTE {
+            self.sub_cycles -= CYCLES_PER_MINUTE;
+            self.advance(1);
+        }
+    }
+
+    fn command(&mut self, value: u8) {
+        let argument = value & 0x0f;
+        self.command = (value >> 4) & 0b111;
+        match self.command {
+            // Read and increment the address
+            0x1 => {
+                self.result = self.memory[self.address as usize];
+                self.address = self.address.wrapping_add(1);
+            }
+            // Write and increment the address
+            0x3 => {
+                self.memory[self.address as usize] = argument;
+                self.address = self.address.wrapping_add(1);
+            }
+            0x4 => self.address = (self.address & 0xf0) | argument,
+            0x5 => self.address = (self.address & 0x0f) | argument << 4,
+            0x6 => self.extended_command(argument),
+            _ => {}
+        }
+    }
+
+    fn extended_command(&mut self, argument: u8) {
+        match argument {
+            // Copy the time into memory
+            0x0 => {
+                for nibble in 0..3 {
+                    self.memory[nibble] = (self.minutes >> (nibble * 4)) as u8 & 0x0f;
+                    self.memory[3 + nibble] = (self.days >> (nibble * 4)) as u8 & 0x0f;
+                }
+            }
+            // Set the time from memory
+            0x1 => {
+                let read = |start: usize| (0..3).fold(0u64, |value, nibble| value | (self.memory[start + nibble] as u64) << (nibble * 4));
+                self.minutes = read(0) % MINUTES_PER_DAY;
+                self.days = read(3);
+                self.sub_cycles = 0;
+            }
+            // Status, the clock is always running
+            0x2 => self.result = 0x1,
+            _ => {}
+        }
+    }
+
+    fn footer(&self, timestamp: u64) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        footer.extend_from_slice(&(self.minutes as u32).to_le_bytes());
+        footer.extend_from_slice(&(self.days as u32).to_le_bytes());
+        footer.extend_from_slice(&timestamp.to_le_bytes());
+        footer
+    }
+
+    fn load_footer(&mut self, footer: &[u8]) {
+        self.minutes = u32::from_le_bytes(footer[0..4].try_into().unwrap()) as u64 % MINUTES_PER_DAY;
+        self.days = u32::from_le_bytes(footer[4..8].try_into().unwrap()) as u64 & 0xfff;
+        let timestamp = u64::from_le_bytes(footer[8..16].try_into().unwrap());
+        self.advance(unix_time().saturating_sub(timestamp) / 60);
+    }
+}
+
+pub struct HuC3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Rtc,
+    mode: Mode,
+    rom_bank: u8,
+    ram_bank: u8,
+}
+
+impl HuC3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        Self { rom, ram: vec![0; ram_size], rtc: Rtc::new(), mode: Mode::RamReadOnly, rom_bank: 1, ram_bank: 0 }
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        Some(bank_offset(self.ram_bank as usize, RAM_BANK_SIZE, address as usize - 0xa000, self.ram.len()))
+    }
+}
+
+impl Mapper for HuC3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let (bank, offset) = match address {
+            0x0000..=0x3fff => (0, address as usize),
+            _ => (self.rom_bank as usize, address as usize - 0x4000),
+        };
+        self.rom[bank_offset(bank, ROM_BANK_SIZE, offset, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1fff => self.mode = Mode::from_value(value),
+            0x2000..=0x3fff => self.rom_bank = value & 0x7f,
+            0x4000..=0x5fff => self.ram_bank = value & 0x0f,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        match self.mode {
+            Mode::Ram | Mode::RamReadOnly => self.ram_offset(address).map_or(0xff, |offset| self.ram[offset]),
+            Mode::RtcResult => 0x80 | self.rtc.command << 4 | self.rtc.result,
+            Mode::RtcReady => 0x01,
+            Mode::Infrared => IR_NO_LIGHT,
+            Mode::RtcCommand => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        match self.mode {
+            Mode::Ram => {
+                if let Some(offset) = self.ram_offset(address) {
+                    self.ram[offset] = value;
+                }
+            }
+            Mode::RtcCommand => self.rtc.command(value),
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        self.rtc.tick(cycles);
+    }
+
+    fn save_data(&self, timestamp: u64) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        data.extend(self.rtc.footer(timestamp));
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+        let footer = &data[len..];
+        if footer.len() == RTC_FOOTER_SIZE {
+            self.rtc.load_footer(footer);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mapper::banked_rom;
+
+    fn huc3() -> HuC3 {
+        let mut huc = HuC3::new(banked_rom(4), 0x2000);
+        huc.write_rom(0x0000, 0x0b);
+        huc
+    }
+
+    // Send a clock command and read its result
+    fn command(huc: &mut HuC3, value: u8) -> u8 {
+        huc.write_rom(0x0000, 0x0b);
+        huc.write_ram(0xa000, value);
+        huc.write_rom(0x0000, 0x0c);
+        huc.read_ram(0xa000)
+    }
+
+    fn set_time(huc: &mut HuC3, minutes: u16, days: u16) {
+        command(huc, 0x40);
+        command(huc, 0x50);
+        for value in [minutes, days] {
+            for nibble in 0..3 {
+                command(huc, 0x30 | (value >> (nibble * 4)) as u8 & 0x0f);
+            }
+        }
+        command(huc, 0x61);
+    }
+
+    // Minutes and days read back through the clock memory
+    fn read_time(huc: &mut HuC3) -> (u16, u16) {
+        command(huc, 0x60);
+        command(huc, 0x40);
+        command(huc, 0x50);
+        let mut read = || (0..3).fold(0, |value, nibble| value | ((command(huc, 0x10) & 0x0f) as u16) << (nibble * 4));
+        (read(), read())
+    }
+
+    #[test]
+    fn modes_select_what_is_mapped() {
+        let mut huc = HuC3::new(banked_rom(4), 0x2000);
+        // Read only until 0x0A
+        huc.write_ram(0xa000, 0x12);
+        assert_eq!(huc.read_ram(0xa000), 0x00);
+        huc.write_rom(0x0000, 0x0a);
+        huc.write_ram(0xa000, 0x12);
+        huc.write_rom(0x0000, 0x00);
+        assert_eq!(huc.read_ram(0xa000), 0x12);
+        huc.write_rom(0x0000, 0x0d);
+        assert_eq!(huc.read_ram(0xa000), 0x01);
+        huc.write_rom(0x0000, 0x0e);
+        assert_eq!(huc.read_ram(0xa000), IR_NO_LIGHT);
+        huc.write_rom(0x2000, 0x03);
+        assert_eq!(huc.read_rom(0x4000), 3);
+    }
+
+    #[test]
+    fn clock_set_and_read() {
+        let mut huc = huc3();
+        set_time(&mut huc, 0x123, 0x045);
+        assert_eq!(read_time(&mut huc), (0x123, 0x045));
+        // The result of a read carries the command
+        command(&mut huc, 0x40);
+        assert_eq!(command(&mut huc, 0x10), 0x80 | 0x10 | 0x3);
+        assert_eq!(command(&mut huc, 0x62), 0x80 | 0x60 | 0x1);
+    }
+
+    #[test]
+    fn clock_advances_every_minute_and_day() {
+        let mut huc = huc3();
+        set_time(&mut huc, MINUTES_PER_DAY as u16 - 1, 0xfff);
+        huc.rtc.sub_cycles = CYCLES_PER_SECOND as u64 * 60 - 4;
+        huc.tick(4);
+        // The 12 bit day counter wraps
+        assert_eq!(read_time(&mut huc), (0, 0));
+    }
+
+    #[test]
+    fn rtc_footer_round_trip() {
+        let mut huc = huc3();
+        set_time(&mut huc, 100, 200);
+        let timestamp = unix_time();
+        let data = huc.save_data(timestamp);
+        assert_eq!(data.len(), 0x2000 + RTC_FOOTER_SIZE);
+        assert_eq!(data[0x2000..0x2008], [100, 0, 0, 0, 200, 0, 0, 0]);
+        assert_eq!(data[0x2008..], timestamp.to_le_bytes());
+        let mut loaded = huc3();
+        loaded.load_save_data(&data);
+        assert_eq!((loaded.rtc.minutes, loaded.rtc.days), (100, 200));
+        // Saved two days and two minutes ago
+        let data = huc.save_data(timestamp - 2 * 86400 - 120);
+        loaded.load_save_data(&data);
+        assert_eq!((loaded.rtc.minutes, loaded.rtc.days), (102, 202));
+    }
+}
@@ -0,0 +1,168 @@
+//! MBC1, up to 2 MiB of ROM and 32 KiB of RAM
+//!
+//! Registers, selected by the address written to:
+//! * 0x0000-0x1FFF: RAM enable, 0x0A in the lower nibble enables RAM
+//! * 0x2000-0x3FFF: BANK1, lower 5 bits of the ROM bank, 0 is read as 1
+//! * 0x4000-0x5FFF: BANK2, 2 bits used as the upper ROM bank bits or the RAM bank
+//! * 0x6000-0x7FFF: Banking mode, in mode 1 BANK2 also applies to 0x0000-0x3FFF and RAM
+//!
+//! MBC1M multicarts wire BANK1 with only 4 bits, so BANK2 selects one of four 256 KiB games.
+
+use super::{bank_offset, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+use crate::emulator::cartridge::NINTENDO_LOGO;
+
+pub struct MBC1 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    bank1: u8,
+    bank2: u8,
+    mode: u8,
+    multicart: bool,
+}
+
+impl MBC1 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        let multicart = Self::is_multicart(&rom);
+        Self { rom, ram: vec![0; ram_size], ram_enabled: false, bank1: 1, bank2: 0, mode: 0, multicart }
+    }
+
+    // MBC1M carts are 1 MiB with a second game header, and so a second logo, in bank 0x10
+    fn is_multicart(rom: &[u8]) -> bool {
+        let header = 0x10 * ROM_BANK_SIZE + 0x104;
+        rom.len() == 0x100000 && rom[header..header + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+    }
+
+    // Number of bits of BANK1 used in the ROM bank number
+    fn bank1_bits(&self) -> u8 {
+        if self.multicart { 4 } else { 5 }
+    }
+
+    fn rom_bank_0(&self) -> usize {
+        if self.mode == 1 { (self.bank2 << self.bank1_bits()) as usize } else { 0 }
+    }
+
+    fn rom_bank_n(&self) -> usize {
+        let bank1 = self.bank1 & ((1 << self.bank1_bits()) - 1);
+        ((self.bank2 << self.bank1_bits()) | bank1) as usize
+    }
+
+    fn ram_offset(&self, address: u16) -> usize {
+        let bank = if self.mode == 1 { self.bank2 as usize } else { 0 };
+        bank_offset(bank, RAM_BANK_SIZE, address as usize - 0xa000, self.ram.len())
+    }
+}
+
+impl Mapper for MBC1 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let (bank, offset) = match address {
+            0x0000..=0x3fff => (self.rom_bank_0(), address as usize),
+            _ => (self.rom_bank_n(), address as usize - 0x4000),
+        };
+        self.rom[bank_offset(bank, ROM_BANK_SIZE, offset, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = value & 0x0f == 0x0a,
+            // The zero check is done on all 5 bits, even on multicarts
+            0x2000..=0x3fff => self.bank1 = if value & 0x1f == 0 { 1 } else { value & 0x1f },
+            0x4000..=0x5fff => self.bank2 = value & 0b11,
+            _ => self.mode = value & 0b1,
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return 0xff;
+        }
+        self.ram[self.ram_offset(address)]
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return;
+        }
+        let offset = self.ram_offset(address);
+        self.ram[offset] = value;
+    }
+
+    fn save_data(&self, _timestamp: u64) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mapper::banked_rom;
+
+    #[test]
+    fn rom_banking() {
+        let mut mbc = MBC1::new(banked_rom(128), 0);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x1f);
+        assert_eq!(mbc.read_rom(0x7fff), 0x1f);
+        // Bank 0 is read as 1, the check ignores BANK2 so banks 0x20, 0x40 and 0x60 are unreachable
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x20);
+        assert_eq!(mbc.read_rom(0x4000), 0x21);
+        // BANK2 only applies to 0x0000-0x3FFF in mode 1
+        assert_eq!(mbc.read_rom(0x0000), 0);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x20);
+    }
+
+    #[test]
+    fn rom_bank_wraps_around_rom_size() {
+        let mut mbc = MBC1::new(banked_rom(4), 0);
+        mbc.write_rom(0x2000, 0x05);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+    }
+
+    #[test]
+    fn ram_enable_and_banking() {
+        let mut mbc = MBC1::new(banked_rom(4), 0x8000);
+        mbc.write_ram(0xa000, 0x12);
+        assert_eq!(mbc.read_ram(0xa000), 0xff);
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_ram(0xa000, 0x12);
+        assert_eq!(mbc.read_ram(0xa000), 0x12);
+        // BANK2 selects the RAM bank in mode 1 only
+        mbc.write_rom(0x4000, 0x02);
+        assert_eq!(mbc.read_ram(0xa000), 0x12);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xa000), 0x00);
+        mbc.write_ram(0xa000, 0x34);
+        assert_eq!(mbc.save_data(0)[2 * RAM_BANK_SIZE], 0x34);
+        // Any value without 0x0A in the lower nibble disables RAM
+        mbc.write_rom(0x0000, 0x1b);
+        assert_eq!(mbc.read_ram(0xa000), 0xff);
+    }
+
+    #[test]
+    fn multicart_uses_4_bits_of_bank1() {
+        let mut rom = banked_rom(64);
+        let header = 0x10 * ROM_BANK_SIZE + 0x104;
+        rom[header..header + NINTENDO_LOGO.len()].copy_from_slice(&NINTENDO_LOGO);
+        let mut mbc = MBC1::new(rom, 0);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(0x4000), 0x12);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_rom(0x0000), 0x10);
+        // Without the second logo it is a regular MBC1
+        let mut mbc = MBC1::new(banked_rom(64), 0);
+        mbc.write_rom(0x4000, 0x01);
+        mbc.write_rom(0x2000, 0x12);
+        assert_eq!(mbc.read_rom(0x4000), 0x32);
+    }
+}
@@ -0,0 +1,101 @@
+//! MBC2, up to 256 KiB of ROM and 512 half bytes of built-in RAM
+//!
+//! Both registers are at 0x0000-0x3FFF, bit 8 of the address selects between them:
+//! * Bit 8 clear: RAM enable, 0x0A in the lower nibble enables RAM
+//! * Bit 8 set: ROM bank, 4 bits, 0 is read as 1
+//!
+//! The RAM is mirrored through 0xA000-0xBFFF, and the upper nibble of every byte reads as 1s.
+
+use super::{bank_offset, Mapper, ROM_BANK_SIZE};
+
+const RAM_SIZE: usize = 512;
+
+pub struct MBC2 {
+    rom: Vec<u8>,
+    ram: Vec<u8>, // One half byte per byte, like the save files of other emulators
+    ram_enabled: bool,
+    rom_bank: u8,
+}
+
+impl MBC2 {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Self { rom, ram: vec![0; RAM_SIZE], ram_enabled: false, rom_bank: 1 }
+    }
+}
+
+impl Mapper for MBC2 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let (bank, offset) = match address {
+            0x0000..=0x3fff => (0, address as usize),
+            _ => (self.rom_bank as usize, address as usize - 0x4000),
+        };
+        self.rom[bank_offset(bank, ROM_BANK_SIZE, offset, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x3fff if address & 0x100 == 0 => self.ram_enabled = value & 0x0f == 0x0a,
+            0x0000..=0x3fff => self.rom_bank = if value & 0x0f == 0 { 1 } else { value & 0x0f },
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        self.ram[address as usize % RAM_SIZE] | 0xf0
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.ram_enabled {
+            self.ram[address as usize % RAM_SIZE] = value & 0x0f;
+        }
+    }
+
+    fn save_data(&self, _timestamp: u64) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        for (byte, value) in self.ram.iter_mut().zip(data) {
+            *byte = value & 0x0f;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mapper::banked_rom;
+
+    #[test]
+    fn address_bit_8_selects_register() {
+        let mut mbc = MBC2::new(banked_rom(16));
+        // Bit 8 clear is the RAM enable, even with a ROM bank looking value
+        mbc.write_rom(0x2000, 0x03);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2100, 0x03);
+        assert_eq!(mbc.read_rom(0x4000), 3);
+        mbc.write_rom(0x0100, 0x10);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_ram(0xa000, 0x05);
+        assert_eq!(mbc.read_ram(0xa000), 0xff);
+        mbc.write_rom(0x3e00, 0x0a);
+        mbc.write_ram(0xa000, 0x05);
+        assert_eq!(mbc.read_ram(0xa000), 0xf5);
+    }
+
+    #[test]
+    fn ram_holds_half_bytes_and_is_mirrored() {
+        let mut mbc = MBC2::new(banked_rom(2));
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_ram(0xa1ff, 0xab);
+        assert_eq!(mbc.read_ram(0xa1ff), 0xfb);
+        assert_eq!(mbc.read_ram(0xbfff), 0xfb);
+        mbc.write_ram(0xb000, 0x0c);
+        assert_eq!(mbc.read_ram(0xa000), 0xfc);
+        let data = mbc.save_data(0);
+        assert_eq!((data.len(), data[0], data[0x1ff]), (RAM_SIZE, 0x0c, 0x0b));
+    }
+}
@@ -0,0 +1,393 @@
+//! MBC3, up to 2 MiB of ROM, 32 KiB of RAM and an optional real time clock
+//!
+//! Registers, selected by the address written to:
+//! * 0x0000-0x1FFF: RAM and RTC enable, 0x0A in the lower nibble enables them
+//! * 0x2000-0x3FFF: ROM bank, 7 bits, 0 is read as 1
+//! * 0x4000-0x5FFF: RAM bank 0x00-0x07, or RTC register 0x08-0x0C mapped at 0xA000-0xBFFF
+//! * 0x6000-0x7FFF: Writing 0x00 then 0x01 latches the clock into the readable RTC registers
+
+use super::{bank_offset, unix_time, Mapper, CYCLES_PER_SECOND, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+// Size of the RTC footer appended to the save file by BGB and VBA, the older 32 bit timestamp
+// version is 4 bytes shorter
+const RTC_FOOTER_SIZE: usize = 48;
+const RTC_FOOTER_SIZE_32: usize = 44;
+
+// Seconds, minutes, hours, lower 8 bits of the day counter, and DH
+// DH: bit 0 day counter bit 8, bit 6 halt, bit 7 day counter carry
+const RTC_MASKS: [u8; 5] = [0x3f, 0x3f, 0x1f, 0xff, 0xc1];
+const DH_HALT: u8 = 0x40;
+const DH_CARRY: u8 = 0x80;
+
+#[derive(Debug, Default)]
+struct Rtc {
+    registers: [u8; 5], // Live counters, in register order
+    latched: [u8; 5], // Copy read by the CPU
+    sub_cycles: u32, // T-cycles since the last second
+}
+
+impl Rtc {
+    fn halted(&self) -> bool {
+        self.registers[4] & DH_HALT != 0
+    }
+
+    fn days(&self) -> u64 {
+        ((self.registers[4] as u64 & 0b1) << 8) | self.registers[3] as u64
+    }
+
+    fn set_days(&mut self, days: u64) {
+        if days > 0x1ff {
+            self.registers[4] |= DH_CARRY;
+        }
+        let days = days & 0x1ff;
+        self.registers[3] = days as u8;
+        self.registers[4] = (self.registers[4] & !0b1) | (days >> 8) as u8;
+    }
+
+    // Values written by the game can be out of range, they count up to the width of the register
+    // and wrap to 0 without carrying into the next one
+    fn in_range(&self) -> bool {
+        self.registers[0] < 60 && self.registers[1] < 60 && self.registers[2] < 24
+    }
+
+    fn tick_second(&mut self) {
+        let [seconds, minutes, hours, ..] = &mut self.registers;
+        *seconds = (*seconds + 1) & RTC_MASKS[0];
+        if *seconds != 60 {
+            return;
+        }
+        *seconds = 0;
+        *minutes = (*minutes + 1) & RTC_MASKS[1];
+        if *minutes != 60 {
+            return;
+        }
+        *minutes = 0;
+        *hours = (*hours + 1) & RTC_MASKS[2];
+        if *hours != 24 {
+            return;
+        }
+        *hours = 0;
+        self.set_days(self.days() + 1);
+    }
+
+    /// Advance the clock by a number of seconds, unless it is halted
+    fn advance(&mut self, mut seconds: u64) {
+        if self.halted() {
+            return;
+        }
+        while seconds > 0 && !self.in_range() {
+            self.tick_second();
+            seconds -= 1;
+        }
+        if !self.in_range() {
+            return;
+        }
+        let total = seconds
+            + self.registers[0] as u64
+            + self.registers[1] as u64 * 60
+            + self.registers[2] as u64 * 3600
+            + self.days() * 86400;
+        self.registers[0] = (total % 60) as u8;
+        self.registers[1] = (total / 60 % 60) as u8;
+        self.registers[2] = (total / 3600 % 24) as u8;
+        self.set_days(total / 86400);
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        if self.halted() {
+            return;
+        }
+        self.sub_cycles += cycles as u32;
+        while self.sub_cycles >= CYCLES_PER_SECOND {
+            self.sub_cycles -= CYCLES_PER_SECOND;
+            self.tick_second();
+        }
+    }
+
+    fn write(&mut self, register: usize, value: u8) {
+        // Writing the seconds resets the sub-second counter
+        if register == 0 {
+            self.sub_cycles = 0;
+        }
+        self.registers[register] = value & RTC_MASKS[register];
+    }
+
+    // BGB/VBA footer: the live and latched registers as 32 bit little endian values, followed by
+    // the UNIX timestamp of the save as a 64 bit little endian value
+    fn footer(&self, timestamp: u64) -> Vec<u8> {
+        let mut footer: Vec<u8> = self
+            .registers
+            .iter()
+            .chain(self.latched.iter())
+            .flat_map(|&register| (register as u32).to_le_bytes())
+            .collect();
+        footer.extend_from_slice(&timestamp.to_le_bytes());
+        footer
+    }
+
+    // Restore the clock from a footer, and advance it by the time elapsed since it was saved
+    fn load_footer(&mut self, footer: &[u8]) {
+        let value = |index: usize| footer[index * 4];
+        for (register, mask) in RTC_MASKS.iter().enumerate() {
+            self.registers[register] = value(register) & mask;
+            self.latched[register] = value(register + 5) & mask;
+        }
+        let timestamp = match footer.len() {
+            RTC_FOOTER_SIZE => u64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            _ => u32::from_le_bytes(footer[40..44].try_into().unwrap()) as u64,
+        };
+        self.advance(unix_time().saturating_sub(timestamp));
+    }
+}
+
+pub struct MBC3 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    rtc: Option<Rtc>,
+    enabled: bool, // RAM and RTC enabled
+    rom_bank: u8,
+    ram_bank: u8, // RAM bank, or RTC register when 0x08-0x0C
+    latch: u8, // Last value written to the latch register
+}
+
+impl MBC3 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, has_rtc: bool) -> Self {
+        Self {
+            rom,
+            ram: vec![0; ram_size],
+            rtc: has_rtc.then(Rtc::default),
+            enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            latch: 0xff,
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        Some(bank_offset(self.ram_bank as usize, RAM_BANK_SIZE, address as usize - 0xa000, self.ram.len()))
+    }
+}
+
+impl Mapper for MBC3 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let (bank, offset) = match address {
+            0x0000..=0x3fff => (0, address as usize),
+            _ => (self.rom_bank as usize, address as usize - 0x4000),
+        };
+        self.rom[bank_offset(bank, ROM_BANK_SIZE, offset, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1fff => self.enabled = value & 0x0f == 0x0a,
+            0x2000..=0x3fff => self.rom_bank = if value & 0x7f == 0 { 1 } else { value & 0x7f },
+            0x4000..=0x5fff => self.ram_bank = value & 0x0f,
+            _ => {
+                if let Some(rtc) = self.rtc.as_mut().filter(|_| self.latch == 0x00 && value == 0x01) {
+                    rtc.latched = rtc.registers;
+                }
+                self.latch = value;
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.enabled {
+            return 0xff;
+        }
+        match self.ram_bank {
+            0x08..=0x0c => self.rtc.as_ref().map_or(0xff, |rtc| rtc.latched[self.ram_bank as usize - 0x08]),
+            0x00..=0x07 => self.ram_offset(address).map_or(0xff, |offset| self.ram[offset]),
+            _ => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.enabled {
+            return;
+        }
+        match self.ram_bank {
+            0x08..=0x0c => {
+                if let Some(rtc) = self.rtc.as_mut() {
+                    rtc.write(self.ram_bank as usize - 0x08, value);
+                }
+            }
+            0x00..=0x07 => {
+                if let Some(offset) = self.ram_offset(address) {
+                    self.ram[offset] = value;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        if let Some(rtc) = self.rtc.as_mut() {
+            rtc.tick(cycles);
+        }
+    }
+
+    fn save_data(&self, timestamp: u64) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        if let Some(rtc) = &self.rtc {
+            data.extend(rtc.footer(timestamp));
+        }
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+        let footer = &data[len..];
+        if let Some(rtc) = self.rtc.as_mut() {
+            if footer.len() == RTC_FOOTER_SIZE || footer.len() == RTC_FOOTER_SIZE_32 {
+                rtc.load_footer(footer);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mapper::banked_rom;
+
+    // MBC3 with RAM and RTC enabled
+    fn mbc3() -> MBC3 {
+        let mut mbc = MBC3::new(banked_rom(128), 0x8000, true);
+        mbc.write_rom(0x0000, 0x0a);
+        mbc
+    }
+
+    fn write_rtc(mbc: &mut MBC3, register: u8, value: u8) {
+        mbc.write_rom(0x4000, register);
+        mbc.write_ram(0xa000, value);
+    }
+
+    fn latch(mbc: &mut MBC3) {
+        mbc.write_rom(0x6000, 0x00);
+        mbc.write_rom(0x6000, 0x01);
+    }
+
+    // Latched seconds, minutes, hours, day low and DH
+    fn read_rtc(mbc: &mut MBC3) -> [u8; 5] {
+        latch(mbc);
+        std::array::from_fn(|register| {
+            mbc.write_rom(0x4000, 0x08 + register as u8);
+            mbc.read_ram(0xa000)
+        })
+    }
+
+    fn tick_second(mbc: &mut MBC3) {
+        for _ in 0..CYCLES_PER_SECOND / 4 {
+            mbc.tick(4);
+        }
+    }
+
+    #[test]
+    fn rom_and_ram_banking() {
+        let mut mbc = mbc3();
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        mbc.write_rom(0x2000, 0x7f);
+        assert_eq!(mbc.read_rom(0x4000), 0x7f);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+        mbc.write_rom(0x4000, 0x03);
+        mbc.write_ram(0xa000, 0x12);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_ram(0xa000), 0x00);
+        mbc.write_rom(0x4000, 0x03);
+        assert_eq!(mbc.read_ram(0xa000), 0x12);
+        mbc.write_rom(0x0000, 0x00);
+        assert_eq!(mbc.read_ram(0xa000), 0xff);
+    }
+
+    #[test]
+    fn rtc_is_read_through_latch() {
+        let mut mbc = mbc3();
+        write_rtc(&mut mbc, 0x08, 59);
+        assert_eq!(read_rtc(&mut mbc), [59, 0, 0, 0, 0]);
+        tick_second(&mut mbc);
+        // Reads keep the latched value until the next 0x00, 0x01 sequence
+        mbc.write_rom(0x4000, 0x08);
+        assert_eq!(mbc.read_ram(0xa000), 59);
+        mbc.write_rom(0x6000, 0x01);
+        assert_eq!(mbc.read_ram(0xa000), 59);
+        assert_eq!(read_rtc(&mut mbc), [0, 1, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rtc_halt_stops_the_clock() {
+        let mut mbc = mbc3();
+        write_rtc(&mut mbc, 0x0c, DH_HALT);
+        tick_second(&mut mbc);
+        assert_eq!(read_rtc(&mut mbc), [0, 0, 0, 0, DH_HALT]);
+    }
+
+    #[test]
+    fn rtc_advance_carries_into_days() {
+        let mut rtc = Rtc::default();
+        rtc.advance(86400 * 0x1ff + 3600 + 61);
+        assert_eq!(rtc.registers, [1, 1, 1, 0xff, 0x01]);
+        // Day 512 wraps to 0 and sets the carry, which stays set
+        rtc.advance(86400);
+        assert_eq!(rtc.registers, [1, 1, 1, 0x00, DH_CARRY]);
+        rtc.advance(86400);
+        assert_eq!(rtc.registers, [1, 1, 1, 0x01, DH_CARRY]);
+    }
+
+    #[test]
+    fn rtc_out_of_range_values_wrap_without_carry() {
+        let mut rtc = Rtc::default();
+        rtc.write(0, 0x3f);
+        rtc.write(2, 0x1f);
+        rtc.advance(1);
+        assert_eq!(rtc.registers, [0, 0, 0x1f, 0, 0]);
+        // Once back in range the clock counts normally
+        rtc.advance(60);
+        assert_eq!(rtc.registers, [0, 1, 0x1f, 0, 0]);
+    }
+
+    #[test]
+    fn rtc_footer_round_trip() {
+        let mut mbc = mbc3();
+        mbc.write_ram(0xa000, 0x12);
+        write_rtc(&mut mbc, 0x08, 10);
+        write_rtc(&mut mbc, 0x09, 20);
+        latch(&mut mbc);
+        // Halted so the time spent between the save and the load does not change it
+        write_rtc(&mut mbc, 0x0c, DH_HALT | 0x01);
+        let data = mbc.save_data(0x1122334455);
+        assert_eq!(data.len(), 0x8000 + RTC_FOOTER_SIZE);
+        let footer = &data[0x8000..];
+        assert_eq!(footer[0..4], [10, 0, 0, 0]);
+        assert_eq!(footer[16..20], [DH_HALT | 0x01, 0, 0, 0]);
+        assert_eq!(footer[20..24], [10, 0, 0, 0]);
+        assert_eq!(footer[40..48], 0x1122334455u64.to_le_bytes());
+
+        let mut loaded = mbc3();
+        loaded.load_save_data(&data);
+        assert_eq!(loaded.read_ram(0xa000), 0x12);
+        assert_eq!(loaded.rtc.as_ref().unwrap().registers, [10, 20, 0, 0, DH_HALT | 0x01]);
+        assert_eq!(loaded.rtc.as_ref().unwrap().latched, [10, 20, 0, 0, 0]);
+    }
+
+    #[test]
+    fn rtc_catches_up_on_time_saved() {
+        let mut mbc = mbc3();
+        let mut data = mbc.save_data(unix_time() - 3600);
+        // The older footer with a 32 bit timestamp
+        data.truncate(0x8000 + RTC_FOOTER_SIZE_32);
+        let mut loaded = mbc3();
+        loaded.load_save_data(&data);
+        let [seconds, minutes, hours, days, _] = loaded.rtc.as_ref().unwrap().registers;
+        assert_eq!((minutes, hours, days), (0, 1, 0));
+        assert!(seconds <= 1);
+        // Without a footer the clock is left alone
+        mbc.load_save_data(&data[..0x8000]);
+        assert_eq!(mbc.rtc.as_ref().unwrap().registers, [0; 5]);
+    }
+}
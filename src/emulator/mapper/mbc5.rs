@@ -0,0 +1,152 @@
+//! MBC5, up to 8 MiB of ROM and 128 KiB of RAM, with an optional rumble motor
+//!
+//! Registers, selected by the address written to:
+//! * 0x0000-0x1FFF: RAM enable, 0x0A in the lower nibble enables RAM
+//! * 0x2000-0x2FFF: Lower 8 bits of the ROM bank, bank 0 can be mapped at 0x4000
+//! * 0x3000-0x3FFF: Bit 8 of the ROM bank
+//! * 0x4000-0x5FFF: RAM bank, 4 bits. On rumble carts bit 3 drives the motor instead
+
+use super::{bank_offset, CartridgeEvent, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const RUMBLE_MOTOR: u8 = 0b1000;
+
+pub struct MBC5 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u16,
+    ram_bank: u8,
+    rumble: bool, // Cartridge has a rumble motor
+    motor: bool,
+    events: Vec<CartridgeEvent>,
+}
+
+impl MBC5 {
+    pub fn new(rom: Vec<u8>, ram_size: usize, rumble: bool) -> Self {
+        Self {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rumble,
+            motor: false,
+            events: Vec::new(),
+        }
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        Some(bank_offset(self.ram_bank as usize, RAM_BANK_SIZE, address as usize - 0xa000, self.ram.len()))
+    }
+}
+
+impl Mapper for MBC5 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let (bank, offset) = match address {
+            0x0000..=0x3fff => (0, address as usize),
+            _ => (self.rom_bank as usize, address as usize - 0x4000),
+        };
+        self.rom[bank_offset(bank, ROM_BANK_SIZE, offset, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = value & 0x0f == 0x0a,
+            0x2000..=0x2fff => self.rom_bank = (self.rom_bank & 0x100) | value as u16,
+            0x3000..=0x3fff => self.rom_bank = (self.rom_bank & 0xff) | ((value as u16 & 0b1) << 8),
+            0x4000..=0x5fff if self.rumble => {
+                self.ram_bank = value & 0b0111;
+                let motor = value & RUMBLE_MOTOR != 0;
+                if motor != self.motor {
+                    self.motor = motor;
+                    self.events.push(CartridgeEvent::Rumble(motor));
+                }
+            }
+            0x4000..=0x5fff => self.ram_bank = value & 0x0f,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        self.ram_offset(address).map_or(0xff, |offset| self.ram[offset])
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = value;
+        }
+    }
+
+    fn take_events(&mut self) -> Vec<CartridgeEvent> {
+        std::mem::take(&mut self.events)
+    }
+
+    fn save_data(&self, _timestamp: u64) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mapper::banked_rom;
+
+    #[test]
+    fn rom_banking() {
+        let mut rom = banked_rom(512);
+        // Bank numbers above 0xFF do not fit the byte, mark bank 0x100
+        rom[0x100 * ROM_BANK_SIZE] = 0xaa;
+        let mut mbc = MBC5::new(rom, 0, false);
+        assert_eq!(mbc.read_rom(0x4000), 1);
+        // Unlike the other MBCs bank 0 can be mapped at 0x4000
+        mbc.write_rom(0x2000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0);
+        mbc.write_rom(0x3000, 0x01);
+        assert_eq!(mbc.read_rom(0x4000), 0xaa);
+        mbc.write_rom(0x2000, 0x00);
+        mbc.write_rom(0x3000, 0x00);
+        mbc.write_rom(0x2000, 0xfe);
+        assert_eq!(mbc.read_rom(0x7fff), 0xfe);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+    }
+
+    #[test]
+    fn ram_banking() {
+        let mut mbc = MBC5::new(banked_rom(4), 0x20000, false);
+        mbc.write_ram(0xa000, 0x12);
+        assert_eq!(mbc.read_ram(0xa000), 0xff);
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x4000, 0x0f);
+        mbc.write_ram(0xa000, 0x12);
+        assert_eq!(mbc.save_data(0)[0x0f * RAM_BANK_SIZE], 0x12);
+        mbc.write_rom(0x4000, 0x07);
+        assert_eq!(mbc.read_ram(0xa000), 0x00);
+    }
+
+    #[test]
+    fn rumble_motor() {
+        let mut mbc = MBC5::new(banked_rom(4), 0x10000, true);
+        mbc.write_rom(0x0000, 0x0a);
+        // Bit 3 drives the motor, it is not a RAM bank bit
+        mbc.write_rom(0x4000, RUMBLE_MOTOR | 0x01);
+        mbc.write_ram(0xa000, 0x12);
+        mbc.write_rom(0x4000, RUMBLE_MOTOR | 0x01);
+        mbc.write_rom(0x4000, 0x01);
+        assert_eq!(mbc.read_ram(0xa000), 0x12);
+        // Only changes are reported
+        assert_eq!(mbc.take_events(), [CartridgeEvent::Rumble(true), CartridgeEvent::Rumble(false)]);
+        assert!(mbc.take_events().is_empty());
+        // Carts without a motor use the bit for the RAM bank
+        let mut mbc = MBC5::new(banked_rom(4), 0x20000, false);
+        mbc.write_rom(0x4000, RUMBLE_MOTOR);
+        assert!(mbc.take_events().is_empty());
+    }
+}
@@ -0,0 +1,265 @@
+//! MBC6, 1 MiB of ROM, 1 MiB of flash and 32 KiB of RAM, all banked in 8 KiB (ROM, flash) and
+//! 4 KiB (RAM) halves
+//!
+//! 0x4000-0x5FFF and 0x6000-0x7FFF are two independent windows, each mapping a bank of ROM or flash.
+//! 0xA000-0xAFFF and 0xB000-0xBFFF are two independent RAM windows.
+//!
+//! Registers, selected by the address written to:
+//! * 0x0000-0x03FF: RAM enable, 0x0A in the lower nibble enables RAM
+//! * 0x0400-0x07FF: RAM bank of window A
+//! * 0x0800-0x0BFF: RAM bank of window B
+//! * 0x0C00-0x0FFF: Flash enable, bit 0
+//! * 0x1000: Flash write enable, bit 0
+//! * 0x2000-0x27FF: ROM/flash bank of window A
+//! * 0x2800-0x2FFF: Window A maps flash when 0x08, ROM otherwise
+//! * 0x3000-0x37FF: ROM/flash bank of window B
+//! * 0x3800-0x3FFF: Window B maps flash when 0x08, ROM otherwise
+//!
+//! The flash is programmed with the usual unlock sequence (0xAA to 0x5555, 0x55 to 0x2AAA) followed
+//! by a command. Byte program, sector erase and chip erase are supported, and complete immediately.
+
+use super::{bank_offset, Mapper, ROM_BANK_SIZE};
+
+const BANK_SIZE: usize = 0x2000;
+const RAM_BANK_SIZE: usize = 0x1000;
+const RAM_SIZE: usize = 0x8000;
+const FLASH_SIZE: usize = 0x100000;
+const FLASH_SECTOR_SIZE: usize = 0x20000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FlashState {
+    Ready,
+    Unlock1, // 0xAA written
+    Command, // Unlock sequence complete, waiting for a command
+    Program, // Next write is programmed
+    EraseUnlock, // Erase command, waiting for the second unlock sequence
+    EraseUnlock1,
+    EraseCommand,
+}
+
+pub struct MBC6 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    flash: Vec<u8>,
+    ram_enabled: bool,
+    ram_banks: [u8; 2],
+    flash_enabled: bool,
+    flash_write_enabled: bool,
+    rom_banks: [u8; 2],
+    flash_mapped: [bool; 2],
+    flash_state: FlashState,
+}
+
+impl MBC6 {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Self {
+            rom,
+            ram: vec![0; RAM_SIZE],
+            // Erased flash reads as 1s
+            flash: vec![0xff; FLASH_SIZE],
+            ram_enabled: false,
+            ram_banks: [0; 2],
+            flash_enabled: false,
+            flash_write_enabled: false,
+            rom_banks: [0; 2],
+            flash_mapped: [false; 2],
+            flash_state: FlashState::Ready,
+        }
+    }
+
+    // Window (0 or 1) and offset in it of an address in 0x4000-0x7FFF
+    fn window(address: u16) -> (usize, usize) {
+        let address = address as usize - 0x4000;
+        (address / BANK_SIZE, address % BANK_SIZE)
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled {
+            return None;
+        }
+        let address = address as usize - 0xa000;
+        let bank = self.ram_banks[address / RAM_BANK_SIZE] as usize;
+        Some(bank_offset(bank, RAM_BANK_SIZE, address % RAM_BANK_SIZE, self.ram.len()))
+    }
+
+    fn write_flash(&mut self, offset: usize, value: u8) {
+        // Commands are decoded from the lower 15 bits of the flash address
+        let command_address = offset & 0x7fff;
+        self.flash_state = match (self.flash_state, command_address, value) {
+            (_, _, 0xf0) => FlashState::Ready,
+            (FlashState::Ready, 0x5555, 0xaa) => FlashState::Unlock1,
+            (FlashState::Unlock1, 0x2aaa, 0x55) => FlashState::Command,
+            (FlashState::Command, 0x5555, 0xa0) => FlashState::Program,
+            (FlashState::Command, 0x5555, 0x80) => FlashState::EraseUnlock,
+            (FlashState::Program, _, _) => {
+                // Programming can only clear bits
+                self.flash[offset] &= value;
+                FlashState::Ready
+            }
+            (FlashState::EraseUnlock, 0x5555, 0xaa) => FlashState::EraseUnlock1,
+            (FlashState::EraseUnlock1, 0x2aaa, 0x55) => FlashState::EraseCommand,
+            (FlashState::EraseCommand, 0x5555, 0x10) => {
+                self.flash.fill(0xff);
+                FlashState::Ready
+            }
+            (FlashState::EraseCommand, _, 0x30) => {
+                let sector = offset / FLASH_SECTOR_SIZE * FLASH_SECTOR_SIZE;
+                self.flash[sector..sector + FLASH_SECTOR_SIZE].fill(0xff);
+                FlashState::Ready
+            }
+            _ => FlashState::Ready,
+        };
+    }
+}
+
+impl Mapper for MBC6 {
+    fn read_rom(&self, address: u16) -> u8 {
+        if address < 0x4000 {
+            return self.rom[bank_offset(0, ROM_BANK_SIZE, address as usize, self.rom.len())];
+        }
+        let (window, offset) = Self::window(address);
+        let bank = self.rom_banks[window] as usize;
+        if self.flash_mapped[window] {
+            if !self.flash_enabled {
+                return 0xff;
+            }
+            return self.flash[bank_offset(bank, BANK_SIZE, offset, self.flash.len())];
+        }
+        self.rom[bank_offset(bank, BANK_SIZE, offset, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x03ff => self.ram_enabled = value & 0x0f == 0x0a,
+            0x0400..=0x07ff => self.ram_banks[0] = value & 0b111,
+            0x0800..=0x0bff => self.ram_banks[1] = value & 0b111,
+            0x0c00..=0x0fff => self.flash_enabled = value & 0b1 != 0,
+            0x1000 => self.flash_write_enabled = value & 0b1 != 0,
+            0x2000..=0x27ff => self.rom_banks[0] = value & 0x7f,
+            0x2800..=0x2fff => self.flash_mapped[0] = value == 0x08,
+            0x3000..=0x37ff => self.rom_banks[1] = value & 0x7f,
+            0x3800..=0x3fff => self.flash_mapped[1] = value == 0x08,
+            0x4000..=0x7fff => {
+                let (window, offset) = Self::window(address);
+                if self.flash_mapped[window] && self.flash_enabled && self.flash_write_enabled {
+                    let offset = bank_offset(self.rom_banks[window] as usize, BANK_SIZE, offset, FLASH_SIZE);
+                    self.write_flash(offset, value);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        self.ram_offset(address).map_or(0xff, |offset| self.ram[offset])
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = value;
+        }
+    }
+
+    // The RAM followed by the flash
+    fn save_data(&self, _timestamp: u64) -> Vec<u8> {
+        let mut data = self.ram.clone();
+        data.extend_from_slice(&self.flash);
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+        if let Some(flash) = data.get(RAM_SIZE..RAM_SIZE + FLASH_SIZE) {
+            self.flash.copy_from_slice(flash);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // ROM where every byte holds the number of its 8 KiB bank
+    fn rom() -> Vec<u8> {
+        (0..0x100000).map(|offset| (offset / BANK_SIZE) as u8).collect()
+    }
+
+    // MBC6 with flash enabled and writable, window A mapping flash bank 2 so 0x5555 is the command
+    // address, and window B mapping flash bank 1 so 0x6AAA is the second command address
+    fn flash_mbc6() -> MBC6 {
+        let mut mbc = MBC6::new(rom());
+        mbc.write_rom(0x0c00, 0x01);
+        mbc.write_rom(0x1000, 0x01);
+        mbc.write_rom(0x2000, 0x02);
+        mbc.write_rom(0x2800, 0x08);
+        mbc.write_rom(0x3000, 0x01);
+        mbc.write_rom(0x3800, 0x08);
+        mbc
+    }
+
+    fn command(mbc: &mut MBC6, command: u8) {
+        mbc.write_rom(0x5555, 0xaa);
+        mbc.write_rom(0x6aaa, 0x55);
+        mbc.write_rom(0x5555, command);
+    }
+
+    #[test]
+    fn rom_windows_are_independent() {
+        let mut mbc = MBC6::new(rom());
+        mbc.write_rom(0x2000, 0x05);
+        mbc.write_rom(0x3000, 0x7a);
+        assert_eq!(mbc.read_rom(0x0000), 0);
+        assert_eq!(mbc.read_rom(0x2000), 1);
+        assert_eq!(mbc.read_rom(0x5fff), 0x05);
+        assert_eq!(mbc.read_rom(0x6000), 0x7a);
+    }
+
+    #[test]
+    fn ram_windows_are_independent() {
+        let mut mbc = MBC6::new(rom());
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x0400, 0x03);
+        mbc.write_rom(0x0800, 0x05);
+        mbc.write_ram(0xa000, 0x12);
+        mbc.write_ram(0xb000, 0x34);
+        let data = mbc.save_data(0);
+        assert_eq!((data[3 * RAM_BANK_SIZE], data[5 * RAM_BANK_SIZE]), (0x12, 0x34));
+        assert_eq!(data.len(), RAM_SIZE + FLASH_SIZE);
+    }
+
+    #[test]
+    fn flash_program_and_erase() {
+        let mut mbc = flash_mbc6();
+        assert_eq!(mbc.read_rom(0x4000), 0xff);
+        command(&mut mbc, 0xa0);
+        mbc.write_rom(0x4000, 0x0f);
+        assert_eq!(mbc.read_rom(0x4000), 0x0f);
+        // Programming only clears bits
+        command(&mut mbc, 0xa0);
+        mbc.write_rom(0x4000, 0xf3);
+        assert_eq!(mbc.read_rom(0x4000), 0x03);
+        // Without a command writes do nothing
+        mbc.write_rom(0x4001, 0x00);
+        assert_eq!(mbc.read_rom(0x4001), 0xff);
+        command(&mut mbc, 0x80);
+        mbc.write_rom(0x5555, 0xaa);
+        mbc.write_rom(0x6aaa, 0x55);
+        mbc.write_rom(0x4000, 0x30);
+        assert_eq!(mbc.read_rom(0x4000), 0xff);
+    }
+
+    #[test]
+    fn flash_needs_enables() {
+        let mut mbc = flash_mbc6();
+        mbc.write_rom(0x1000, 0x00);
+        command(&mut mbc, 0xa0);
+        mbc.write_rom(0x4000, 0x00);
+        assert_eq!(mbc.read_rom(0x4000), 0xff);
+        mbc.write_rom(0x0c00, 0x00);
+        assert_eq!(mbc.read_rom(0x6000), 0xff);
+        // Window B back to ROM
+        mbc.write_rom(0x3800, 0x00);
+        assert_eq!(mbc.read_rom(0x6000), 1);
+    }
+}
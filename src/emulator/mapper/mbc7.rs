@@ -0,0 +1,345 @@
+//! MBC7, up to 2 MiB of ROM, a two axis accelerometer and a 93LC56 serial EEPROM (256 bytes)
+//!
+//! Registers, selected by the address written to:
+//! * 0x0000-0x1FFF: RAM enable 1, 0x0A enables the registers at 0xA000-0xAFFF
+//! * 0x2000-0x3FFF: ROM bank, 7 bits
+//! * 0x4000-0x5FFF: RAM enable 2, 0x40 enables the registers at 0xA000-0xAFFF
+//!
+//! Registers at 0xA000-0xAFFF, selected by bits 4-7 of the address:
+//! * 0x0: Writing 0x55 clears the latched accelerometer values
+//! * 0x1: Writing 0xAA latches the accelerometer, once cleared
+//! * 0x2-0x5: Latched X (low, high) and Y (low, high)
+//! * 0x8: EEPROM lines, bit 7 chip select, bit 6 clock, bit 1 data in, bit 0 data out
+
+use super::{bank_offset, Mapper, ROM_BANK_SIZE};
+
+const EEPROM_WORDS: usize = 128;
+// Accelerometer value when flat, and the change for 1 g
+const ACCELEROMETER_CENTER: f32 = 0x81d0 as f32;
+const ACCELEROMETER_G: f32 = 0x70 as f32;
+const UNLATCHED: u16 = 0x8000;
+
+const EEPROM_CS: u8 = 0x80;
+const EEPROM_CLK: u8 = 0x40;
+const EEPROM_DI: u8 = 0x02;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EepromState {
+    Idle, // Waiting for the start bit
+    Command { bits: u8, value: u16 }, // Receiving the 2 bit opcode and 8 bit address
+    Read { bits: u8, value: u16 }, // Shifting a word out
+    Write { bits: u8, value: u16, address: u8 }, // Receiving a word
+    WriteAll { bits: u8, value: u16 },
+}
+
+// 93LC56 in 16 bit mode
+#[derive(Debug)]
+struct Eeprom {
+    words: [u16; EEPROM_WORDS],
+    state: EepromState,
+    write_enabled: bool,
+    lines: u8, // Last value written to the lines
+    data_out: bool,
+}
+
+impl Eeprom {
+    fn new() -> Self {
+        Self { words: [0xffff; EEPROM_WORDS], state: EepromState::Idle, write_enabled: false, lines: 0, data_out: true }
+    }
+
+    fn read(&self) -> u8 {
+        (self.lines & (EEPROM_CS | EEPROM_CLK | EEPROM_DI)) | self.data_out as u8
+    }
+
+    fn write(&mut self, lines: u8) {
+        let rising_clock = self.lines & EEPROM_CLK == 0 && lines & EEPROM_CLK != 0;
+        self.lines = lines;
+        if lines & EEPROM_CS == 0 {
+            self.state = EepromState::Idle;
+            return;
+        }
+        if rising_clock {
+            self.clock(lines & EEPROM_DI != 0);
+        }
+    }
+
+    // Shift one bit in or out on a rising clock edge
+    fn clock(&mut self, bit: bool) {
+        self.state = match self.state {
+            EepromState::Idle if bit => EepromState::Command { bits: 0, value: 0 },
+            EepromState::Idle => EepromState::Idle,
+            EepromState::Command { bits, value } => {
+                let value = value << 1 | bit as u16;
+                if bits + 1 < 10 {
+                    EepromState::Command { bits: bits + 1, value }
+                } else {
+                    self.command((value >> 8) as u8, value as u8)
+                }
+            }
+            EepromState::Read { bits, value } => {
+                self.data_out = value & 0x8000 != 0;
+                if bits + 1 < 16 { EepromState::Read { bits: bits + 1, value: value << 1 } } else { EepromState::Idle }
+            }
+            EepromState::Write { bits, value, address } => {
+                let value = value << 1 | bit as u16;
+                if bits + 1 < 16 {
+                    EepromState::Write { bits: bits + 1, value, address }
+                } else {
+                    if self.write_enabled {
+                        self.words[address as usize % EEPROM_WORDS] = value;
+                    }
+                    self.data_out = true;
+                    EepromState::Idle
+                }
+            }
+            EepromState::WriteAll { bits, value } => {
+                let value = value << 1 | bit as u16;
+                if bits + 1 < 16 {
+                    EepromState::WriteAll { bits: bits + 1, value }
+                } else {
+                    if self.write_enabled {
+                        self.words.fill(value);
+                    }
+                    self.data_out = true;
+                    EepromState::Idle
+                }
+            }
+        };
+    }
+
+    // The upper two address bits select the commands sharing opcode 00
+    fn command(&mut self, opcode: u8, address: u8) -> EepromState {
+        let extended = address >> 6;
+        let address = address & 0x7f;
+        match (opcode, extended) {
+            // READ, a dummy 0 bit comes before the data
+            (0b10, _) => {
+                self.data_out = false;
+                EepromState::Read { bits: 0, value: self.words[address as usize] }
+            }
+            // WRITE
+            (0b01, _) => EepromState::Write { bits: 0, value: 0, address },
+            // ERASE
+            (0b11, _) => {
+                if self.write_enabled {
+                    self.words[address as usize] = 0xffff;
+                }
+                self.data_out = true;
+                EepromState::Idle
+            }
+            // EWDS
+            (0b00, 0b00) => {
+                self.write_enabled = false;
+                EepromState::Idle
+            }
+            // WRAL
+            (0b00, 0b01) => EepromState::WriteAll { bits: 0, value: 0 },
+            // ERAL
+            (0b00, 0b10) => {
+                if self.write_enabled {
+                    self.words.fill(0xffff);
+                }
+                self.data_out = true;
+                EepromState::Idle
+            }
+            // EWEN
+            _ => {
+                self.write_enabled = true;
+                EepromState::Idle
+            }
+        }
+    }
+}
+
+pub struct MBC7 {
+    rom: Vec<u8>,
+    eeprom: Eeprom,
+    enabled_1: bool,
+    enabled_2: bool,
+    rom_bank: u8,
+    tilt: (f32, f32), // In g, positive x is right and positive y is down
+    latched: (u16, u16),
+}
+
+impl MBC7 {
+    pub fn new(rom: Vec<u8>) -> Self {
+        Self {
+            rom,
+            eeprom: Eeprom::new(),
+            enabled_1: false,
+            enabled_2: false,
+            rom_bank: 1,
+            tilt: (0.0, 0.0),
+            latched: (UNLATCHED, UNLATCHED),
+        }
+    }
+
+    fn enabled(&self) -> bool {
+        self.enabled_1 && self.enabled_2
+    }
+
+    fn accelerometer(g: f32) -> u16 {
+        (ACCELEROMETER_CENTER + g.clamp(-1.0, 1.0) * ACCELEROMETER_G) as u16
+    }
+}
+
+impl Mapper for MBC7 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let (bank, offset) = match address {
+            0x0000..=0x3fff => (0, address as usize),
+            _ => (self.rom_bank as usize, address as usize - 0x4000),
+        };
+        self.rom[bank_offset(bank, ROM_BANK_SIZE, offset, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1fff => self.enabled_1 = value == 0x0a,
+            0x2000..=0x3fff => self.rom_bank = value & 0x7f,
+            0x4000..=0x5fff => self.enabled_2 = value == 0x40,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if !self.enabled() || address >= 0xb000 {
+            return 0xff;
+        }
+        match (address >> 4) & 0x0f {
+            0x2 => self.latched.0 as u8,
+            0x3 => (self.latched.0 >> 8) as u8,
+            0x4 => self.latched.1 as u8,
+            0x5 => (self.latched.1 >> 8) as u8,
+            0x6 => 0x00,
+            0x8 => self.eeprom.read(),
+            _ => 0xff,
+        }
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if !self.enabled() || address >= 0xb000 {
+            return;
+        }
+        match (address >> 4) & 0x0f {
+            0x0 if value == 0x55 => self.latched = (UNLATCHED, UNLATCHED),
+            0x1 if value == 0xaa && self.latched == (UNLATCHED, UNLATCHED) => {
+                self.latched = (Self::accelerometer(self.tilt.0), Self::accelerometer(self.tilt.1));
+            }
+            0x8 => self.eeprom.write(value),
+            _ => {}
+        }
+    }
+
+    fn set_tilt(&mut self, x: f32, y: f32) {
+        self.tilt = (x, y);
+    }
+
+    // The EEPROM as little endian words
+    fn save_data(&self, _timestamp: u64) -> Vec<u8> {
+        self.eeprom.words.iter().flat_map(|word| word.to_le_bytes()).collect()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        for (word, bytes) in self.eeprom.words.iter_mut().zip(data.chunks_exact(2)) {
+            *word = u16::from_le_bytes([bytes[0], bytes[1]]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mapper::banked_rom;
+
+    const EEPROM: u16 = 0xa080;
+
+    fn mbc7() -> MBC7 {
+        let mut mbc = MBC7::new(banked_rom(4));
+        mbc.write_rom(0x0000, 0x0a);
+        mbc.write_rom(0x4000, 0x40);
+        mbc
+    }
+
+    // Shift the lower `count` bits of `value` into the EEPROM, highest first
+    fn send(mbc: &mut MBC7, value: u32, count: u32) {
+        for bit in (0..count).rev() {
+            let di = if value >> bit & 1 != 0 { EEPROM_DI } else { 0 };
+            mbc.write_ram(EEPROM, EEPROM_CS | di);
+            mbc.write_ram(EEPROM, EEPROM_CS | EEPROM_CLK | di);
+        }
+    }
+
+    fn receive(mbc: &mut MBC7) -> u16 {
+        (0..16).fold(0, |value, _| {
+            mbc.write_ram(EEPROM, EEPROM_CS);
+            mbc.write_ram(EEPROM, EEPROM_CS | EEPROM_CLK);
+            value << 1 | (mbc.read_ram(EEPROM) & 1) as u16
+        })
+    }
+
+    // Start bit, 2 bit opcode and 8 bit address, on a fresh chip select
+    fn command(mbc: &mut MBC7, opcode: u32, address: u32) {
+        mbc.write_ram(EEPROM, 0x00);
+        send(mbc, 1 << 10 | opcode << 8 | address, 11);
+    }
+
+    #[test]
+    fn rom_banking_and_enables() {
+        let mut mbc = MBC7::new(banked_rom(4));
+        mbc.write_rom(0x2000, 0x03);
+        assert_eq!(mbc.read_rom(0x4000), 3);
+        assert_eq!(mbc.read_ram(0xa020), 0xff);
+        mbc.write_rom(0x0000, 0x0a);
+        assert_eq!(mbc.read_ram(0xa020), 0xff);
+        mbc.write_rom(0x4000, 0x40);
+        assert_eq!(mbc.read_ram(0xa020), UNLATCHED as u8);
+    }
+
+    #[test]
+    fn accelerometer_latch() {
+        let mut mbc = mbc7();
+        mbc.set_tilt(1.0, -2.0);
+        mbc.write_ram(0xa000, 0x55);
+        mbc.write_ram(0xa010, 0xaa);
+        let read: Vec<u8> = (0xa020..=0xa050).step_by(0x10).map(|address| mbc.read_ram(address)).collect();
+        // Centered at 0x81D0, 0x70 per g, clamped to 1 g
+        assert_eq!(read, [0x40, 0x82, 0x60, 0x81]);
+        // Latching again needs a clear first
+        mbc.set_tilt(0.0, 0.0);
+        mbc.write_ram(0xa010, 0xaa);
+        assert_eq!(mbc.read_ram(0xa020), 0x40);
+        mbc.write_ram(0xa000, 0x55);
+        mbc.write_ram(0xa010, 0xaa);
+        assert_eq!(mbc.read_ram(0xa020), 0xd0);
+    }
+
+    #[test]
+    fn eeprom_write_and_read() {
+        let mut mbc = mbc7();
+        // Writes are ignored until EWEN
+        command(&mut mbc, 0b01, 0x05);
+        send(&mut mbc, 0x1234, 16);
+        command(&mut mbc, 0b10, 0x05);
+        assert_eq!(receive(&mut mbc), 0xffff);
+        command(&mut mbc, 0b00, 0xc0);
+        command(&mut mbc, 0b01, 0x05);
+        send(&mut mbc, 0x1234, 16);
+        command(&mut mbc, 0b10, 0x05);
+        assert_eq!(receive(&mut mbc), 0x1234);
+        assert_eq!(mbc.save_data(0)[10..12], [0x34, 0x12]);
+        // ERASE sets the word back to 1s
+        command(&mut mbc, 0b11, 0x05);
+        assert_eq!(mbc.save_data(0)[10..12], [0xff, 0xff]);
+    }
+
+    #[test]
+    fn eeprom_save_round_trip() {
+        let mut data = vec![0xff; EEPROM_WORDS * 2];
+        data[0..2].copy_from_slice(&[0xcd, 0xab]);
+        let mut mbc = mbc7();
+        mbc.load_save_data(&data);
+        command(&mut mbc, 0b10, 0x00);
+        assert_eq!(receive(&mut mbc), 0xabcd);
+        assert_eq!(mbc.save_data(0), data);
+    }
+}
@@ -0,0 +1,186 @@
+//! MMM01 multicart, up to 8 MiB of ROM and 128 KiB of RAM shared between several games
+//!
+//! At power on the last 32 KiB of the ROM, holding the menu, are mapped at 0x0000-0x7FFF. The menu
+//! selects a game through the upper bits of the registers, then locks them by setting bit 6 of the
+//! RAM enable register. From then on the selected game sees an MBC1 like mapper limited to its own
+//! part of the ROM and RAM.
+//!
+//! Registers, selected by the address written to (bits marked * can only be written before lock):
+//! * 0x0000-0x1FFF: RAM enable (bits 0-3), *RAM bank mask (bits 4-5), *lock (bit 6)
+//! * 0x2000-0x3FFF: ROM bank (bits 0-4), *ROM bank bits 5-6 (bits 5-6)
+//! * 0x4000-0x5FFF: RAM bank (bits 0-1), *RAM bank bits 2-3 (bits 2-3), *ROM bank bits 7-8 (bits 4-5)
+//! * 0x6000-0x7FFF: Banking mode (bit 0), *ROM bank mask (bits 2-5)
+
+use super::{bank_offset, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+pub struct MMM01 {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    locked: bool,
+    ram_enabled: bool,
+    rom_bank: u16, // 9 bits
+    ram_bank: u8, // 4 bits
+    rom_mask: u8, // Bits 1-4 of the ROM bank set here are fixed when locked
+    ram_mask: u8, // Bits 0-1 of the RAM bank set here are fixed when locked
+    mode: u8,
+}
+
+impl MMM01 {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        Self {
+            rom,
+            ram: vec![0; ram_size],
+            locked: false,
+            ram_enabled: false,
+            rom_bank: 0,
+            ram_bank: 0,
+            rom_mask: 0,
+            ram_mask: 0,
+            mode: 0,
+        }
+    }
+
+    // The menu lives in the last two banks
+    fn menu_bank(&self) -> usize {
+        (self.rom.len() / ROM_BANK_SIZE).saturating_sub(2)
+    }
+
+    // First bank of the selected game, the bits of the ROM bank fixed by the mask
+    fn game_bank(&self) -> usize {
+        (self.rom_bank & !(0x1f & !((self.rom_mask as u16) << 1))) as usize
+    }
+
+    fn rom_bank_n(&self) -> usize {
+        let bank = self.rom_bank & 0x1f;
+        let bank = if bank & !((self.rom_mask as u16) << 1) == 0 { bank | 1 } else { bank };
+        self.game_bank() | bank as usize
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if !self.ram_enabled || self.ram.is_empty() {
+            return None;
+        }
+        let bank = if self.mode == 1 { self.ram_bank } else { self.ram_bank & (0b1100 | self.ram_mask) };
+        Some(bank_offset(bank as usize, RAM_BANK_SIZE, address as usize - 0xa000, self.ram.len()))
+    }
+}
+
+impl Mapper for MMM01 {
+    fn read_rom(&self, address: u16) -> u8 {
+        let bank = match (self.locked, address) {
+            (false, 0x0000..=0x3fff) => self.menu_bank(),
+            (false, _) => self.menu_bank() + 1,
+            (true, 0x0000..=0x3fff) => self.game_bank(),
+            (true, _) => self.rom_bank_n(),
+        };
+        self.rom[bank_offset(bank, ROM_BANK_SIZE, address as usize & 0x3fff, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        let locked = self.locked;
+        match address {
+            0x0000..=0x1fff => {
+                self.ram_enabled = value & 0x0f == 0x0a;
+                if !locked {
+                    self.ram_mask = (value >> 4) & 0b11;
+                    self.locked = value & 0x40 != 0;
+                }
+            }
+            0x2000..=0x3fff => {
+                // Bits fixed by the mask cannot be changed by the game once locked
+                let writable = if locked { 0x1f & !((self.rom_mask as u16) << 1) } else { 0x7f };
+                self.rom_bank = (self.rom_bank & !writable) | (value as u16 & writable);
+            }
+            0x4000..=0x5fff => {
+                let writable = if locked { !self.ram_mask & 0b11 } else { 0b1111 };
+                self.ram_bank = (self.ram_bank & !writable) | (value & writable);
+                if !locked {
+                    self.rom_bank = (self.rom_bank & 0x7f) | ((value as u16 >> 4) & 0b11) << 7;
+                }
+            }
+            _ => {
+                self.mode = value & 0b1;
+                if !locked {
+                    self.rom_mask = (value >> 2) & 0x0f;
+                }
+            }
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        self.ram_offset(address).map_or(0xff, |offset| self.ram[offset])
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = value;
+        }
+    }
+
+    fn save_data(&self, _timestamp: u64) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mapper::banked_rom;
+
+    #[test]
+    fn menu_is_mapped_at_power_on() {
+        let mmm = MMM01::new(banked_rom(64), 0);
+        assert_eq!(mmm.read_rom(0x0000), 62);
+        assert_eq!(mmm.read_rom(0x4000), 63);
+    }
+
+    #[test]
+    fn locked_game_stays_in_its_banks() {
+        let mut mmm = MMM01::new(banked_rom(64), 0x8000);
+        // 512 KiB game starting at bank 0x20
+        mmm.write_rom(0x2000, 0x20);
+        mmm.write_rom(0x0000, 0x40);
+        assert_eq!(mmm.read_rom(0x0000), 0x20);
+        assert_eq!(mmm.read_rom(0x4000), 0x21);
+        mmm.write_rom(0x2000, 0x03);
+        assert_eq!(mmm.read_rom(0x4000), 0x23);
+        // The upper bits and the lock are fixed from now on
+        mmm.write_rom(0x2000, 0x40);
+        assert_eq!(mmm.read_rom(0x4000), 0x21);
+        mmm.write_rom(0x0000, 0x00);
+        assert_eq!(mmm.read_rom(0x0000), 0x20);
+    }
+
+    #[test]
+    fn rom_mask_limits_game_size() {
+        let mut mmm = MMM01::new(banked_rom(64), 0);
+        // 32 KiB game at banks 0x26-0x27, the mask fixes bits 1-4 of the bank
+        mmm.write_rom(0x2000, 0x26);
+        mmm.write_rom(0x6000, 0x0f << 2);
+        mmm.write_rom(0x0000, 0x40);
+        assert_eq!(mmm.read_rom(0x0000), 0x26);
+        assert_eq!(mmm.read_rom(0x4000), 0x27);
+        mmm.write_rom(0x2000, 0x1f);
+        assert_eq!(mmm.read_rom(0x4000), 0x27);
+    }
+
+    #[test]
+    fn ram_mask_fixes_ram_bank_bits() {
+        let mut mmm = MMM01::new(banked_rom(64), 0x8000);
+        mmm.write_rom(0x4000, 0x01);
+        // RAM enabled, bit 0 of the RAM bank fixed, locked
+        mmm.write_rom(0x0000, 0x40 | 0x10 | 0x0a);
+        mmm.write_rom(0x4000, 0x02);
+        // In mode 0 only the fixed bits select the bank
+        mmm.write_ram(0xa000, 0x12);
+        mmm.write_rom(0x6000, 0x01);
+        mmm.write_ram(0xa000, 0x34);
+        let data = mmm.save_data(0);
+        assert_eq!((data[RAM_BANK_SIZE], data[3 * RAM_BANK_SIZE]), (0x12, 0x34));
+    }
+}
@@ -0,0 +1,139 @@
+//! Memory bank controllers, mapping the cartridge ROM and RAM into 0x0000-0x7FFF and 0xA000-0xBFFF
+
+pub mod rom_only;
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod mbc6;
+pub mod mbc7;
+pub mod mmm01;
+pub mod huc1;
+pub mod huc3;
+pub mod pocket_camera;
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::cartridge::{Cartridge, CartridgeError};
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+// Clock of the cartridge hardware, real time clocks count seconds from it
+pub const CYCLES_PER_SECOND: u32 = 4194304;
+
+/// Event raised by cartridge hardware, for the frontend to act on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CartridgeEvent {
+    // The rumble motor was switched on or off
+    Rumble(bool),
+}
+
+/// Cartridge hardware behind the ROM and external RAM address ranges
+pub trait Mapper {
+    /// Read from ROM, 0x0000-0x7FFF
+    fn read_rom(&self, address: u16) -> u8;
+
+    /// Write to the mapper registers, which are mapped over ROM at 0x0000-0x7FFF
+    fn write_rom(&mut self, address: u16, value: u8);
+
+    /// Read from external RAM, 0xA000-0xBFFF
+    fn read_ram(&self, address: u16) -> u8;
+
+    /// Write to external RAM, 0xA000-0xBFFF
+    fn write_ram(&mut self, address: u16, value: u8);
+
+    /// Advance hardware with its own clock, like a real time clock, by a number of T-cycles
+    fn tick(&mut self, _cycles: u8) {}
+
+    /// Set the tilt seen by an accelerometer, in g along each axis
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
+
+    /// Events raised since the last call
+    fn take_events(&mut self) -> Vec<CartridgeEvent> {
+        Vec::new()
+    }
+
+    /// Battery backed state to write to a save file, the RAM followed by any mapper specific data
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - UNIX time stored by real time clocks, to catch up on the time spent off
+    ///
+    fn save_data(&self, _timestamp: u64) -> Vec<u8> {
+        Vec::new()
+    }
+
+    /// Restore the state read from a save file
+    fn load_save_data(&mut self, _data: &[u8]) {}
+}
+
+/// Create the mapper for the cartridge type in the header
+pub fn from_cartridge(cartridge: Cartridge) -> Result<Box<dyn Mapper>, CartridgeError> {
+    let ram_size = cartridge.header.ram_size;
+    let cartridge_type = cartridge.header.cartridge_type;
+    let rom = cartridge.rom;
+    let mapper: Box<dyn Mapper> = match cartridge_type {
+        0x00 | 0x08 | 0x09 => Box::new(rom_only::RomOnly::new(rom, ram_size)),
+        0x01..=0x03 => Box::new(mbc1::MBC1::new(rom, ram_size)),
+        0x05 | 0x06 => Box::new(mbc2::MBC2::new(rom)),
+        0x0b..=0x0d => Box::new(mmm01::MMM01::new(rom, ram_size)),
+        0x0f..=0x13 => Box::new(mbc3::MBC3::new(rom, ram_size, cartridge_type <= 0x10)),
+        0x19..=0x1e => Box::new(mbc5::MBC5::new(rom, ram_size, cartridge_type >= 0x1c)),
+        0x20 => Box::new(mbc6::MBC6::new(rom)),
+        0x22 => Box::new(mbc7::MBC7::new(rom)),
+        0xfc => Box::new(pocket_camera::PocketCamera::new(rom, ram_size)),
+        0xfe => Box::new(huc3::HuC3::new(rom, ram_size)),
+        0xff => Box::new(huc1::HuC1::new(rom, ram_size)),
+        _ => return Err(CartridgeError::UnsupportedMapper(cartridge_type)),
+    };
+    Ok(mapper)
+}
+
+/// Offset of an address in a bank, wrapped around the size of the memory like the unconnected
+/// upper address lines of a smaller chip
+///
+/// # Arguments
+///
+/// * `bank` - Bank number, may be larger than the number of banks
+/// * `bank_size` - Size of a bank in bytes
+/// * `offset` - Offset in the bank
+/// * `len` - Size of the memory in bytes
+///
+pub fn bank_offset(bank: usize, bank_size: usize, offset: usize, len: usize) -> usize {
+    (bank * bank_size + offset) % len
+}
+
+/// ROM where every byte holds the number of its bank, to see which bank is mapped
+#[cfg(test)]
+pub fn banked_rom(banks: usize) -> Vec<u8> {
+    (0..banks * ROM_BANK_SIZE).map(|offset| (offset / ROM_BANK_SIZE) as u8).collect()
+}
+
+/// Host wall clock time in seconds since the UNIX epoch, for real time clocks
+pub fn unix_time() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |time| time.as_secs())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bank_offset_wraps_around_memory_size() {
+        assert_eq!(bank_offset(3, ROM_BANK_SIZE, 0x10, 8 * ROM_BANK_SIZE), 3 * ROM_BANK_SIZE + 0x10);
+        assert_eq!(bank_offset(9, ROM_BANK_SIZE, 0x10, 8 * ROM_BANK_SIZE), ROM_BANK_SIZE + 0x10);
+        // A 2 KiB RAM is mirrored over the whole bank
+        assert_eq!(bank_offset(0, RAM_BANK_SIZE, 0x1801, 0x800), 0x001);
+    }
+
+    #[test]
+    fn mapper_from_cartridge_type() {
+        let mut rom = banked_rom(2);
+        rom[0x147] = 0x01;
+        let cartridge = Cartridge::from_bytes(rom.clone()).unwrap();
+        assert!(from_cartridge(cartridge).is_ok());
+        rom[0x147] = 0xfd;
+        let cartridge = Cartridge::from_bytes(rom).unwrap();
+        assert!(matches!(from_cartridge(cartridge), Err(CartridgeError::UnsupportedMapper(0xfd))));
+    }
+}
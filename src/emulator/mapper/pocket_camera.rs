@@ -0,0 +1,204 @@
+//! Game Boy Camera (Pocket Camera), up to 1 MiB of ROM, 128 KiB of RAM and the camera sensor
+//!
+//! Registers, selected by the address written to:
+//! * 0x0000-0x1FFF: RAM enable, 0x0A in the lower nibble enables writing RAM
+//! * 0x2000-0x3FFF: ROM bank, 6 bits
+//! * 0x4000-0x5FFF: RAM bank, 4 bits, or the camera registers at 0xA000-0xBFFF when bit 4 is set
+//!
+//! Camera registers, mirrored every 0x80 bytes:
+//! * 0xA000: Bit 0 starts a capture and stays set until it is done
+//! * 0xA002-0xA003: Exposure time, high byte first
+//! * 0xA006-0xA035: 4x4 dither matrix, three thresholds per pixel
+//!
+//! No host camera is used, captures see a fixed gradient, processed through the dither matrix
+//! into the 128x112 image at 0xA100 in RAM bank 0.
+
+use super::{bank_offset, Mapper, RAM_BANK_SIZE, ROM_BANK_SIZE};
+
+const REGISTER_COUNT: usize = 0x36;
+const MATRIX_START: usize = 0x06;
+const IMAGE_WIDTH: usize = 128;
+const IMAGE_HEIGHT: usize = 112;
+const IMAGE_OFFSET: usize = 0x100;
+
+pub struct PocketCamera {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+    ram_enabled: bool,
+    rom_bank: u8,
+    ram_bank: u8, // Camera registers are mapped when bit 4 is set
+    registers: [u8; REGISTER_COUNT],
+    capture_cycles: u32, // T-cycles left until the capture is done
+}
+
+impl PocketCamera {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        Self {
+            rom,
+            ram: vec![0; ram_size],
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            registers: [0; REGISTER_COUNT],
+            capture_cycles: 0,
+        }
+    }
+
+    fn camera_mapped(&self) -> bool {
+        self.ram_bank & 0x10 != 0
+    }
+
+    fn ram_offset(&self, address: u16) -> Option<usize> {
+        if self.ram.is_empty() {
+            return None;
+        }
+        Some(bank_offset(self.ram_bank as usize & 0x0f, RAM_BANK_SIZE, address as usize - 0xa000, self.ram.len()))
+    }
+
+    fn start_capture(&mut self) {
+        let exposure = (self.registers[2] as u32) << 8 | self.registers[3] as u32;
+        self.capture_cycles = 4 * (32446 + 16 * exposure);
+    }
+
+    // Write the captured image as tiles, two bits per pixel
+    fn finish_capture(&mut self) {
+        self.registers[0] &= !0b1;
+        if self.ram.len() < IMAGE_OFFSET + IMAGE_WIDTH * IMAGE_HEIGHT / 4 {
+            return;
+        }
+        for y in 0..IMAGE_HEIGHT {
+            for x in 0..IMAGE_WIDTH {
+                // Darker towards the bottom right
+                let value = 0xff - ((x + y) * 0xff / (IMAGE_WIDTH + IMAGE_HEIGHT)) as u8;
+                let matrix = MATRIX_START + ((y % 4) * 4 + x % 4) * 3;
+                let thresholds = &self.registers[matrix..matrix + 3];
+                let color = 3 - thresholds.iter().filter(|&&threshold| value >= threshold).count() as u8;
+                let row = IMAGE_OFFSET + ((y / 8) * (IMAGE_WIDTH / 8) + x / 8) * 16 + (y % 8) * 2;
+                let bit = 0x80 >> (x % 8);
+                for (plane, mask) in [(0, 0b01), (1, 0b10)] {
+                    if color & mask != 0 {
+                        self.ram[row + plane] |= bit;
+                    } else {
+                        self.ram[row + plane] &= !bit;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Mapper for PocketCamera {
+    fn read_rom(&self, address: u16) -> u8 {
+        let (bank, offset) = match address {
+            0x0000..=0x3fff => (0, address as usize),
+            _ => (self.rom_bank as usize, address as usize - 0x4000),
+        };
+        self.rom[bank_offset(bank, ROM_BANK_SIZE, offset, self.rom.len())]
+    }
+
+    fn write_rom(&mut self, address: u16, value: u8) {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = value & 0x0f == 0x0a,
+            0x2000..=0x3fff => self.rom_bank = value & 0x3f,
+            0x4000..=0x5fff => self.ram_bank = value & 0x1f,
+            _ => {}
+        }
+    }
+
+    fn read_ram(&self, address: u16) -> u8 {
+        if self.camera_mapped() {
+            // Only the capture register can be read
+            return if address & 0x7f == 0 { self.registers[0] & 0b111 } else { 0x00 };
+        }
+        // RAM is disconnected while capturing
+        if self.capture_cycles > 0 {
+            return 0x00;
+        }
+        self.ram_offset(address).map_or(0xff, |offset| self.ram[offset])
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if self.camera_mapped() {
+            let register = address as usize & 0x7f;
+            if register < REGISTER_COUNT {
+                self.registers[register] = value;
+                if register == 0 && value & 0b1 != 0 {
+                    self.start_capture();
+                }
+            }
+            return;
+        }
+        if !self.ram_enabled || self.capture_cycles > 0 {
+            return;
+        }
+        if let Some(offset) = self.ram_offset(address) {
+            self.ram[offset] = value;
+        }
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        if self.capture_cycles == 0 {
+            return;
+        }
+        self.capture_cycles = self.capture_cycles.saturating_sub(cycles as u32);
+        if self.capture_cycles == 0 {
+            self.finish_capture();
+        }
+    }
+
+    fn save_data(&self, _timestamp: u64) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mapper::banked_rom;
+
+    #[test]
+    fn rom_and_ram_banking() {
+        let mut camera = PocketCamera::new(banked_rom(64), 0x20000);
+        camera.write_rom(0x2000, 0x3f);
+        assert_eq!(camera.read_rom(0x4000), 0x3f);
+        camera.write_rom(0x2000, 0x00);
+        assert_eq!(camera.read_rom(0x4000), 0);
+        // Writes need the enable, reads do not
+        camera.write_rom(0x4000, 0x0f);
+        camera.write_ram(0xa000, 0x12);
+        assert_eq!(camera.read_ram(0xa000), 0x00);
+        camera.write_rom(0x0000, 0x0a);
+        camera.write_ram(0xa000, 0x12);
+        assert_eq!(camera.read_ram(0xa000), 0x12);
+        assert_eq!(camera.save_data(0)[0x0f * RAM_BANK_SIZE], 0x12);
+    }
+
+    #[test]
+    fn capture_writes_dithered_image() {
+        let mut camera = PocketCamera::new(banked_rom(64), 0x20000);
+        camera.write_rom(0x4000, 0x10);
+        // Thresholds at the maximum, only the brightest pixel at the top left is white
+        for register in MATRIX_START..REGISTER_COUNT {
+            camera.write_ram(0xa000 + register as u16, 0xff);
+        }
+        camera.write_ram(0xa000, 0x01);
+        assert_eq!(camera.read_ram(0xa000), 0x01);
+        // Registers are mirrored every 0x80 bytes
+        assert_eq!(camera.read_ram(0xa080), 0x01);
+        camera.write_rom(0x4000, 0x00);
+        assert_eq!(camera.read_ram(0xa100), 0x00);
+        for _ in 0..32446 {
+            camera.tick(4);
+        }
+        assert_eq!(camera.read_ram(0xa100), 0x7f);
+        assert_eq!(camera.read_ram(0xa101), 0x7f);
+        assert_eq!(camera.read_ram(0xa102), 0xff);
+        camera.write_rom(0x4000, 0x10);
+        assert_eq!(camera.read_ram(0xa000), 0x00);
+    }
+}
@@ -0,0 +1,60 @@
+//! Cartridges without a memory bank controller: 32 KiB of ROM and up to 8 KiB of RAM
+
+use super::Mapper;
+
+pub struct RomOnly {
+    rom: Vec<u8>,
+    ram: Vec<u8>,
+}
+
+impl RomOnly {
+    pub fn new(rom: Vec<u8>, ram_size: usize) -> Self {
+        Self { rom, ram: vec![0; ram_size] }
+    }
+}
+
+impl Mapper for RomOnly {
+    fn read_rom(&self, address: u16) -> u8 {
+        self.rom.get(address as usize).copied().unwrap_or(0xff)
+    }
+
+    // There are no registers, writes to ROM are ignored
+    fn write_rom(&mut self, _address: u16, _value: u8) {}
+
+    fn read_ram(&self, address: u16) -> u8 {
+        self.ram.get(address as usize - 0xa000).copied().unwrap_or(0xff)
+    }
+
+    fn write_ram(&mut self, address: u16, value: u8) {
+        if let Some(byte) = self.ram.get_mut(address as usize - 0xa000) {
+            *byte = value;
+        }
+    }
+
+    fn save_data(&self, _timestamp: u64) -> Vec<u8> {
+        self.ram.clone()
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = data.len().min(self.ram.len());
+        self.ram[..len].copy_from_slice(&data[..len]);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::mapper::banked_rom;
+
+    #[test]
+    fn rom_is_read_only_and_ram_is_optional() {
+        let mut cartridge = RomOnly::new(banked_rom(2), 0x2000);
+        cartridge.write_rom(0x4000, 0x12);
+        assert_eq!(cartridge.read_rom(0x4000), 1);
+        cartridge.write_ram(0xbfff, 0x34);
+        assert_eq!(cartridge.read_ram(0xbfff), 0x34);
+        let mut cartridge = RomOnly::new(banked_rom(2), 0);
+        cartridge.write_ram(0xa000, 0x34);
+        assert_eq!(cartridge.read_ram(0xa000), 0xff);
+    }
+}
@@ -1,52 +1,166 @@
-use std::fs::File;
-use std::io::Read;
+use super::cartridge::{Cartridge, CartridgeError, Header};
+use super::mapper::{self, CartridgeEvent, Mapper};
+use super::trace::trace;
+use super::interrupts::{Interrupt, Interrupts, IE_ADDRESS};
+use super::io::IoRegister;
+use super::joypad::{Button, Joypad};
+use super::serial::Serial;
+use super::timer::Timer;
+use super::apu::APU;
+use super::lcd::Lcd;
+use super::dma::Dma;
+use super::cgb::CgbRegisters;
 
-#[derive(Debug)]
 pub struct MMU {
-    pub rom: Vec<u8>,
-    pub rom_bank_0: [u8; 16384],
-    pub rom_bank_n: [u8; 16384],
-    pub external_ram_bank_n: [u8; 8192],
+    pub header: Option<Header>,
+    pub mapper: Option<Box<dyn Mapper>>, // Cartridge ROM and RAM, reads 0xFF without a cartridge
     pub wram_bank_0: [u8; 4096],
     pub wram_bank_n: [u8; 4096],
     pub vram: [u8; 8192],
     pub object_attribute_memory: [u8; 160],
-    pub io_registers: [u8; 128],
+    pub hram: [u8; 127],
+    pub interrupts: Interrupts,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    pub timer: Timer,
+    pub apu: APU,
+    pub lcd: Lcd,
+    pub dma: Dma,
+    pub cgb: CgbRegisters,
+    pub cgb_mode: bool, // Running in CGB mode, never set while only DMG hardware is emulated
+    pub cycles: u64, // Time the bus has been ticked for, in T-cycles at normal speed
+    pub doctor_mode: bool, // LY always reads 0x90, as expected by Gameboy Doctor logs
 }
 
 impl MMU {
     pub fn new() -> Self {
         Self {
-            rom_bank_0: [0; 16384],
-            rom_bank_n: [0; 16384],
-            external_ram_bank_n: [00; 8192],
+            header: None,
+            mapper: None,
             wram_bank_0: [0; 4096],
             wram_bank_n: [0; 4096],
             vram: [0; 8192],
             object_attribute_memory: [0; 160],
-            io_registers: [0; 128],
-            rom: Vec::new(),
+            hram: [0; 127],
+            interrupts: Interrupts::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            timer: Timer::new(),
+            apu: APU::new(),
+            lcd: Lcd::new(),
+            dma: Dma::new(),
+            cgb: CgbRegisters::new(),
+            cgb_mode: false,
+            cycles: 0,
+            doctor_mode: false,
+        }
+    }
+    /// Insert a cartridge, creating the mapper for its type
+    pub fn load_cartridge(&mut self, cartridge: Cartridge) -> Result<(), CartridgeError> {
+        let header = cartridge.header.clone();
+        self.mapper = Some(mapper::from_cartridge(cartridge)?);
+        // Only DMG hardware is emulated, CGB cartridges run in their DMG mode whatever the header
+        // says. `cgb_mode` and the APU stay on DMG behaviour until VRAM/WRAM banking and CGB
+        // palettes are implemented
+        self.cgb_mode = false;
+        self.apu.set_cgb(self.cgb_mode);
+        self.header = Some(header);
+        Ok(())
+    }
+    /// Battery backed cartridge state, in the save file format
+    ///
+    /// # Arguments
+    ///
+    /// * `timestamp` - UNIX time stored by cartridges with a real time clock
+    ///
+    pub fn save_data(&self, timestamp: u64) -> Vec<u8> {
+        self.mapper.as_ref().map_or_else(Vec::new, |mapper| mapper.save_data(timestamp))
+    }
+
+    /// Restore battery backed cartridge state from a save file
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        if let Some(mapper) = self.mapper.as_mut() {
+            mapper.load_save_data(data);
+        }
+    }
+
+    /// Events raised by the cartridge hardware since the last call, like rumble motor changes
+    pub fn take_cartridge_events(&mut self) -> Vec<CartridgeEvent> {
+        self.mapper.as_mut().map_or_else(Vec::new, |mapper| mapper.take_events())
+    }
+
+    /// Press or release a button, raising the joypad interrupt if it is selected
+    pub fn set_button(&mut self, button: Button, pressed: bool) {
+        if self.joypad.set_button(button, pressed) {
+            self.interrupts.request(Interrupt::Joypad);
+        }
+    }
+
+    /// Tilt the cartridge, for carts with an accelerometer
+    ///
+    /// # Arguments
+    ///
+    /// * `x` - Tilt to the right in g, from -1 to 1
+    /// * `y` - Tilt towards the bottom in g, from -1 to 1
+    ///
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        if let Some(mapper) = self.mapper.as_mut() {
+            mapper.set_tilt(x, y);
         }
     }
-    pub fn load_rom(&mut self, rom_path: &str) {
-        let mut rom = File::open(rom_path).unwrap_or_else(|_err| panic!("Valid ROM needed!"));
-        rom.read_to_end(&mut self.rom).unwrap_or_else(|_err| panic!("Error reading ROM"));
 
-	for i in 0..self.rom_bank_0.len() {
-            let item = self.rom.get(i);
-            match item {
-                Some(byte) => { self.rom_bank_0[i] = byte.to_owned() },
-                None => { return; }
-            }
+    /// Advance the memory mapped hardware by a number of CPU T-cycles. In double speed the timer,
+    /// serial port and OAM DMA follow the CPU, while the APU and the cartridge clock keep running at
+    /// normal speed
+    pub fn tick(&mut self, cycles: u8) {
+        let normal_cycles = self.normal_speed_cycles(cycles);
+        self.cycles += normal_cycles as u64;
+        if let Some(mapper) = self.mapper.as_mut() {
+            mapper.tick(normal_cycles);
+        }
+        if self.timer.tick(cycles) {
+            self.interrupts.request(Interrupt::Timer);
         }
-	for i in 0..self.rom_bank_n.len() {
-            let item = self.rom.get(i + self.rom_bank_0.len());
-            match item {
-                Some(byte) => { self.rom_bank_n[i] = byte.to_owned() },
-                None => { return; }
-            }
+        // DIV counts twice as fast in double speed, the frame sequencer watches the next bit up
+        let div = if self.double_speed() { self.timer.read_div() >> 1 } else { self.timer.read_div() };
+        self.apu.tick(normal_cycles, div);
+        if self.serial.tick(cycles) {
+            self.interrupts.request(Interrupt::Serial);
+        }
+        for (source, offset) in self.dma.tick(cycles) {
+            self.object_attribute_memory[offset] = self.read_dma_source(source);
+        }
+    }
+
+    /// Whether the CPU runs at double speed, only possible in CGB mode
+    pub fn double_speed(&self) -> bool {
+        self.cgb.key1 & 0x80 != 0
+    }
+
+    /// Convert CPU T-cycles to T-cycles at normal speed, the clock of the PPU and the APU
+    pub fn normal_speed_cycles(&self, cycles: u8) -> u8 {
+        if self.double_speed() { cycles / 2 } else { cycles }
+    }
+
+    // OAM DMA reads 0xE000-0xFFFF from WRAM
+    fn read_dma_source(&self, address: u16) -> u8 {
+        let address = if address >= 0xe000 { address - 0x2000 } else { address };
+        match address {
+            0x8000..=0x9fff => self.vram[address as usize - 0x8000],
+            _ => self.read_mapped(address),
+        }
+    }
+
+    /// Switch between normal and double speed if a switch was armed by writing to KEY1
+    /// Returns whether the speed was switched. Called by the CPU when executing STOP
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb_mode || self.cgb.key1 & 0b1 == 0 {
+            return false;
         }
+        self.cgb.key1 = (self.cgb.key1 ^ 0x80) & 0x80;
+        true
     }
+
     /// Write to memory
     /// Write a 8 bit value to memory addressed in 16 bits
     /// The function decides which bank to write to based on the address value
@@ -55,18 +169,27 @@ impl MMU {
     /// * `address` - Address to write to
     /// * `value` - Value to write to address
     pub fn write_memory(&mut self, address: u16, value: u8) {
+        // The CPU can only reach HRAM and I/O during OAM DMA
+        if self.dma.active() && address < 0xff00 {
+            return;
+        }
         match address {
-            0x0000..=0x3fff => { println!("Writing: ROM Bank 0: 0x{:04X}", address); self.rom_bank_0[address as usize] = value  }, //Fixed bank, rom_bank_0
-            0x4000..=0x7fff => { println!("Writing: ROM Bank 1: 0x{:04X}", address); self.rom_bank_n[address as usize - 0x4000] = value },
-            0x8000..=0x9fff => {  },
-            0xa000..=0xbfff => {  },
-            0xc000..=0xcfff => {  },
-            0xd000..=0xdfff => {  },
-            0xfe00..=0xfe9f => {  },
-            0xff00..=0xff7f => {  },
-            0xff80..=0xfffe => {  },
-            0xffff..=0xffff => {  },
-            _ => {  }
+            0x0000..=0x7fff => {
+                trace!(Mmu, Trace, "Writing: Mapper register: 0x{:04X} = 0x{:02X}", address, value);
+                if let Some(mapper) = self.mapper.as_mut() { mapper.write_rom(address, value) }
+            },
+            0x8000..=0x9fff => { if self.lcd.mode.vram_accessible() { self.vram[address as usize - 0x8000] = value } },
+            0xa000..=0xbfff => { if let Some(mapper) = self.mapper.as_mut() { mapper.write_ram(address, value) } },
+            0xc000..=0xcfff => { self.wram_bank_0[address as usize - 0xc000] = value },
+            0xd000..=0xdfff => { self.wram_bank_n[address as usize - 0xd000] = value },
+            // Echo RAM, mirrors 0xC000-0xDDFF
+            0xe000..=0xfdff => { self.write_memory(address - 0x2000, value) },
+            0xfe00..=0xfe9f => { if self.lcd.mode.oam_accessible() { self.object_attribute_memory[address as usize - 0xfe00] = value } },
+            // Unusable, writes are ignored
+            0xfea0..=0xfeff => {  },
+            0xff00..=0xff7f => { self.write_io(IoRegister::from_address(address), value) },
+            0xff80..=0xfffe => { self.hram[address as usize - 0xff80] = value },
+            IE_ADDRESS => { self.interrupts.enable = value },
         };
     }
     /// Read from memory
@@ -76,19 +199,245 @@ impl MMU {
     /// * `address` - 16 bit address to access
     ///
     pub fn read_memory(&self, address: u16) -> u8 {
+        // The CPU can only reach HRAM and I/O during OAM DMA
+        if self.dma.active() && address < 0xff00 {
+            return 0xff;
+        }
+        self.read_mapped(address)
+    }
+
+    // Read from memory, ignoring OAM DMA
+    fn read_mapped(&self, address: u16) -> u8 {
         let address = match address {
-            0x0000..=0x3fff => { println!("Reading ROM Bank 0: 0x{:04X}", address); self.rom_bank_0[address as usize]  }, //Fixed bank, rom_bank_0
-            0x4000..=0x7fff => { println!("Reading ROM Bank 1: 0x{:04X}", address); self.rom_bank_n[address as usize - 0x4000] },
-            0x8000..=0x9fff => { 0x0 },
-            0xa000..=0xbfff => { 0x0 },
-            0xc000..=0xcfff => { 0x0 },
-            0xd000..=0xdfff => { 0x0 },
-            0xfe00..=0xfe9f => { 0x0 },
-            0xff00..=0xff7f => { 0x0 },
-            0xff80..=0xfffe => { 0x0 },
-            0xffff..=0xffff => { 0x0 },
-            _ => { 0xff }
+            0x0000..=0x7fff => {
+                trace!(Mmu, Trace, "Reading ROM: 0x{:04X}", address);
+                self.mapper.as_ref().map_or(0xff, |mapper| mapper.read_rom(address))
+            },
+            0x8000..=0x9fff => { if self.lcd.mode.vram_accessible() { self.vram[address as usize - 0x8000] } else { 0xff } },
+            0xa000..=0xbfff => { self.mapper.as_ref().map_or(0xff, |mapper| mapper.read_ram(address)) },
+            0xc000..=0xcfff => { self.wram_bank_0[address as usize - 0xc000] },
+            0xd000..=0xdfff => { self.wram_bank_n[address as usize - 0xd000] },
+            // Echo RAM, mirrors 0xC000-0xDDFF
+            0xe000..=0xfdff => { self.read_mapped(address - 0x2000) },
+            0xfe00..=0xfe9f => { if self.lcd.mode.oam_accessible() { self.object_attribute_memory[address as usize - 0xfe00] } else { 0xff } },
+            // Unusable, reads 0x00 on DMG, or 0xFF while OAM is blocked
+            0xfea0..=0xfeff => { if self.lcd.mode.oam_accessible() { 0x00 } else { 0xff } },
+            0xff00..=0xff7f => { self.read_io(IoRegister::from_address(address)) },
+            0xff80..=0xfffe => { self.hram[address as usize - 0xff80] },
+            IE_ADDRESS => { self.interrupts.enable },
         };
         address
     }
+
+    // Route an I/O register read to the component owning it, unused bits read as 1
+    fn read_io(&self, register: IoRegister) -> u8 {
+        let value = match register {
+            IoRegister::P1 => self.joypad.read(),
+            IoRegister::SB => self.serial.read_data(),
+            IoRegister::SC => self.serial.read_control(),
+            IoRegister::DIV => self.timer.read_div(),
+            IoRegister::TIMA => self.timer.read_tima(),
+            IoRegister::TMA => self.timer.read_tma(),
+            IoRegister::TAC => self.timer.read_tac(),
+            IoRegister::IF => self.interrupts.read_flag(),
+            IoRegister::Audio(offset) => self.apu.read(offset),
+            IoRegister::WaveRam(offset) => self.apu.read_wave_ram(offset),
+            IoRegister::LCDC => self.lcd.lcdc,
+            IoRegister::STAT => self.lcd.read_stat(),
+            IoRegister::SCY => self.lcd.scy,
+            IoRegister::SCX => self.lcd.scx,
+            IoRegister::LY if self.doctor_mode => 0x90,
+            IoRegister::LY => self.lcd.ly,
+            IoRegister::LYC => self.lcd.lyc,
+            IoRegister::DMA => self.dma.read(),
+            IoRegister::BGP => self.lcd.bgp,
+            IoRegister::OBP0 => self.lcd.obp0,
+            IoRegister::OBP1 => self.lcd.obp1,
+            IoRegister::WY => self.lcd.wy,
+            IoRegister::WX => self.lcd.wx,
+            _ if !self.cgb_mode => 0xff,
+            IoRegister::KEY1 => self.cgb.key1 | 0x7e,
+            IoRegister::VBK => self.cgb.vbk | 0xfe,
+            IoRegister::RP => self.cgb.rp | 0x3c,
+            IoRegister::BCPS => self.cgb.bcps | 0x40,
+            IoRegister::BCPD => self.cgb.read_bcpd(),
+            IoRegister::OCPS => self.cgb.ocps | 0x40,
+            IoRegister::OCPD => self.cgb.read_ocpd(),
+            IoRegister::OPRI => self.cgb.opri | 0xfe,
+            IoRegister::SVBK => self.cgb.svbk | 0xf8,
+            // HDMA1-4 are write only, HDMA5 reads the remaining length
+            IoRegister::HDMA(4) => self.cgb.hdma[4],
+            _ => 0xff,
+        };
+        value | register.read_mask()
+    }
+
+    // Route an I/O register write to the component owning it
+    fn write_io(&mut self, register: IoRegister, value: u8) {
+        match register {
+            IoRegister::P1 => {
+                // Selecting a line with a pressed key pulls it low too
+                let interrupt = self.joypad.write(value);
+                if interrupt {
+                    self.interrupts.request(Interrupt::Joypad);
+                }
+            },
+            IoRegister::SB => self.serial.write_data(value),
+            IoRegister::SC => self.serial.write_control(value),
+            IoRegister::DIV => self.timer.write_div(),
+            IoRegister::TIMA => self.timer.write_tima(value),
+            IoRegister::TMA => self.timer.write_tma(value),
+            IoRegister::TAC => self.timer.write_tac(value),
+            IoRegister::IF => self.interrupts.write_flag(value),
+            IoRegister::Audio(offset) => self.apu.write(offset, value),
+            IoRegister::WaveRam(offset) => self.apu.write_wave_ram(offset, value),
+            IoRegister::LCDC => self.lcd.lcdc = value,
+            IoRegister::STAT => self.lcd.write_stat(value),
+            IoRegister::SCY => self.lcd.scy = value,
+            IoRegister::SCX => self.lcd.scx = value,
+            // LY is read only
+            IoRegister::LY => {},
+            IoRegister::LYC => self.lcd.lyc = value,
+            IoRegister::DMA => self.dma.write(value),
+            IoRegister::BGP => self.lcd.bgp = value,
+            IoRegister::OBP0 => self.lcd.obp0 = value,
+            IoRegister::OBP1 => self.lcd.obp1 = value,
+            IoRegister::WY => self.lcd.wy = value,
+            IoRegister::WX => self.lcd.wx = value,
+            _ if !self.cgb_mode => {},
+            IoRegister::KEY1 => self.cgb.key1 = (self.cgb.key1 & 0x80) | (value & 0b1),
+            IoRegister::VBK => self.cgb.vbk = value & 0b1,
+            IoRegister::RP => self.cgb.rp = value & 0xc1,
+            IoRegister::BCPS => self.cgb.bcps = value & 0xbf,
+            IoRegister::BCPD => self.cgb.write_bcpd(value),
+            IoRegister::OCPS => self.cgb.ocps = value & 0xbf,
+            IoRegister::OCPD => self.cgb.write_ocpd(value),
+            IoRegister::OPRI => self.cgb.opri = value & 0b1,
+            IoRegister::SVBK => self.cgb.svbk = value & 0b111,
+            IoRegister::HDMA(index) => self.cgb.hdma[index as usize] = value,
+            _ => {},
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const KEY1: u16 = 0xff4d;
+
+    // MMU with a 32 KiB ROM only cartridge, with the given CGB flag at 0x0143
+    fn cartridge_mmu(cgb_flag: u8) -> MMU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x143] = cgb_flag;
+        let mut mmu = MMU::new();
+        mmu.load_cartridge(Cartridge::from_bytes(rom).unwrap()).unwrap();
+        mmu
+    }
+
+    // MMU with a CGB cartridge, forced into CGB mode to exercise the CGB registers
+    fn mmu(cgb_mode: bool) -> MMU {
+        let mut mmu = cartridge_mmu(0x80);
+        mmu.cgb_mode = cgb_mode;
+        mmu
+    }
+
+    #[test]
+    fn cgb_cartridges_run_in_dmg_mode() {
+        for cgb_flag in [0x00, 0x80, 0xc0] {
+            let mut mmu = cartridge_mmu(cgb_flag);
+            assert!(!mmu.cgb_mode);
+            mmu.write_memory(KEY1, 0x01);
+            assert_eq!(mmu.read_memory(KEY1), 0xff);
+            assert!(!mmu.switch_speed());
+        }
+    }
+
+    #[test]
+    fn speed_switch_needs_cgb_mode_and_key1() {
+        let mut mmu = self::mmu(false);
+        mmu.write_memory(KEY1, 0x01);
+        assert!(!mmu.switch_speed());
+        assert!(!mmu.double_speed());
+
+        let mut mmu = self::mmu(true);
+        assert!(!mmu.switch_speed());
+        mmu.write_memory(KEY1, 0x01);
+        assert!(mmu.switch_speed());
+        assert!(mmu.double_speed());
+        // The switch disarms itself
+        assert!(!mmu.switch_speed());
+        mmu.write_memory(KEY1, 0x01);
+        assert!(mmu.switch_speed());
+        assert!(!mmu.double_speed());
+    }
+
+    #[test]
+    fn double_speed_halves_normal_speed_time() {
+        let mut mmu = self::mmu(true);
+        mmu.write_memory(KEY1, 0x01);
+        mmu.switch_speed();
+        mmu.timer.write_div();
+        for _ in 0..64 {
+            mmu.tick(4);
+        }
+        // The timer follows the CPU, everything else sees half the time
+        assert_eq!(mmu.timer.read_div(), 1);
+        assert_eq!(mmu.cycles, 128);
+        assert_eq!(mmu.normal_speed_cycles(4), 2);
+    }
+
+    #[test]
+    fn io_reads_set_unused_bits() {
+        let mut mmu = self::mmu(false);
+        mmu.write_memory(0xff07, 0x05);
+        assert_eq!(mmu.read_memory(0xff07), 0xfd);
+        mmu.write_memory(0xff0f, 0x01);
+        assert_eq!(mmu.read_memory(0xff0f), 0xe1);
+        mmu.write_memory(0xff06, 0x42);
+        assert_eq!(mmu.read_memory(0xff06), 0x42);
+        mmu.write_memory(0xff47, 0xe4);
+        assert_eq!(mmu.read_memory(0xff47), 0xe4);
+        // Unmapped registers read 0xFF
+        assert_eq!(mmu.read_memory(0xff03), 0xff);
+        assert_eq!(mmu.read_memory(0xff7f), 0xff);
+    }
+
+    #[test]
+    fn cgb_registers_only_exist_in_cgb_mode() {
+        let mut mmu = self::mmu(false);
+        mmu.write_memory(0xff4f, 0x00);
+        mmu.write_memory(0xff70, 0x00);
+        assert_eq!(mmu.read_memory(0xff4f), 0xff);
+        assert_eq!(mmu.read_memory(0xff70), 0xff);
+        assert_eq!(mmu.read_memory(KEY1), 0xff);
+
+        let mut mmu = self::mmu(true);
+        mmu.write_memory(0xff4f, 0x00);
+        assert_eq!(mmu.read_memory(0xff4f), 0xfe);
+        mmu.write_memory(0xff70, 0x03);
+        assert_eq!(mmu.read_memory(0xff70), 0xfb);
+        mmu.write_memory(0xff6c, 0x00);
+        assert_eq!(mmu.read_memory(0xff6c), 0xfe);
+        mmu.write_memory(KEY1, 0x01);
+        assert_eq!(mmu.read_memory(KEY1), 0x7f);
+        mmu.switch_speed();
+        // Bit 7 reports the current speed
+        assert_eq!(mmu.read_memory(KEY1), 0xfe);
+    }
+
+    #[test]
+    fn cgb_palette_index_auto_increments() {
+        let mut mmu = self::mmu(true);
+        mmu.write_memory(0xff68, 0x80);
+        mmu.write_memory(0xff69, 0x12);
+        mmu.write_memory(0xff69, 0x34);
+        assert_eq!(mmu.read_memory(0xff68), 0xc2);
+        mmu.write_memory(0xff68, 0x01);
+        assert_eq!(mmu.read_memory(0xff69), 0x34);
+        // Without bit 7 the index stays put
+        mmu.write_memory(0xff69, 0x56);
+        assert_eq!(mmu.read_memory(0xff68), 0x41);
+        assert_eq!(mmu.read_memory(0xff69), 0x56);
+    }
 }
@@ -1,5 +1,29 @@
 pub mod cpu;
 pub mod ppu;
+pub mod fifo;
 pub mod dassm;
 pub mod mmu;
+pub mod cartridge;
+pub mod mapper;
+pub mod save;
+pub mod bus;
+pub mod io;
+pub mod joypad;
+pub mod serial;
+pub mod timer;
+pub mod apu;
+pub mod lcd;
+pub mod dma;
+pub mod cgb;
 pub mod emulator;
+pub mod interrupts;
+pub mod trace;
+pub mod doctor;
+#[cfg(test)]
+mod sm83_tests;
+#[cfg(test)]
+mod test_roms;
+#[cfg(test)]
+mod mooneye_tests;
+#[cfg(test)]
+mod ppu_tests;
@@ -0,0 +1,37 @@
+//! Runs the mooneye test suite ROMs (https://github.com/Gekkio/mooneye-test-suite)
+//!
+//! The ROMs are not shipped with the crate. Build the suite or download a release, then point
+//! `MOONEYE_TESTS_DIR` at its `acceptance` directory and run `cargo test -- --ignored mooneye`.
+//! Every ROM of the covered subdirectories is run until it executes `LD B, B`, the suite's
+//! breakpoint, and passes if the registers hold the Fibonacci sequence 3, 5, 8, 13, 21, 34.
+
+use std::path::Path;
+
+use super::test_roms::{load, roms_in, run_roms, run_until, tests_dir, BREAKPOINT_OPCODE};
+
+// B, C, D, E, H and L after a passing test
+const PASS_REGISTERS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+
+// Run a ROM until the breakpoint and check the registers
+fn run(rom: &Path) -> Result<(), String> {
+    let mut emulator = load(rom)?;
+    run_until(&mut emulator, |emulator| emulator.cpu.instr == BREAKPOINT_OPCODE)?;
+    let cpu = &emulator.cpu;
+    let registers = [cpu.b, cpu.c, cpu.d, cpu.e, cpu.h, cpu.l];
+    if registers != PASS_REGISTERS {
+        return Err(format!("failed with registers {:02X?}", registers));
+    }
+    Ok(())
+}
+
+// Run every ROM of a subdirectory of `acceptance`
+fn run_dir(name: &str) {
+    let roms = roms_in(&tests_dir("MOONEYE_TESTS_DIR").join(name));
+    run_roms(&roms, name, run);
+}
+
+#[test]
+#[ignore = "needs the mooneye test suite, see MOONEYE_TESTS_DIR"]
+fn mooneye_acceptance_timer() {
+    run_dir("timer");
+}
@@ -1,28 +1,368 @@
+//! PPU timing and the scanline renderer
+//!
+//! Every line takes 456 dots (T-cycles): 80 in mode 2 scanning OAM, mode 3 drawing and the rest in
+//! mode 0. Lines 144-153 are spent in mode 1.
+//!
+//! Two renderers draw mode 3:
+//! * `Renderer::Scanline` draws a whole line at once when mode 3 starts and always takes 172 dots,
+//!   register changes in the middle of a line are not visible
+//! * `Renderer::Fifo` models the pixel fetchers and FIFOs, see `fifo.rs`
+
+use super::fifo::Fifo;
+use super::interrupts::Interrupt;
+use super::lcd::*;
+use super::mmu::MMU;
+use super::trace::trace;
+
+pub const SCREEN_WIDTH: usize = 160;
+pub const SCREEN_HEIGHT: usize = 144;
+
+const DOTS_PER_LINE: u16 = 456;
+const OAM_SCAN_DOTS: u16 = 80;
+const DRAWING_DOTS: u16 = 172;
+const LINES_PER_FRAME: u8 = 154;
+pub const CYCLES_PER_FRAME: u32 = DOTS_PER_LINE as u32 * LINES_PER_FRAME as u32;
+const MAX_OBJECTS_PER_LINE: usize = 10;
+
+// Object attribute flags
+pub const OBJ_BEHIND_BG: u8 = 0x80;
+pub const OBJ_Y_FLIP: u8 = 0x40;
+pub const OBJ_X_FLIP: u8 = 0x20;
+pub const OBJ_PALETTE_1: u8 = 0x10;
+
+/// Implementation used to draw mode 3
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renderer {
+    Scanline,
+    Fifo,
+}
+
+impl Renderer {
+    /// Parse a renderer name, scanline or fifo
+    pub fn parse(name: &str) -> Result<Self, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "scanline" => Ok(Renderer::Scanline),
+            "fifo" => Ok(Renderer::Fifo),
+            _ => Err(format!("Unknown renderer {}, expected scanline or fifo", name)),
+        }
+    }
+}
+
+/// PPU mode, as reported in the lower two bits of STAT
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    HBlank = 0,
+    VBlank = 1,
+    OamScan = 2,
+    Drawing = 3,
+}
+
+impl Mode {
+    /// Whether the CPU can access VRAM, the PPU reads it while drawing
+    pub fn vram_accessible(self) -> bool {
+        self != Mode::Drawing
+    }
+
+    /// Whether the CPU can access OAM, the PPU reads it while scanning and drawing
+    pub fn oam_accessible(self) -> bool {
+        matches!(self, Mode::HBlank | Mode::VBlank)
+    }
+}
+
+/// Object from OAM
+#[derive(Debug, Clone, Copy)]
+pub struct Object {
+    pub y: i16, // Screen position of the top left corner
+    pub x: i16,
+    pub tile: u8,
+    pub flags: u8,
+}
+
 pub struct PPU {
+    renderer: Renderer,
+    line_renderer: Renderer, // Renderer drawing the current line, a new one takes over on the next line
+    fifo: Fifo,
+    dots: u16, // Position in the current line
+    window_line: u8, // Line of the window to draw next, it only advances on lines showing the window
+    stat_line: bool, // STAT interrupt line, the interrupt is raised on its rising edge
+    /// Shades of the last frame, 0 (white) to 3 (black), row by row
+    pub frame: [u8; SCREEN_WIDTH * SCREEN_HEIGHT],
+    frame_ready: bool,
 }
 
 impl PPU {
     pub fn new() -> Self {
-        Self {  }
-    }
-
-    // fn mode1(&mut self, scanline: u8) {
-    // }
-    // fn mode2(&mut self, scanline: u8) {
-    // }
-    // fn mode3(&mut self, scanline: u8) {
-    // }
-    // fn mode0(&mut self, scanline: u8) {
-    // }
-    // fn render(&mut self) {
-    //     // For each scan line
-    //     for line in 0..144 {
-    //         self.mode2(line);
-    //         self.mode3(line);
-    //         self.mode0(line);
-    //     }
-    //     for line in 144..154 {
-    //         self.mode1(line);
-    //     }
-    // }
+        Self {
+            renderer: Renderer::Scanline,
+            line_renderer: Renderer::Scanline,
+            fifo: Fifo::new(),
+            dots: 0,
+            window_line: 0,
+            stat_line: false,
+            frame: [0; SCREEN_WIDTH * SCREEN_HEIGHT],
+            frame_ready: false,
+        }
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    /// Change the renderer, it takes over at the start of the next line
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    /// Returns true once after every completed frame
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    /// Advance the PPU by a number of T-cycles
+    ///
+    /// # Arguments
+    ///
+    /// * `mmu` - Memory holding the LCD registers, VRAM and OAM
+    /// * `cycles` - Number of T-cycles to advance by
+    ///
+    pub fn tick(&mut self, mmu: &mut MMU, cycles: u8) {
+        if mmu.lcd.lcdc & LCDC_ENABLE == 0 {
+            // The LCD is off, it restarts at the beginning of line 0
+            self.dots = 0;
+            self.window_line = 0;
+            self.fifo.start_frame();
+            mmu.lcd.ly = 0;
+            mmu.lcd.mode = Mode::HBlank;
+            self.stat_line = false;
+            return;
+        }
+        for _ in 0..cycles {
+            self.dot(mmu);
+        }
+    }
+
+    fn dot(&mut self, mmu: &mut MMU) {
+        self.dots += 1;
+        if self.dots == DOTS_PER_LINE {
+            self.dots = 0;
+            mmu.lcd.ly = (mmu.lcd.ly + 1) % LINES_PER_FRAME;
+            if mmu.lcd.ly == 0 {
+                self.window_line = 0;
+                self.fifo.start_frame();
+            }
+        }
+        let ly = mmu.lcd.ly as usize;
+        let mode = match (ly, self.dots) {
+            (SCREEN_HEIGHT.., _) => Mode::VBlank,
+            (_, 0..OAM_SCAN_DOTS) => Mode::OamScan,
+            _ if mmu.lcd.mode == Mode::OamScan => Mode::Drawing,
+            _ if mmu.lcd.mode == Mode::Drawing && !self.draw(mmu) => Mode::Drawing,
+            _ => Mode::HBlank,
+        };
+        if mode != mmu.lcd.mode {
+            trace!(Ppu, Trace, "LY {}: mode {:?} at dot {}", ly, mode, self.dots);
+            match mode {
+                Mode::Drawing => {
+                    self.line_renderer = self.renderer;
+                    match self.line_renderer {
+                        Renderer::Scanline => self.render_line(mmu),
+                        Renderer::Fifo => self.fifo.start_line(mmu),
+                    }
+                }
+                Mode::VBlank => {
+                    mmu.interrupts.request(Interrupt::VBlank);
+                    self.frame_ready = true;
+                }
+                _ => {}
+            }
+            mmu.lcd.mode = mode;
+        }
+        self.update_stat_line(mmu);
+    }
+
+    // Run mode 3 for a dot, returns true once the line is drawn
+    fn draw(&mut self, mmu: &MMU) -> bool {
+        match self.line_renderer {
+            Renderer::Scanline => self.dots >= OAM_SCAN_DOTS + DRAWING_DOTS,
+            Renderer::Fifo => {
+                let start = mmu.lcd.ly as usize * SCREEN_WIDTH;
+                self.fifo.dot(mmu, &mut self.frame[start..start + SCREEN_WIDTH])
+            }
+        }
+    }
+
+    // The STAT interrupt is raised when any of the selected conditions becomes true
+    fn update_stat_line(&mut self, mmu: &mut MMU) {
+        let lcd = &mmu.lcd;
+        let line = (lcd.stat & STAT_LYC != 0 && lcd.ly == lcd.lyc)
+            || (lcd.stat & STAT_OAM != 0 && lcd.mode == Mode::OamScan)
+            || (lcd.stat & STAT_VBLANK != 0 && lcd.mode == Mode::VBlank)
+            || (lcd.stat & STAT_HBLANK != 0 && lcd.mode == Mode::HBlank);
+        if line && !self.stat_line {
+            mmu.interrupts.request(Interrupt::LcdStat);
+        }
+        self.stat_line = line;
+    }
+
+    // Color index (0-3) of a pixel in a tile
+    fn tile_pixel(mmu: &MMU, tile_address: u16, x: u8, y: u8) -> u8 {
+        let row = (tile_address - 0x8000) as usize + y as usize * 2;
+        let bit = 7 - x;
+        let low = (mmu.vram[row] >> bit) & 1;
+        let high = (mmu.vram[row + 1] >> bit) & 1;
+        high << 1 | low
+    }
+
+    /// Address of a background or window tile
+    ///
+    /// # Arguments
+    ///
+    /// * `lcdc` - LCDC, bit 4 selects the tile data area
+    /// * `index` - Tile index from the tile map
+    ///
+    pub fn bg_tile_address(lcdc: u8, index: u8) -> u16 {
+        if lcdc & LCDC_TILE_DATA != 0 {
+            0x8000 + index as u16 * 16
+        } else {
+            0x9000u16.wrapping_add_signed(index as i8 as i16 * 16)
+        }
+    }
+
+    /// Shade (0-3) of a color index through a palette register
+    pub fn shade(palette: u8, color: u8) -> u8 {
+        (palette >> (color * 2)) & 0b11
+    }
+
+    /// OAM scan, returns the first 10 objects on the current line in drawing order. The object
+    /// with the smallest X is on top, then the first one in OAM
+    pub fn scan_objects(mmu: &MMU) -> Vec<Object> {
+        let ly = mmu.lcd.ly as i16;
+        let height = Self::object_height(mmu.lcd.lcdc);
+        let mut objects: Vec<Object> = mmu
+            .object_attribute_memory
+            .chunks_exact(4)
+            .map(|entry| Object { y: entry[0] as i16 - 16, x: entry[1] as i16 - 8, tile: entry[2], flags: entry[3] })
+            .filter(|object| (object.y..object.y + height).contains(&ly))
+            .take(MAX_OBJECTS_PER_LINE)
+            .collect();
+        // The sort is stable, objects with the same X stay in OAM order
+        objects.sort_by_key(|object| object.x);
+        objects
+    }
+
+    fn object_height(lcdc: u8) -> i16 {
+        if lcdc & LCDC_OBJ_SIZE != 0 { 16 } else { 8 }
+    }
+
+    /// Color indexes of the row of an object on the current line, left to right after flipping
+    pub fn object_row(mmu: &MMU, object: &Object) -> [u8; 8] {
+        let height = Self::object_height(mmu.lcd.lcdc);
+        let mut row = (mmu.lcd.ly as i16 - object.y) as u8;
+        if object.flags & OBJ_Y_FLIP != 0 {
+            row = height as u8 - 1 - row;
+        }
+        // In 8x16 mode the lower bit of the tile index is ignored
+        let tile = if height == 16 { object.tile & 0xfe } else { object.tile };
+        let tile_address = 0x8000 + tile as u16 * 16 + (row / 8) as u16 * 16;
+        let mut colors = [0; 8];
+        for (column, color) in colors.iter_mut().enumerate() {
+            let tile_x = if object.flags & OBJ_X_FLIP != 0 { 7 - column as u8 } else { column as u8 };
+            *color = Self::tile_pixel(mmu, tile_address, tile_x, row % 8);
+        }
+        colors
+    }
+
+    fn render_line(&mut self, mmu: &MMU) {
+        let lcd = &mmu.lcd;
+        let ly = lcd.ly;
+        // Color indexes of the background and window, objects need them for their priority
+        let mut bg_colors = [0u8; SCREEN_WIDTH];
+        if lcd.lcdc & LCDC_BG_ENABLE != 0 {
+            let window_visible = lcd.lcdc & LCDC_WINDOW_ENABLE != 0 && ly >= lcd.wy && lcd.wx <= 166;
+            let window_start = lcd.wx as i16 - 7;
+            for (x, color) in bg_colors.iter_mut().enumerate() {
+                let (map, map_x, map_y) = if window_visible && x as i16 >= window_start {
+                    let map = if lcd.lcdc & LCDC_WINDOW_MAP != 0 { 0x9c00 } else { 0x9800 };
+                    (map, (x as i16 - window_start) as u8, self.window_line)
+                } else {
+                    let map = if lcd.lcdc & LCDC_BG_MAP != 0 { 0x9c00 } else { 0x9800 };
+                    (map, lcd.scx.wrapping_add(x as u8), lcd.scy.wrapping_add(ly))
+                };
+                let index = mmu.vram[map - 0x8000 + (map_y as usize / 8) * 32 + map_x as usize / 8];
+                *color = Self::tile_pixel(mmu, Self::bg_tile_address(lcd.lcdc, index), map_x % 8, map_y % 8);
+            }
+            if window_visible && window_start < SCREEN_WIDTH as i16 {
+                self.window_line += 1;
+            }
+        }
+
+        let line = &mut self.frame[ly as usize * SCREEN_WIDTH..(ly as usize + 1) * SCREEN_WIDTH];
+        for (pixel, &color) in line.iter_mut().zip(bg_colors.iter()) {
+            *pixel = Self::shade(lcd.bgp, color);
+        }
+
+        if lcd.lcdc & LCDC_OBJ_ENABLE == 0 {
+            return;
+        }
+        // Every pixel shows the first opaque object in priority order. That object hides the ones
+        // below it even when it is itself behind the background
+        let mut covered = [false; SCREEN_WIDTH];
+        for object in Self::scan_objects(mmu) {
+            let palette = if object.flags & OBJ_PALETTE_1 != 0 { lcd.obp1 } else { lcd.obp0 };
+            for (column, &color) in Self::object_row(mmu, &object).iter().enumerate() {
+                let x = object.x + column as i16;
+                // Color 0 is transparent
+                if !(0..SCREEN_WIDTH as i16).contains(&x) || color == 0 || covered[x as usize] {
+                    continue;
+                }
+                covered[x as usize] = true;
+                if object.flags & OBJ_BEHIND_BG == 0 || bg_colors[x as usize] == 0 {
+                    line[x as usize] = Self::shade(palette, color);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // MMU drawing line 0 with a background of color `bg_color`, and two overlapping objects: a
+    // color 3 object behind the background at X 0, and a color 2 object at X 4
+    fn mmu(bg_color: u8) -> MMU {
+        let mut mmu = MMU::new();
+        mmu.lcd.lcdc = LCDC_ENABLE | LCDC_TILE_DATA | LCDC_OBJ_ENABLE | LCDC_BG_ENABLE;
+        mmu.lcd.bgp = 0xe4;
+        mmu.lcd.obp0 = 0xe4;
+        mmu.lcd.ly = 0;
+        // Tile 0 for the background, tiles 1 and 2 for the objects, first row only
+        mmu.vram[0x00] = if bg_color & 1 != 0 { 0xff } else { 0x00 };
+        mmu.vram[0x01] = if bg_color & 2 != 0 { 0xff } else { 0x00 };
+        mmu.vram[0x10..0x12].copy_from_slice(&[0xff, 0xff]);
+        mmu.vram[0x20..0x22].copy_from_slice(&[0x00, 0xff]);
+        mmu.object_attribute_memory[0..4].copy_from_slice(&[16, 8, 1, OBJ_BEHIND_BG]);
+        mmu.object_attribute_memory[4..8].copy_from_slice(&[16, 12, 2, 0]);
+        mmu
+    }
+
+    fn render(mmu: &MMU) -> [u8; 16] {
+        let mut ppu = PPU::new();
+        ppu.render_line(mmu);
+        ppu.frame[..16].try_into().unwrap()
+    }
+
+    #[test]
+    fn object_behind_background_hides_lower_objects() {
+        let line = render(&mmu(1));
+        // The first object wins X 4-7 and stays behind the background, the second one only shows
+        // where the first one does not reach
+        assert_eq!(line, [1, 1, 1, 1, 1, 1, 1, 1, 2, 2, 2, 2, 1, 1, 1, 1]);
+    }
+
+    #[test]
+    fn object_behind_background_shows_over_color_0() {
+        let line = render(&mmu(0));
+        assert_eq!(line, [3, 3, 3, 3, 3, 3, 3, 3, 2, 2, 2, 2, 0, 0, 0, 0]);
+    }
 }
@@ -0,0 +1,77 @@
+//! Headless PPU tests comparing frames with reference screenshots
+//!
+//! The ROMs are not shipped with the crate. Point `PPU_TESTS_DIR` at a directory holding
+//! `dmg-acid2.gb` (https://github.com/mattcurrie/dmg-acid2) and a `mealybug` directory with ROMs
+//! from https://github.com/mattcurrie/mealybug-tearoom-tests, then run
+//! `cargo test -- --ignored ppu_tests`. Every ROM needs its DMG reference screenshot next to it,
+//! with the same name and a `.png` extension.
+//!
+//! The ROMs signal they are done by executing `LD B, B`, the frame drawn after that is compared.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::ppu::{Renderer, SCREEN_HEIGHT, SCREEN_WIDTH};
+use super::test_roms::{load, roms_in, run_roms, run_until, tests_dir, BREAKPOINT_OPCODE};
+
+// Shades (0-3) of a reference screenshot. Its colors are mapped to the closest gray, white to black
+fn load_reference(path: &Path) -> Result<Vec<u8>, String> {
+    let file = fs::File::open(path).map_err(|err| format!("cannot open {}: {}", path.display(), err))?;
+    let mut decoder = png::Decoder::new(file);
+    decoder.set_transformations(png::Transformations::EXPAND | png::Transformations::STRIP_16);
+    let mut reader = decoder.read_info().map_err(|err| err.to_string())?;
+    let mut buffer = vec![0; reader.output_buffer_size()];
+    let info = reader.next_frame(&mut buffer).map_err(|err| err.to_string())?;
+    if info.width as usize != SCREEN_WIDTH || info.height as usize != SCREEN_HEIGHT {
+        return Err(format!("reference is {}x{}, expected {}x{}", info.width, info.height, SCREEN_WIDTH, SCREEN_HEIGHT));
+    }
+    let channels = info.color_type.samples();
+    let shades = buffer[..info.buffer_size()]
+        .chunks_exact(channels)
+        .map(|pixel| {
+            // Average of the color channels, leaving out alpha
+            let colors = if channels >= 3 { &pixel[..3] } else { &pixel[..1] };
+            let level = colors.iter().map(|&value| value as u32).sum::<u32>() / colors.len() as u32;
+            3 - ((level + 42) / 85) as u8
+        })
+        .collect();
+    Ok(shades)
+}
+
+// Run a ROM until the frame after its breakpoint and compare it with the reference screenshot
+fn run(rom: &Path, renderer: Renderer) -> Result<(), String> {
+    let expected = load_reference(&rom.with_extension("png"))?;
+    let mut emulator = load(rom)?;
+    emulator.ppu.set_renderer(renderer);
+    run_until(&mut emulator, |emulator| emulator.cpu.instr == BREAKPOINT_OPCODE)?;
+    emulator.ppu.take_frame_ready();
+    run_until(&mut emulator, |emulator| emulator.ppu.take_frame_ready())?;
+
+    let mismatches = emulator.ppu.frame.iter().zip(&expected).filter(|(actual, expected)| actual != expected).count();
+    if mismatches == 0 {
+        return Ok(());
+    }
+    let first = emulator.ppu.frame.iter().zip(&expected).position(|(actual, expected)| actual != expected).unwrap();
+    Err(format!("{} pixels differ, first at ({}, {})", mismatches, first % SCREEN_WIDTH, first / SCREEN_WIDTH))
+}
+
+// Run ROMs with a renderer, panicking with the list of failures
+fn run_with(roms: &[PathBuf], renderer: Renderer) {
+    run_roms(roms, &format!("{:?}", renderer), |rom| run(rom, renderer));
+}
+
+#[test]
+#[ignore = "needs dmg-acid2, see PPU_TESTS_DIR"]
+fn dmg_acid2() {
+    let roms = [tests_dir("PPU_TESTS_DIR").join("dmg-acid2.gb")];
+    run_with(&roms, Renderer::Scanline);
+    run_with(&roms, Renderer::Fifo);
+}
+
+#[test]
+#[ignore = "needs the mealybug tearoom tests, see PPU_TESTS_DIR"]
+fn mealybug_tearoom() {
+    // Mid-line register changes are only visible with the FIFO renderer
+    let roms = roms_in(&tests_dir("PPU_TESTS_DIR").join("mealybug"));
+    run_with(&roms, Renderer::Fifo);
+}
@@ -0,0 +1,95 @@
+//! Battery backed save files
+//!
+//! Saves are kept next to the ROM with a `.sav` extension, like most emulators do, so saves can be
+//! moved between them. The file holds the raw cartridge RAM, followed by mapper specific data like
+//! the RTC footer of MBC3 carts.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use super::mapper::unix_time;
+use super::mmu::MMU;
+
+pub struct SaveFile {
+    path: PathBuf,
+    saved: Vec<u8>, // Last state written with a zero timestamp, to skip writing when nothing changed
+}
+
+impl SaveFile {
+    /// Save file of a ROM, the ROM path with a `.sav` extension
+    pub fn for_rom(rom_path: &str) -> Self {
+        Self { path: Path::new(rom_path).with_extension("sav"), saved: Vec::new() }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Load the save file into the cartridge
+    /// Returns false if there is no save file yet
+    pub fn load(&mut self, mmu: &mut MMU) -> io::Result<bool> {
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        mmu.load_save_data(&data);
+        self.saved = mmu.save_data(0);
+        Ok(true)
+    }
+
+    /// Write the battery backed state of the cartridge, if it changed since the last write
+    /// Only the RAM and clock registers are compared, the timestamp of the clock is filled in when
+    /// writing. The file is replaced at once, so a crash while saving cannot leave half a save behind
+    pub fn flush(&mut self, mmu: &MMU) -> io::Result<()> {
+        let data = mmu.save_data(0);
+        if data == self.saved {
+            return Ok(());
+        }
+        let temporary = self.path.with_extension("sav.tmp");
+        fs::write(&temporary, mmu.save_data(unix_time()))?;
+        fs::rename(&temporary, &self.path)?;
+        self.saved = data;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::cartridge::Cartridge;
+
+    // MMU with an MBC3 cartridge with RAM, a real time clock and a battery
+    fn mmu() -> MMU {
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x10;
+        rom[0x149] = 0x02;
+        let mut mmu = MMU::new();
+        mmu.load_cartridge(Cartridge::from_bytes(rom).unwrap()).unwrap();
+        mmu
+    }
+
+    #[test]
+    fn flush_only_writes_changes() {
+        let rom_path = std::env::temp_dir().join(format!("gameboy-save-test-{}.gb", std::process::id()));
+        let mut save = SaveFile::for_rom(&rom_path.to_string_lossy());
+        let mut mmu = mmu();
+        save.flush(&mmu).unwrap();
+        assert!(save.path().exists());
+        // The clock timestamp alone does not make the save dirty
+        fs::remove_file(save.path()).unwrap();
+        save.flush(&mmu).unwrap();
+        assert!(!save.path().exists());
+        // Enable RAM and write to it
+        mmu.write_memory(0x0000, 0x0a);
+        mmu.write_memory(0xa000, 0x42);
+        save.flush(&mmu).unwrap();
+        let data = fs::read(save.path()).unwrap();
+        fs::remove_file(save.path()).unwrap();
+        assert_eq!(data[0], 0x42);
+        // 8 KiB of RAM and the 48 byte RTC footer, with the timestamp of the write
+        assert_eq!(data.len(), 0x2000 + 48);
+        assert_ne!(u64::from_le_bytes(data[0x2000 + 40..].try_into().unwrap()), 0);
+    }
+}
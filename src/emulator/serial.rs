@@ -0,0 +1,62 @@
+//! Serial port, SB (0xFF01) and SC (0xFF02)
+//!
+//! No link cable is connected. A transfer started with the internal clock shifts the byte out
+//! at 8192 Hz while shifting in 1s, then raises the serial interrupt. Bytes sent are traced, test
+//! ROMs print their results through the serial port.
+
+use super::trace::trace;
+
+// T-cycles per transferred bit with the internal clock
+const CYCLES_PER_BIT: u32 = 512;
+const SC_TRANSFER: u8 = 0x80;
+const SC_INTERNAL_CLOCK: u8 = 0x01;
+
+#[derive(Debug)]
+pub struct Serial {
+    data: u8, // SB
+    control: u8, // SC, bit 7 transfer in progress, bit 0 internal clock
+    cycles: u32, // T-cycles left in the transfer
+}
+
+impl Serial {
+    pub fn new() -> Self {
+        Self { data: 0, control: 0, cycles: 0 }
+    }
+
+    pub fn read_data(&self) -> u8 {
+        self.data
+    }
+
+    pub fn write_data(&mut self, value: u8) {
+        self.data = value;
+    }
+
+    pub fn read_control(&self) -> u8 {
+        self.control
+    }
+
+    pub fn write_control(&mut self, value: u8) {
+        self.control = value & (SC_TRANSFER | SC_INTERNAL_CLOCK);
+        // With the external clock, the transfer waits for a link partner that never comes
+        if self.control == SC_TRANSFER | SC_INTERNAL_CLOCK {
+            trace!(Mmu, Info, "Serial: 0x{:02X} {:?}", self.data, self.data as char);
+            self.cycles = 8 * CYCLES_PER_BIT;
+        }
+    }
+
+    /// Advance a transfer by a number of T-cycles
+    /// Returns true when the transfer is done and the serial interrupt should be raised
+    pub fn tick(&mut self, cycles: u8) -> bool {
+        if self.cycles == 0 {
+            return false;
+        }
+        self.cycles = self.cycles.saturating_sub(cycles as u32);
+        if self.cycles > 0 {
+            return false;
+        }
+        // Nothing is connected, the line reads high
+        self.data = 0xff;
+        self.control &= !SC_TRANSFER;
+        true
+    }
+}
@@ -0,0 +1,221 @@
+//! Per-opcode conformance harness driven by the SM83 single-step JSON tests
+//! (https://github.com/SingleStepTests/sm83)
+//!
+//! The test vectors are not shipped with the crate, so the test is ignored by default. Point
+//! `SM83_TESTS_DIR` at the `v1` directory of a checkout, or place it in `tests/sm83/v1`, then run
+//! `cargo test -- --ignored sm83`. Set `SM83_TESTS_FILTER` to a file name prefix (e.g. `cb` or `3e`)
+//! to only run some opcodes.
+//!
+//! Besides the final registers and memory, the bus activity of every machine cycle is compared:
+//! the address and value of the read or write made in that cycle, if any.
+
+use std::fs;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+use super::bus::Bus;
+use super::cpu::{Flags, CPU};
+
+const DEFAULT_TESTS_DIR: &str = "tests/sm83/v1";
+// Number of failures printed in full for every opcode
+const MAX_REPORTED_FAILURES: usize = 1;
+
+#[derive(Debug, Deserialize)]
+struct TestCase {
+    name: String,
+    initial: CpuState,
+    #[serde(rename = "final")]
+    expected: CpuState,
+    cycles: Vec<serde_json::Value>,
+}
+
+#[derive(Debug, Deserialize)]
+struct CpuState {
+    pc: u16,
+    sp: u16,
+    a: u8,
+    b: u8,
+    c: u8,
+    d: u8,
+    e: u8,
+    f: u8,
+    h: u8,
+    l: u8,
+    ime: u8,
+    ram: Vec<(u16, u8)>,
+}
+
+// Memory access made during a machine cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Access {
+    Read(u16, u8),
+    Write(u16, u8),
+}
+
+// Flat 64 KiB memory with no mapping or I/O, recording the access made in every machine cycle
+struct TestBus {
+    memory: Vec<u8>,
+    cycles: Vec<Option<Access>>,
+    extra_writes: Vec<Access>, // Writes made in a cycle that already had an access
+}
+
+impl TestBus {
+    // Record the access of the current machine cycle. Reads beyond the first one of a cycle are
+    // the CPU peeking at IE and IF, which has no effect on a flat memory
+    fn record(&mut self, access: Access) {
+        match self.cycles.last_mut() {
+            Some(slot @ None) => *slot = Some(access),
+            _ if matches!(access, Access::Write(..)) => self.extra_writes.push(access),
+            _ => {}
+        }
+    }
+}
+
+impl Bus for TestBus {
+    fn read(&mut self, address: u16) -> u8 {
+        let value = self.memory[address as usize];
+        self.record(Access::Read(address, value));
+        value
+    }
+
+    fn write(&mut self, address: u16, value: u8) {
+        self.memory[address as usize] = value;
+        self.record(Access::Write(address, value));
+    }
+
+    fn tick(&mut self, cycles: u8) {
+        for _ in 0..cycles / 4 {
+            self.cycles.push(None);
+        }
+    }
+}
+
+// Parse a machine cycle of the test vectors, `[address, value, pins]`. The pins are three
+// characters, `r` or `w` when the cycle reads or writes memory. Cycles without an access are null
+// or have `-` for both
+fn parse_cycle(cycle: &serde_json::Value) -> Option<Access> {
+    let cycle = cycle.as_array()?;
+    let address = cycle.first()?.as_u64()? as u16;
+    let value = cycle.get(1)?.as_u64()? as u8;
+    let pins = cycle.get(2)?.as_str()?;
+    if pins.starts_with('r') {
+        Some(Access::Read(address, value))
+    } else if pins.get(1..2) == Some("w") {
+        Some(Access::Write(address, value))
+    } else {
+        None
+    }
+}
+
+fn setup(state: &CpuState) -> (CPU, TestBus) {
+    let mut cpu = CPU::new();
+    cpu.pc = state.pc;
+    cpu.sp = state.sp;
+    cpu.a = state.a;
+    cpu.b = state.b;
+    cpu.c = state.c;
+    cpu.d = state.d;
+    cpu.e = state.e;
+    cpu.h = state.h;
+    cpu.l = state.l;
+    cpu.ime = state.ime;
+    cpu.flags = Flags::from_bits(state.f);
+    let mut bus = TestBus { memory: vec![0; 0x10000], cycles: Vec::new(), extra_writes: Vec::new() };
+    for &(address, value) in &state.ram {
+        bus.memory[address as usize] = value;
+    }
+    (cpu, bus)
+}
+
+// Compare the CPU and memory against the expected state, returning every mismatch
+fn diff(cpu: &CPU, bus: &TestBus, test: &TestCase) -> Vec<String> {
+    let expected = &test.expected;
+    let registers = [
+        ("a", cpu.a as u16, expected.a as u16),
+        ("b", cpu.b as u16, expected.b as u16),
+        ("c", cpu.c as u16, expected.c as u16),
+        ("d", cpu.d as u16, expected.d as u16),
+        ("e", cpu.e as u16, expected.e as u16),
+        ("f", cpu.f() as u16, expected.f as u16),
+        ("h", cpu.h as u16, expected.h as u16),
+        ("l", cpu.l as u16, expected.l as u16),
+        ("sp", cpu.sp, expected.sp),
+        ("pc", cpu.pc, expected.pc),
+        ("ime", cpu.ime as u16, expected.ime as u16),
+    ];
+    let mut mismatches: Vec<String> = registers
+        .iter()
+        .filter(|(_, actual, expected)| actual != expected)
+        .map(|(name, actual, expected)| format!("{}: got 0x{:04X}, expected 0x{:04X}", name, actual, expected))
+        .collect();
+    for &(address, value) in &expected.ram {
+        let actual = bus.memory[address as usize];
+        if actual != value {
+            mismatches.push(format!("[0x{:04X}]: got 0x{:02X}, expected 0x{:02X}", address, actual, value));
+        }
+    }
+    let expected_cycles: Vec<Option<Access>> = test.cycles.iter().map(parse_cycle).collect();
+    if bus.cycles.len() != expected_cycles.len() {
+        mismatches.push(format!("cycles: got {}, expected {}", bus.cycles.len(), expected_cycles.len()));
+    }
+    for (cycle, (actual, expected)) in bus.cycles.iter().zip(&expected_cycles).enumerate() {
+        if actual != expected {
+            mismatches.push(format!("cycle {}: got {:X?}, expected {:X?}", cycle, actual, expected));
+        }
+    }
+    for access in &bus.extra_writes {
+        mismatches.push(format!("{:X?} shares a cycle with another access", access));
+    }
+    mismatches
+}
+
+fn tests_dir() -> PathBuf {
+    std::env::var_os("SM83_TESTS_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(env!("CARGO_MANIFEST_DIR")).join(DEFAULT_TESTS_DIR))
+}
+
+#[test]
+#[ignore = "needs the SM83 test vectors, see SM83_TESTS_DIR"]
+fn sm83_single_step() {
+    let dir = tests_dir();
+    let entries = fs::read_dir(&dir).unwrap_or_else(|err| panic!("Cannot read {}: {}", dir.display(), err));
+    let filter = std::env::var("SM83_TESTS_FILTER").unwrap_or_default();
+    let mut files: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "json"))
+        .filter(|path| path.file_name().is_some_and(|name| name.to_string_lossy().starts_with(filter.as_str())))
+        .collect();
+    files.sort();
+    assert!(!files.is_empty(), "No test vectors in {}", dir.display());
+
+    let mut summary = Vec::new();
+    for file in &files {
+        let contents = fs::read_to_string(file).unwrap_or_else(|err| panic!("Cannot read {}: {}", file.display(), err));
+        let tests: Vec<TestCase> = serde_json::from_str(&contents).unwrap_or_else(|err| panic!("Cannot parse {}: {}", file.display(), err));
+        let mut failures = Vec::new();
+        for test in &tests {
+            let (mut cpu, mut bus) = setup(&test.initial);
+            cpu.fetch(&mut bus);
+            cpu.execute(&mut bus);
+            let mismatches = diff(&cpu, &bus, test);
+            if !mismatches.is_empty() {
+                failures.push((test.name.as_str(), mismatches));
+            }
+        }
+        for (name, mismatches) in failures.iter().take(MAX_REPORTED_FAILURES) {
+            eprintln!("FAIL {}\n    {}", name, mismatches.join("\n    "));
+        }
+        let opcode = file.file_stem().unwrap().to_string_lossy().into_owned();
+        summary.push((opcode, tests.len(), failures.len()));
+    }
+
+    eprintln!("{:<8} {:>6} {:>6}", "opcode", "passed", "failed");
+    for (opcode, total, failed) in summary.iter().filter(|(_, _, failed)| *failed != 0) {
+        eprintln!("{:<8} {:>6} {:>6}", opcode, total - failed, failed);
+    }
+    let failing: Vec<&str> = summary.iter().filter(|(_, _, failed)| *failed != 0).map(|(opcode, _, _)| opcode.as_str()).collect();
+    eprintln!("{} of {} opcodes passing", summary.len() - failing.len(), summary.len());
+    assert!(failing.is_empty(), "Failing opcodes: {}", failing.join(", "));
+}
@@ -0,0 +1,76 @@
+//! Support for the runners of external test ROM suites
+//!
+//! The ROMs are not shipped with the crate, every runner reads them from a directory named by an
+//! environment variable and is `#[ignore]`d. The ROMs signal they are done by executing `LD B, B`.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::cartridge::Cartridge;
+use super::emulator::Emulator;
+use super::mapper::CYCLES_PER_SECOND;
+
+// LD B, B, executed by the ROMs once they are done
+pub const BREAKPOINT_OPCODE: u8 = 0x40;
+// Every test finishes well within this many emulated seconds
+const TIMEOUT_SECONDS: u64 = 10;
+
+/// Directory named by an environment variable, panicking when it is not set
+pub fn tests_dir(variable: &str) -> PathBuf {
+    let dir = std::env::var_os(variable).unwrap_or_else(|| panic!("{} is not set", variable));
+    PathBuf::from(dir)
+}
+
+/// The `.gb` files of a directory, sorted by name
+pub fn roms_in(dir: &Path) -> Vec<PathBuf> {
+    let entries = fs::read_dir(dir).unwrap_or_else(|err| panic!("Cannot read {}: {}", dir.display(), err));
+    let mut roms: Vec<PathBuf> = entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .filter(|path| path.extension().is_some_and(|ext| ext == "gb"))
+        .collect();
+    roms.sort();
+    roms
+}
+
+/// Create an emulator with a ROM inserted
+pub fn load(rom: &Path) -> Result<Emulator, String> {
+    let cartridge = Cartridge::load(&rom.to_string_lossy()).map_err(|err| err.to_string())?;
+    let mut emulator = Emulator::new();
+    emulator.mmu.load_cartridge(cartridge).map_err(|err| err.to_string())?;
+    Ok(emulator)
+}
+
+/// Step the emulator until `done` returns true, failing once the timeout is reached
+pub fn run_until(emulator: &mut Emulator, mut done: impl FnMut(&mut Emulator) -> bool) -> Result<(), String> {
+    while emulator.cpu.cycles < TIMEOUT_SECONDS * CYCLES_PER_SECOND as u64 {
+        emulator.step();
+        if done(emulator) {
+            return Ok(());
+        }
+    }
+    Err("timed out".to_string())
+}
+
+/// Run every ROM, printing its result, and panic with the list of failures
+///
+/// # Arguments
+///
+/// * `roms` - ROMs to run
+/// * `label` - Shown with the results, to tell runs of the same ROMs apart
+/// * `run` - Runs a ROM, returning an error describing the failure
+///
+pub fn run_roms(roms: &[PathBuf], label: &str, run: impl Fn(&Path) -> Result<(), String>) {
+    assert!(!roms.is_empty(), "No ROMs found ({})", label);
+    let mut failures = Vec::new();
+    for rom in roms {
+        let name = rom.file_name().unwrap().to_string_lossy().into_owned();
+        match run(rom) {
+            Ok(()) => eprintln!("PASS {} ({})", name, label),
+            Err(err) => {
+                eprintln!("FAIL {} ({}): {}", name, label, err);
+                failures.push(name);
+            }
+        }
+    }
+    assert!(failures.is_empty(), "Failing ROMs ({}): {}", label, failures.join(", "));
+}
@@ -0,0 +1,213 @@
+//! Timer, DIV (0xFF04), TIMA (0xFF05), TMA (0xFF06) and TAC (0xFF07)
+//!
+//! DIV is the upper byte of a 16 bit counter incremented every T-cycle. TIMA is incremented on the
+//! falling edge of one bit of that counter, selected by TAC, ANDed with the TAC enable bit. Since
+//! it is an edge detector, resetting the counter through DIV or changing TAC can also make the
+//! signal fall and increment TIMA.
+//!
+//! When TIMA overflows it reads 0 for one M-cycle, then TMA is copied into it and the timer
+//! interrupt is raised. Writing TIMA during that M-cycle cancels the reload, writing it during the
+//! reload M-cycle is ignored, and writing TMA during the reload M-cycle is copied into TIMA too.
+
+// Counter bit watched by TIMA, for every TAC clock select value
+const TAC_BITS: [u16; 4] = [9, 3, 5, 7];
+const TAC_ENABLE: u8 = 0b100;
+
+#[derive(Debug)]
+pub struct Timer {
+    counter: u16, // Internal counter, DIV is its upper byte
+    tima: u8,
+    tma: u8,
+    tac: u8,
+    overflow: bool, // TIMA overflowed in the last M-cycle, the reload happens in the next one
+    reloading: bool, // TMA is being copied into TIMA in this M-cycle
+}
+
+impl Timer {
+    pub fn new() -> Self {
+        // DIV value after the DMG boot ROM
+        Self { counter: 0xab00, tima: 0, tma: 0, tac: 0, overflow: false, reloading: false }
+    }
+
+    // Input of the falling edge detector
+    fn signal(&self) -> bool {
+        self.tac & TAC_ENABLE != 0 && self.counter & (1 << TAC_BITS[(self.tac & 0b11) as usize]) != 0
+    }
+
+    fn increment(&mut self) {
+        let (tima, overflow) = self.tima.overflowing_add(1);
+        self.tima = tima;
+        self.overflow |= overflow;
+    }
+
+    /// Advance the timer by a number of T-cycles, one M-cycle at a time
+    /// Returns true when the timer interrupt should be raised
+    pub fn tick(&mut self, cycles: u8) -> bool {
+        let mut interrupt = false;
+        for _ in 0..cycles / 4 {
+            self.reloading = false;
+            if self.overflow {
+                self.overflow = false;
+                self.reloading = true;
+                self.tima = self.tma;
+                interrupt = true;
+            }
+            let signal = self.signal();
+            self.counter = self.counter.wrapping_add(4);
+            if signal && !self.signal() {
+                self.increment();
+            }
+        }
+        interrupt
+    }
+
+    pub fn read_div(&self) -> u8 {
+        (self.counter >> 8) as u8
+    }
+
+    // Any write resets the whole counter
+    pub fn write_div(&mut self) {
+        let signal = self.signal();
+        self.counter = 0;
+        if signal {
+            self.increment();
+        }
+    }
+
+    pub fn read_tima(&self) -> u8 {
+        self.tima
+    }
+
+    pub fn write_tima(&mut self, value: u8) {
+        if self.reloading {
+            return;
+        }
+        self.tima = value;
+        self.overflow = false;
+    }
+
+    pub fn read_tma(&self) -> u8 {
+        self.tma
+    }
+
+    pub fn write_tma(&mut self, value: u8) {
+        self.tma = value;
+        if self.reloading {
+            self.tima = value;
+        }
+    }
+
+    pub fn read_tac(&self) -> u8 {
+        self.tac
+    }
+
+    pub fn write_tac(&mut self, value: u8) {
+        let signal = self.signal();
+        self.tac = value & 0b111;
+        if signal && !self.signal() {
+            self.increment();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Timer with the counter cleared and TIMA counting every 16 T-cycles
+    fn timer() -> Timer {
+        let mut timer = Timer::new();
+        timer.write_div();
+        timer.write_tac(TAC_ENABLE | 0b01);
+        timer
+    }
+
+    #[test]
+    fn tima_increments_on_falling_edge() {
+        let mut timer = timer();
+        // Bit 3 falls every 16 T-cycles
+        timer.tick(12);
+        assert_eq!(timer.read_tima(), 0);
+        timer.tick(4);
+        assert_eq!(timer.read_tima(), 1);
+        timer.tick(16 * 9);
+        assert_eq!(timer.read_tima(), 10);
+    }
+
+    #[test]
+    fn tima_does_not_count_when_disabled() {
+        let mut timer = timer();
+        timer.write_tac(0b01);
+        timer.tick(64);
+        assert_eq!(timer.read_tima(), 0);
+    }
+
+    #[test]
+    fn div_write_increments_tima_when_bit_is_set() {
+        let mut timer = timer();
+        timer.tick(8);
+        // Bit 3 is set, resetting the counter makes it fall
+        timer.write_div();
+        assert_eq!(timer.read_tima(), 1);
+        assert_eq!(timer.read_div(), 0);
+        // Bit 3 is clear, no edge
+        timer.tick(4);
+        timer.write_div();
+        assert_eq!(timer.read_tima(), 1);
+    }
+
+    #[test]
+    fn tac_write_increments_tima_when_signal_falls() {
+        let mut timer = timer();
+        timer.tick(8);
+        // Disabling the timer while the selected bit is set is a falling edge
+        timer.write_tac(0b01);
+        assert_eq!(timer.read_tima(), 1);
+        // So is selecting a bit that is clear
+        let mut timer = self::timer();
+        timer.tick(8);
+        timer.write_tac(TAC_ENABLE | 0b10);
+        assert_eq!(timer.read_tima(), 1);
+    }
+
+    #[test]
+    fn overflow_reloads_tma_one_cycle_later() {
+        let mut timer = timer();
+        timer.write_tma(0x42);
+        timer.write_tima(0xff);
+        assert!(!timer.tick(16));
+        // TIMA reads 0 for one M-cycle before the reload
+        assert_eq!(timer.read_tima(), 0);
+        assert!(timer.tick(4));
+        assert_eq!(timer.read_tima(), 0x42);
+    }
+
+    #[test]
+    fn tima_write_cancels_reload() {
+        let mut timer = timer();
+        timer.write_tma(0x42);
+        timer.write_tima(0xff);
+        timer.tick(16);
+        timer.write_tima(0x10);
+        assert!(!timer.tick(4));
+        assert_eq!(timer.read_tima(), 0x10);
+    }
+
+    #[test]
+    fn writes_during_reload_cycle() {
+        let mut timer = timer();
+        timer.write_tma(0x42);
+        timer.write_tima(0xff);
+        timer.tick(20);
+        // TIMA writes are ignored while TMA is being copied
+        timer.write_tima(0x10);
+        assert_eq!(timer.read_tima(), 0x42);
+        // TMA writes go through to TIMA
+        timer.write_tma(0x24);
+        assert_eq!(timer.read_tima(), 0x24);
+        // The reload cycle is over
+        timer.tick(4);
+        timer.write_tima(0x10);
+        assert_eq!(timer.read_tima(), 0x10);
+    }
+}
@@ -0,0 +1,225 @@
+//! Tracelogger
+//!
+//! Diagnostics are sent through the `trace!` macro with a subsystem and a level. Every subsystem
+//! has its own maximum level, and a message above it is discarded before being formatted, so a
+//! disabled trace only costs an atomic load. Enabled messages are written to the configured sink:
+//! stdout, a file, or a ring buffer kept in memory.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Off = 0,
+    Error = 1,
+    Warn = 2,
+    Info = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl FromStr for Level {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(Level::Off),
+            "error" => Ok(Level::Error),
+            "warn" => Ok(Level::Warn),
+            "info" => Ok(Level::Info),
+            "debug" => Ok(Level::Debug),
+            "trace" => Ok(Level::Trace),
+            _ => Err(format!("Unknown trace level: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Subsystem {
+    Cpu = 0,
+    Mmu = 1,
+    Ppu = 2,
+}
+
+const SUBSYSTEM_COUNT: usize = 3;
+
+impl FromStr for Subsystem {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "cpu" => Ok(Subsystem::Cpu),
+            "mmu" => Ok(Subsystem::Mmu),
+            "ppu" => Ok(Subsystem::Ppu),
+            _ => Err(format!("Unknown trace subsystem: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for Subsystem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Subsystem::Cpu => "cpu",
+            Subsystem::Mmu => "mmu",
+            Subsystem::Ppu => "ppu",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+/// Where enabled trace messages are written to
+pub enum Sink {
+    Stdout,
+    File(BufWriter<File>),
+    // Only the last `capacity` lines are kept
+    Ring { lines: VecDeque<String>, capacity: usize },
+}
+
+impl Sink {
+    pub fn file(path: &str) -> io::Result<Self> {
+        Ok(Sink::File(BufWriter::new(File::create(path)?)))
+    }
+
+    pub fn ring(capacity: usize) -> Self {
+        Sink::Ring { lines: VecDeque::with_capacity(capacity), capacity }
+    }
+}
+
+// Maximum enabled level of every subsystem, errors are reported by default
+static LEVELS: [AtomicU8; SUBSYSTEM_COUNT] = [const { AtomicU8::new(Level::Error as u8) }; SUBSYSTEM_COUNT];
+static SINK: Mutex<Sink> = Mutex::new(Sink::Stdout);
+
+/// Return whether a message of `level` from `subsystem` would be written
+#[inline]
+pub fn enabled(subsystem: Subsystem, level: Level) -> bool {
+    level as u8 <= LEVELS[subsystem as usize].load(Ordering::Relaxed)
+}
+
+pub fn set_level(subsystem: Subsystem, level: Level) {
+    LEVELS[subsystem as usize].store(level as u8, Ordering::Relaxed);
+}
+
+/// Set the levels parsed from a filter
+pub fn set_levels(levels: &[(Subsystem, Level)]) {
+    for &(subsystem, level) in levels {
+        set_level(subsystem, level);
+    }
+}
+
+/// Parse a filter, either a single level applied to every subsystem (e.g. `debug`) or a comma
+/// separated list of subsystem=level (e.g. `cpu=trace,mmu=info`). Returns the levels in order,
+/// later ones override earlier ones
+pub fn parse_filter(filter: &str) -> Result<Vec<(Subsystem, Level)>, String> {
+    let mut levels = Vec::new();
+    for directive in filter.split(',').map(str::trim).filter(|directive| !directive.is_empty()) {
+        match directive.split_once('=') {
+            Some((subsystem, level)) => levels.push((subsystem.trim().parse()?, level.trim().parse()?)),
+            None => {
+                let level: Level = directive.parse()?;
+                levels.extend([Subsystem::Cpu, Subsystem::Mmu, Subsystem::Ppu].map(|subsystem| (subsystem, level)));
+            }
+        }
+    }
+    Ok(levels)
+}
+
+/// Replace the sink, flushing the previous one
+pub fn set_sink(sink: Sink) {
+    let mut current = SINK.lock().unwrap();
+    flush_sink(&mut current);
+    *current = sink;
+}
+
+/// Write a message to the sink. Use the `trace!` macro instead, which checks the level first
+pub fn log(subsystem: Subsystem, level: Level, args: fmt::Arguments) {
+    let mut sink = SINK.lock().unwrap();
+    match &mut *sink {
+        Sink::Stdout => println!("[{}] {:?}: {}", subsystem, level, args),
+        Sink::File(file) => {
+            // Tracing must never bring down the emulator, a failed write only loses the line
+            let _ = writeln!(file, "[{}] {:?}: {}", subsystem, level, args);
+        }
+        Sink::Ring { lines, capacity } => {
+            if lines.len() == *capacity {
+                lines.pop_front();
+            }
+            if *capacity > 0 {
+                lines.push_back(format!("[{}] {:?}: {}", subsystem, level, args));
+            }
+        }
+    }
+}
+
+/// Return the lines currently held by the ring buffer sink, oldest first
+pub fn ring_lines() -> Vec<String> {
+    match &*SINK.lock().unwrap() {
+        Sink::Ring { lines, .. } => lines.iter().cloned().collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Flush buffered output, called before exiting
+pub fn flush() {
+    flush_sink(&mut SINK.lock().unwrap());
+}
+
+fn flush_sink(sink: &mut Sink) {
+    if let Sink::File(file) = sink {
+        let _ = file.flush();
+    }
+}
+
+/// Log a message if the level is enabled for the subsystem, formatting is skipped otherwise
+///
+/// ```ignore
+/// trace!(Cpu, Trace, "PC: 0x{:04X}", pc);
+/// ```
+macro_rules! trace {
+    ($subsystem:ident, $level:ident, $($arg:tt)*) => {
+        if $crate::emulator::trace::enabled($crate::emulator::trace::Subsystem::$subsystem, $crate::emulator::trace::Level::$level) {
+            $crate::emulator::trace::log(
+                $crate::emulator::trace::Subsystem::$subsystem,
+                $crate::emulator::trace::Level::$level,
+                format_args!($($arg)*),
+            );
+        }
+    };
+}
+pub(crate) use trace;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn single_level_applies_to_every_subsystem() {
+        assert_eq!(
+            parse_filter("DEBUG").unwrap(),
+            [(Subsystem::Cpu, Level::Debug), (Subsystem::Mmu, Level::Debug), (Subsystem::Ppu, Level::Debug)]
+        );
+        assert_eq!(parse_filter("").unwrap(), []);
+    }
+
+    #[test]
+    fn subsystem_list() {
+        assert_eq!(
+            parse_filter("cpu=trace, mmu = info,,ppu=off").unwrap(),
+            [(Subsystem::Cpu, Level::Trace), (Subsystem::Mmu, Level::Info), (Subsystem::Ppu, Level::Off)]
+        );
+        // A global level followed by overrides
+        assert_eq!(parse_filter("warn,cpu=trace").unwrap().last(), Some(&(Subsystem::Cpu, Level::Trace)));
+    }
+
+    #[test]
+    fn bad_filters() {
+        assert_eq!(parse_filter("verbose").unwrap_err(), "Unknown trace level: verbose");
+        assert_eq!(parse_filter("apu=debug").unwrap_err(), "Unknown trace subsystem: apu");
+        assert_eq!(parse_filter("cpu=loud").unwrap_err(), "Unknown trace level: loud");
+        assert!(parse_filter("cpu=trace,=debug").is_err());
+    }
+}
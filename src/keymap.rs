@@ -0,0 +1,140 @@
+//! Keyboard mapping of the joypad buttons
+
+use std::collections::HashMap;
+
+use sdl2::keyboard::Keycode;
+
+use crate::emulator::joypad::Button;
+
+/// Keys handled by the main loop before the joypad, they cannot be bound to a button
+pub const HOTKEYS: [Keycode; 10] = [
+    Keycode::Escape,
+    Keycode::Tab,
+    Keycode::P,
+    Keycode::N,
+    Keycode::LeftBracket,
+    Keycode::RightBracket,
+    Keycode::Minus,
+    Keycode::Equals,
+    Keycode::F2,
+    Keycode::F11,
+];
+
+#[derive(Debug, Clone)]
+pub struct KeyMap {
+    keys: HashMap<Keycode, Button>,
+}
+
+impl Default for KeyMap {
+    // Arrows for the directions, Z and X for A and B, Enter and Backspace for Start and Select
+    fn default() -> Self {
+        let keys = HashMap::from([
+            (Keycode::Right, Button::Right),
+            (Keycode::Left, Button::Left),
+            (Keycode::Up, Button::Up),
+            (Keycode::Down, Button::Down),
+            (Keycode::Z, Button::A),
+            (Keycode::X, Button::B),
+            (Keycode::Backspace, Button::Select),
+            (Keycode::Return, Button::Start),
+        ]);
+        Self { keys }
+    }
+}
+
+impl KeyMap {
+    /// Parse a mapping as a comma separated list of button=key, using SDL key names, e.g.
+    /// `a=Space,b=Left Shift,start=Return`. Buttons that are not listed keep their default key, a
+    /// key bound to two buttons or a hotkey is an error
+    pub fn parse(mapping: &str) -> Result<Self, String> {
+        // A button listed twice takes the last key
+        let mut bindings: Vec<(Button, Keycode)> = Vec::new();
+        for entry in mapping.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+            let (button, key) = entry.split_once('=').ok_or_else(|| format!("Expected button=key, got {}", entry))?;
+            let button = parse_button(button.trim())?;
+            let key = Keycode::from_name(key.trim()).ok_or_else(|| format!("Unknown key: {}", key))?;
+            if HOTKEYS.contains(&key) {
+                return Err(format!("Key {} is a hotkey and cannot be bound to {:?}", key.name(), button));
+            }
+            bindings.retain(|&(bound, _)| bound != button);
+            bindings.push((button, key));
+        }
+        let mut keymap = Self::default();
+        keymap.keys.retain(|_, button| bindings.iter().all(|(bound, _)| bound != button));
+        for (button, key) in bindings {
+            if let Some(other) = keymap.keys.insert(key, button) {
+                return Err(format!("Key {} is bound to both {:?} and {:?}", key.name(), other, button));
+            }
+        }
+        Ok(keymap)
+    }
+
+    pub fn button(&self, key: Keycode) -> Option<Button> {
+        self.keys.get(&key).copied()
+    }
+}
+
+fn parse_button(name: &str) -> Result<Button, String> {
+    Button::ALL
+        .into_iter()
+        .find(|button| format!("{:?}", button).eq_ignore_ascii_case(name))
+        .ok_or_else(|| format!("Unknown button: {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn empty_mapping_is_default() {
+        let keymap = KeyMap::parse("").unwrap();
+        assert_eq!(keymap.button(Keycode::Z), Some(Button::A));
+        assert_eq!(keymap.button(Keycode::Return), Some(Button::Start));
+        assert_eq!(keymap.keys.len(), 8);
+    }
+
+    #[test]
+    fn rebinding_replaces_only_that_button() {
+        let keymap = KeyMap::parse(" A=Space , start = Left Shift").unwrap();
+        assert_eq!(keymap.button(Keycode::Space), Some(Button::A));
+        assert_eq!(keymap.button(Keycode::LShift), Some(Button::Start));
+        assert_eq!(keymap.button(Keycode::Z), None);
+        assert_eq!(keymap.button(Keycode::Return), None);
+        assert_eq!(keymap.button(Keycode::X), Some(Button::B));
+        assert_eq!(keymap.keys.len(), 8);
+    }
+
+    #[test]
+    fn keys_can_be_swapped() {
+        let keymap = KeyMap::parse("a=X,b=Z").unwrap();
+        assert_eq!(keymap.button(Keycode::X), Some(Button::A));
+        assert_eq!(keymap.button(Keycode::Z), Some(Button::B));
+        // The last binding of a button wins
+        let keymap = KeyMap::parse("a=Q,a=W").unwrap();
+        assert_eq!(keymap.button(Keycode::Q), None);
+        assert_eq!(keymap.button(Keycode::W), Some(Button::A));
+    }
+
+    #[test]
+    fn conflicts_are_errors() {
+        // X stays bound to B
+        assert_eq!(KeyMap::parse("a=X").unwrap_err(), "Key X is bound to both B and A");
+        assert!(KeyMap::parse("a=Q,b=Q").is_err());
+    }
+
+    #[test]
+    fn hotkeys_are_errors() {
+        assert_eq!(KeyMap::parse("start=Escape").unwrap_err(), "Key Escape is a hotkey and cannot be bound to Start");
+        assert_eq!(KeyMap::parse("a=P").unwrap_err(), "Key P is a hotkey and cannot be bound to A");
+        for key in HOTKEYS {
+            assert!(KeyMap::parse(&format!("b={}", key.name())).is_err(), "{} accepted", key.name());
+        }
+    }
+
+    #[test]
+    fn invalid_entries() {
+        assert_eq!(KeyMap::parse("a").unwrap_err(), "Expected button=key, got a");
+        assert_eq!(KeyMap::parse("turbo=Q").unwrap_err(), "Unknown button: turbo");
+        assert_eq!(KeyMap::parse("a=NotAKey").unwrap_err(), "Unknown key: NotAKey");
+    }
+}
@@ -1,46 +1,517 @@
-use clap::Parser;
+#![allow(clippy::upper_case_acronyms, clippy::module_inception)]
+use clap::{Parser, Subcommand};
+mod display;
 mod emulator;
+mod keymap;
+mod pacer;
 
-use sdl2::pixels::Color;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
+use sdl2::controller::{Axis, GameController};
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::{AudioSubsystem, GameControllerSubsystem};
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use display::Display;
+use emulator::cartridge::{Cartridge, CgbSupport};
+use emulator::dassm;
+use emulator::doctor::Doctor;
+use emulator::mapper::{CartridgeEvent, CYCLES_PER_SECOND};
+use emulator::mmu::MMU;
+use emulator::ppu::{Renderer, CYCLES_PER_FRAME};
+use emulator::save::SaveFile;
+use keymap::KeyMap;
+use pacer::{Pacer, FRAME_RATE, MIN_SPEED};
+use emulator::trace;
+
+// Levels parsed from --trace. An alias, as clap would read a `Vec` field as a list of values
+type TraceFilter = Vec<(trace::Subsystem, trace::Level)>;
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
+    #[command(subcommand)]
+    command: Option<Command>,
     // Name of the ROM file
-    #[arg(name = "ROM")]
-    name: String,
+    #[arg(name = "ROM", required = true)]
+    name: Option<String>,
+    // Trace log filter, a level for every subsystem (off, error, warn, info, debug, trace) or a
+    // comma separated list of subsystem=level, e.g. cpu=trace,mmu=debug
+    #[arg(long, value_parser = trace::parse_filter, default_value = "error")]
+    trace: TraceFilter,
+    // Write the trace log to a file instead of stdout
+    #[arg(long)]
+    trace_file: Option<String>,
+    // Keep only the last N trace lines in memory, they are printed on exit
+    #[arg(long, conflicts_with = "trace_file")]
+    trace_ring: Option<usize>,
+    // Write a Gameboy Doctor log of the CPU state before every instruction to a file
+    #[arg(long)]
+    doctor: Option<String>,
+    // Compare the CPU state against a Gameboy Doctor log, stopping at the first difference
+    #[arg(long)]
+    doctor_reference: Option<String>,
+    // Key mapping, a comma separated list of button=key using SDL key names, e.g. a=Space,start=Return
+    // Buttons are right, left, up, down, a, b, select and start
+    #[arg(long, value_parser = KeyMap::parse, default_value = "")]
+    keys: KeyMap,
+    // Run ROMs with a bad logo or header checksum, which the boot ROM would refuse
+    #[arg(long)]
+    ignore_header: bool,
+    // PPU renderer, scanline or fifo. The FIFO renderer shows register writes in the middle of a
+    // line and has the accurate mode 3 length, F2 switches between them while running
+    #[arg(long, value_parser = Renderer::parse, default_value = "scanline")]
+    renderer: Renderer,
+    // Initial window size, as a multiple of 160x144. Minus and Equals change it while running and
+    // F11 toggles fullscreen
+    #[arg(long, value_parser = clap::value_parser!(u32).range(display::MIN_SCALE as i64..=display::MAX_SCALE as i64), default_value = "3")]
+    scale: u32,
+    // Emulation speed, a multiplier of the 59.73 Hz frame rate. [ and ] change it while running
+    #[arg(long, value_parser = parse_speed, default_value = "1")]
+    speed: f64,
+    // Speed while Tab is held, 0 for no limit
+    #[arg(long, value_parser = parse_fast_forward, default_value = "4")]
+    fast_forward: f64,
+    // Run as fast as possible and print the average frame rate on exit, for benchmarking
+    #[arg(long)]
+    uncapped: bool,
+    // Audio sample rate in Hz
+    #[arg(long, value_parser = clap::value_parser!(i32).range(8000..=192000), default_value = "48000")]
+    sample_rate: i32,
+    // Do not play audio
+    #[arg(long)]
+    mute: bool,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    // Disassemble a ROM. Without --bank, banks 0 and 1 are disassembled as mapped at boot
+    Disasm {
+        // Name of the ROM file
+        #[arg(name = "ROM")]
+        name: String,
+        // Only disassemble this 16 KiB ROM bank
+        #[arg(long)]
+        bank: Option<usize>,
+        // First address to disassemble, in hex
+        #[arg(long, value_parser = parse_address)]
+        start: Option<u16>,
+        // Last address to disassemble, in hex
+        #[arg(long, value_parser = parse_address)]
+        end: Option<u16>,
+    },
+}
+
+// Parse a hex address, with an optional 0x or $ prefix
+fn parse_address(address: &str) -> Result<u16, String> {
+    let digits = address.trim_start_matches("0x").trim_start_matches('$');
+    u16::from_str_radix(digits, 16).map_err(|err| format!("Invalid address {}: {}", address, err))
+}
+
+// Parse a speed multiplier, which must be finite and at least MIN_SPEED
+fn parse_speed(speed: &str) -> Result<f64, String> {
+    match speed.parse::<f64>() {
+        Ok(speed) if speed >= MIN_SPEED && speed.is_finite() => Ok(speed),
+        _ => Err(format!("Invalid speed {}, expected a number from {}", speed, MIN_SPEED)),
+    }
+}
+
+// Parse the fast forward speed, a speed multiplier or 0 for no limit
+fn parse_fast_forward(speed: &str) -> Result<f64, String> {
+    match speed.parse::<f64>() {
+        Ok(speed) if speed == 0.0 => Ok(speed),
+        _ => parse_speed(speed).map_err(|err| format!("{}, or 0 for no limit", err)),
+    }
+}
+
+// Print the disassembly of a ROM bank, or of banks 0 and 1 if no bank is given
+fn disasm(rom_path: &str, bank: Option<usize>, start: Option<u16>, end: Option<u16>) {
+    const BANK_SIZE: usize = 0x4000;
+    let rom = std::fs::read(rom_path).unwrap_or_else(|err| {
+        eprintln!("Cannot read ROM {}: {}", rom_path, err);
+        std::process::exit(1);
+    });
+    let banks = match bank {
+        Some(bank) => vec![bank],
+        None => vec![0, 1],
+    };
+    for bank in banks {
+        let offset = bank * BANK_SIZE;
+        if offset >= rom.len() {
+            eprintln!("ROM has no bank {}", bank);
+            continue;
+        }
+        let data = &rom[offset..rom.len().min(offset + BANK_SIZE)];
+        // Bank 0 is always mapped at 0x0000, every other bank at 0x4000
+        let base: usize = if bank == 0 { 0x0000 } else { 0x4000 };
+        let start = (start.unwrap_or(0) as usize).max(base);
+        let end = (end.unwrap_or(0xffff) as usize).min(base + data.len() - 1);
+        if start > end {
+            continue;
+        }
+        for instruction in dassm::disassemble(&data[start - base..=end - base], start as u16) {
+            println!("{:02X}:{}", bank, instruction);
+        }
+    }
+}
+
+// Load a ROM and report problems with its header
+// Exits if the ROM cannot be used, or if real hardware would refuse it and `ignore_header` is not set
+fn load_cartridge(rom_path: &str, ignore_header: bool) -> Cartridge {
+    let cartridge = Cartridge::load(rom_path).unwrap_or_else(|err| {
+        eprintln!("Cannot load ROM {}: {}", rom_path, err);
+        std::process::exit(1);
+    });
+    let problems = cartridge.verify();
+    for problem in &problems {
+        eprintln!("Warning: {}", problem);
+    }
+    if !ignore_header && problems.iter().any(|problem| problem.is_fatal()) {
+        eprintln!("Refusing to run a ROM the boot ROM would reject, use --ignore-header to run it anyway");
+        std::process::exit(1);
+    }
+    let header = &cartridge.header;
+    if header.cgb == CgbSupport::Only {
+        eprintln!("Warning: {} requires a Game Boy Color, only DMG mode is emulated", header.title);
+    }
+    trace::trace!(Mmu, Info, "Loaded {} ({}, {} KiB ROM, {} KiB RAM, version {}, CGB {:?}, SGB {}, licensee {:?})",
+        header.title, header.cartridge_type_name(), header.rom_size / 1024, header.ram_size / 1024, header.version,
+        header.cgb, header.sgb, header.licensee);
+    cartridge
+}
+
+// Open the first connected game controller, used for rumble
+fn open_controller(subsystem: &GameControllerSubsystem) -> Option<GameController> {
+    let count = subsystem.num_joysticks().unwrap_or(0);
+    let controller = (0..count)
+        .filter(|&index| subsystem.is_game_controller(index))
+        .find_map(|index| subsystem.open(index).ok())?;
+    trace::trace!(Mmu, Info, "Using game controller {} for rumble", controller.name());
+    Some(controller)
+}
+
+// Rumble is refreshed every frame while the motor is on, the duration only has to outlast a frame
+const RUMBLE_DURATION_MS: u32 = 100;
+
+fn set_rumble(controller: Option<&mut GameController>, on: bool) {
+    if let Some(controller) = controller {
+        let strength = if on { 0xffff } else { 0 };
+        let _ = controller.set_rumble(strength, strength, RUMBLE_DURATION_MS);
+    }
+}
+
+// Forward cartridge events to the frontend
+fn handle_cartridge_events(events: Vec<CartridgeEvent>, mut controller: Option<&mut GameController>, rumble: &mut bool) {
+    for event in events {
+        match event {
+            CartridgeEvent::Rumble(on) => {
+                if on != *rumble {
+                    trace::trace!(Mmu, Debug, "Rumble {}", if on { "on" } else { "off" });
+                    *rumble = on;
+                    set_rumble(controller.as_deref_mut(), on);
+                }
+            }
+        }
+    }
+}
+
+// Open the audio output, the emulator keeps running silently if there is none
+fn open_audio(audio_subsystem: &AudioSubsystem, sample_rate: i32) -> Option<AudioQueue<f32>> {
+    let spec = AudioSpecDesired { freq: Some(sample_rate), channels: Some(2), samples: Some(1024) };
+    match audio_subsystem.open_queue::<f32, _>(None, &spec) {
+        Ok(queue) => {
+            queue.resume();
+            Some(queue)
+        }
+        Err(err) => {
+            eprintln!("Cannot open audio, running without sound: {}", err);
+            None
+        }
+    }
+}
+
+// Queue the samples of a frame. When the emulator runs faster than real time they are dropped
+// instead of building up latency
+fn queue_audio(queue: Option<&AudioQueue<f32>>, samples: &[f32]) {
+    // Audio queued ahead, in seconds
+    const MAX_LATENCY: f64 = 0.1;
+    let Some(queue) = queue else {
+        return;
+    };
+    let spec = queue.spec();
+    let max_size = (spec.freq as f64 * spec.channels as f64 * std::mem::size_of::<f32>() as f64 * MAX_LATENCY) as u32;
+    if queue.size() < max_size {
+        if let Err(err) = queue.queue_audio(samples) {
+            eprintln!("Cannot queue audio: {}", err);
+        }
+    }
+}
+
+// Load the save file of a cartridge with a battery
+fn load_save(rom_path: &str, mmu: &mut MMU) -> Option<SaveFile> {
+    if !mmu.header.as_ref().is_some_and(|header| header.has_battery()) {
+        return None;
+    }
+    let mut save_file = SaveFile::for_rom(rom_path);
+    match save_file.load(mmu) {
+        Ok(true) => trace::trace!(Mmu, Info, "Loaded save {}", save_file.path().display()),
+        Ok(false) => trace::trace!(Mmu, Info, "No save {} yet", save_file.path().display()),
+        // Do not overwrite a save that could not be read
+        Err(err) => {
+            eprintln!("Cannot read save {}: {}", save_file.path().display(), err);
+            std::process::exit(1);
+        }
+    }
+    Some(save_file)
+}
+
+// Write the save file, if the cartridge has one
+fn flush_save(save_file: Option<&mut SaveFile>, mmu: &MMU) {
+    if let Some(save_file) = save_file {
+        if let Err(err) = save_file.flush(mmu) {
+            eprintln!("Cannot write save {}: {}", save_file.path().display(), err);
+        }
+    }
+}
+
+// Status shown in the window title
+fn status(paused: bool, fast_forward: bool, speed: f64) -> String {
+    if paused {
+        "Paused".to_string()
+    } else if fast_forward {
+        "Fast forward".to_string()
+    } else if speed != 1.0 {
+        format!("{}x", speed)
+    } else {
+        String::new()
+    }
+}
+
+// Setup the tracelogger from the command line arguments
+fn configure_trace(args: &Args) {
+    trace::set_levels(&args.trace);
+    if let Some(path) = &args.trace_file {
+        let sink = trace::Sink::file(path).unwrap_or_else(|err| {
+            eprintln!("Cannot create trace file {}: {}", path, err);
+            std::process::exit(1);
+        });
+        trace::set_sink(sink);
+    } else if let Some(capacity) = args.trace_ring {
+        trace::set_sink(trace::Sink::ring(capacity));
+    }
+}
+
+// Flush the trace log, and print the ring buffer if one is used
+fn finish_trace() {
+    for line in trace::ring_lines() {
+        println!("{}", line);
+    }
+    trace::flush();
 }
 
 
 fn main() {
+    let args = Args::parse();
+    if let Some(Command::Disasm { name, bank, start, end }) = &args.command {
+        disasm(name, *bank, *start, *end);
+        return;
+    }
+    configure_trace(&args);
+    let rom_path = args.name.as_deref().expect("ROM is required");
+    let cartridge = load_cartridge(rom_path, args.ignore_header);
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
-    let window = video_subsystem.window("Cartridge", 160, 144)
-        .position_centered()
-        .build()
-        .unwrap();
-    let mut canvas = window.into_canvas().build().unwrap();
-    canvas.set_draw_color(Color::RGB(0, 0, 0));
-    canvas.clear();
-    canvas.present();
+    let mut display = Display::new(&video_subsystem, &cartridge.header.title, args.scale).unwrap_or_else(|err| panic!("Cannot open window: {}", err));
+    let texture_creator = display.texture_creator();
+    let mut texture = Display::create_texture(&texture_creator).unwrap_or_else(|err| panic!("Cannot create texture: {}", err));
     let mut event_pump = sdl_context.event_pump().unwrap();
-    let args = Args::parse();
+    // Without a game controller subsystem there is just no rumble
+    let controller_subsystem = sdl_context.game_controller().map_err(|err| eprintln!("No game controller support: {}", err)).ok();
+    let mut controller = controller_subsystem.as_ref().and_then(open_controller);
+    let mut rumble = false;
+    // The left stick tilts carts with an accelerometer
+    let mut tilt = (0.0, 0.0);
     let mut emulator = emulator::emulator::Emulator::new();
-    emulator.mmu.load_rom(&args.name);
+    emulator.ppu.set_renderer(args.renderer);
+    emulator.mmu.load_cartridge(cartridge).unwrap_or_else(|err| {
+        eprintln!("Cannot run ROM: {}", err);
+        std::process::exit(1);
+    });
+    let mut save_file = load_save(rom_path, &mut emulator.mmu);
+    let audio_subsystem = sdl_context.audio().unwrap();
+    let audio = if args.mute { None } else { open_audio(&audio_subsystem, args.sample_rate) };
+    if let Some(audio) = audio.as_ref() {
+        emulator.mmu.apu.set_sample_rate(audio.spec().freq as u32);
+    }
+    // Saves are written once per emulated second when the cartridge state changed
+    let mut next_save = CYCLES_PER_SECOND as u64;
+    let mut doctor = if args.doctor.is_some() || args.doctor_reference.is_some() {
+        emulator.mmu.doctor_mode = true;
+        let doctor = Doctor::new(args.doctor.as_deref(), args.doctor_reference.as_deref())
+            .unwrap_or_else(|err| panic!("Cannot open Gameboy Doctor log: {}", err));
+        Some(doctor)
+    } else {
+        None
+    };
+    let mut pacer = Pacer::new(args.speed, args.uncapped);
+    display.set_status(&status(false, false, pacer.speed()));
+    let mut fast_forward = false;
+    let mut paused = false;
+    let mut advance = false;
+    // The emulator runs a frame at a time, events are handled in between
+    let mut frame_end = CYCLES_PER_FRAME as u64;
+    let started = Instant::now();
+    let mut frames = 0u64;
     loop {
+        // Keys handled here before the joypad must be listed in keymap::HOTKEYS
         for event in event_pump.poll_iter() {
             match event {
                 Event::Quit {..} |
                 Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
+                    flush_save(save_file.as_mut(), &emulator.mmu);
+                    finish_trace();
+                    if let Some(doctor) = doctor.as_mut() {
+                        doctor.flush();
+                    }
+                    if args.uncapped {
+                        let seconds = started.elapsed().as_secs_f64();
+                        let fps = frames as f64 / seconds;
+                        println!("{} frames in {:.2} s, {:.1} fps ({:.0}% speed)", frames, seconds, fps, fps / FRAME_RATE * 100.0);
+                    }
                     return;
                 },
+                Event::KeyDown { keycode: Some(Keycode::Tab), repeat: false, .. } => {
+                    fast_forward = true;
+                    display.set_status(&status(paused, fast_forward, pacer.speed()));
+                },
+                Event::KeyUp { keycode: Some(Keycode::Tab), .. } => {
+                    fast_forward = false;
+                    display.set_status(&status(paused, fast_forward, pacer.speed()));
+                },
+                Event::KeyDown { keycode: Some(Keycode::P), repeat: false, .. } => {
+                    paused = !paused;
+                    pacer.reset();
+                    display.set_status(&status(paused, fast_forward, pacer.speed()));
+                },
+                // Run a single frame while paused
+                Event::KeyDown { keycode: Some(Keycode::N), .. } if paused => advance = true,
+                Event::KeyDown { keycode: Some(key @ (Keycode::LeftBracket | Keycode::RightBracket)), .. } => {
+                    if key == Keycode::LeftBracket { pacer.slower(); } else { pacer.faster(); }
+                    display.set_status(&status(paused, fast_forward, pacer.speed()));
+                },
+                Event::KeyDown { keycode: Some(Keycode::F11), repeat: false, .. } => {
+                    if let Err(err) = display.toggle_fullscreen() {
+                        eprintln!("Cannot toggle fullscreen: {}", err);
+                    }
+                },
+                Event::KeyDown { keycode: Some(key @ (Keycode::Minus | Keycode::Equals)), .. } => {
+                    let scale = if key == Keycode::Minus { display.scale().saturating_sub(1) } else { display.scale() + 1 };
+                    if let Err(err) = display.set_scale(scale) {
+                        eprintln!("Cannot resize window: {}", err);
+                    }
+                },
+                Event::KeyDown { keycode: Some(Keycode::F2), repeat: false, .. } => {
+                    let renderer = match emulator.ppu.renderer() {
+                        Renderer::Scanline => Renderer::Fifo,
+                        Renderer::Fifo => Renderer::Scanline,
+                    };
+                    trace::trace!(Ppu, Info, "Switching to the {:?} renderer", renderer);
+                    emulator.ppu.set_renderer(renderer);
+                },
+                Event::KeyDown { keycode: Some(key), repeat: false, .. } => {
+                    if let Some(button) = args.keys.button(key) {
+                        emulator.mmu.set_button(button, true);
+                    }
+                },
+                Event::KeyUp { keycode: Some(key), .. } => {
+                    if let Some(button) = args.keys.button(key) {
+                        emulator.mmu.set_button(button, false);
+                    }
+                },
+                Event::ControllerDeviceAdded { .. } if controller.is_none() => {
+                    controller = controller_subsystem.as_ref().and_then(open_controller);
+                },
+                Event::ControllerAxisMotion { axis, value, .. } => {
+                    let value = value as f32 / i16::MAX as f32;
+                    match axis {
+                        Axis::LeftX => tilt.0 = value,
+                        Axis::LeftY => tilt.1 = value,
+                        _ => continue,
+                    }
+                    emulator.mmu.set_tilt(tilt.0, tilt.1);
+                },
                 _ => {}
             }
         }
-        emulator.cpu.fetch(&mut emulator.mmu);
-        emulator.cpu.execute(&mut emulator.mmu);
-        canvas.present();
+        if paused && !advance {
+            thread::sleep(Duration::from_secs_f64(1.0 / FRAME_RATE));
+            continue;
+        }
+        advance = false;
+        while emulator.mmu.cycles < frame_end {
+            // Only log when an instruction is about to be executed, not when the CPU idles or
+            // dispatches an interrupt
+            if let Some(doctor) = doctor.as_mut().filter(|_| emulator.cpu.executes_instruction(&mut emulator.mmu)) {
+                if let Err(err) = doctor.log(&emulator.cpu, &emulator.mmu) {
+                    eprintln!("{}", err);
+                    flush_save(save_file.as_mut(), &emulator.mmu);
+                    doctor.flush();
+                    finish_trace();
+                    return;
+                }
+            }
+            emulator.step();
+            if emulator.cpu.locked {
+                eprintln!("Invalid opcode 0x{:02X} at 0x{:04X}", emulator.cpu.instr, emulator.cpu.pc.wrapping_sub(1));
+                flush_save(save_file.as_mut(), &emulator.mmu);
+                if let Some(doctor) = doctor.as_mut() {
+                    doctor.flush();
+                }
+                finish_trace();
+                std::process::exit(1);
+            }
+            handle_cartridge_events(emulator.mmu.take_cartridge_events(), controller.as_mut(), &mut rumble);
+            if emulator.mmu.cycles >= next_save {
+                flush_save(save_file.as_mut(), &emulator.mmu);
+                next_save += CYCLES_PER_SECOND as u64;
+            }
+            if emulator.ppu.take_frame_ready() {
+                if let Err(err) = display.present(&mut texture, &emulator.ppu.frame) {
+                    eprintln!("Cannot draw frame: {}", err);
+                }
+            }
+        }
+        queue_audio(audio.as_ref(), &emulator.mmu.apu.take_samples());
+        if rumble {
+            set_rumble(controller.as_mut(), true);
+        }
+        frame_end += CYCLES_PER_FRAME as u64;
+        frames += 1;
+        pacer.wait(fast_forward.then_some(args.fast_forward));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn speeds_must_be_finite_and_not_too_small() {
+        assert_eq!(parse_speed("1.5"), Ok(1.5));
+        assert_eq!(parse_speed("0.01"), Ok(0.01));
+        for speed in ["0", "-1", "1e-300", "nan", "inf", "fast"] {
+            assert!(parse_speed(speed).is_err(), "{}", speed);
+        }
+    }
+
+    #[test]
+    fn fast_forward_accepts_no_limit() {
+        assert_eq!(parse_fast_forward("0"), Ok(0.0));
+        assert_eq!(parse_fast_forward("4"), Ok(4.0));
+        for speed in ["-0.5", "1e-300", "NaN", "-inf", ""] {
+            assert!(parse_fast_forward(speed).is_err(), "{}", speed);
+        }
     }
 }
@@ -0,0 +1,77 @@
+//! Frame pacing
+//!
+//! The emulator runs a frame (70224 T-cycles) at a time, then sleeps until the frame is due. At
+//! normal speed frames are due 59.73 times per second, the rate of the hardware.
+
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::emulator::mapper::CYCLES_PER_SECOND;
+use crate::emulator::ppu::CYCLES_PER_FRAME;
+
+// Frames per second at normal speed
+pub const FRAME_RATE: f64 = CYCLES_PER_SECOND as f64 / CYCLES_PER_FRAME as f64;
+// Slowest speed accepted, about a frame every 2 seconds
+pub const MIN_SPEED: f64 = 0.01;
+// Speeds stepped through by `faster` and `slower`
+const SPEEDS: [f64; 8] = [0.25, 0.5, 0.75, 1.0, 1.5, 2.0, 4.0, 8.0];
+// When the host falls further behind than this, the lost time is dropped instead of being caught
+// up with a burst of frames
+const MAX_LAG: Duration = Duration::from_millis(100);
+
+pub struct Pacer {
+    speed: f64, // Multiplier of the normal frame rate
+    uncapped: bool, // Run as fast as possible, for benchmarking
+    next_frame: Instant,
+}
+
+impl Pacer {
+    /// # Arguments
+    ///
+    /// * `speed` - Multiplier of the normal frame rate
+    /// * `uncapped` - Never wait, run as fast as the host allows
+    ///
+    pub fn new(speed: f64, uncapped: bool) -> Self {
+        Self { speed, uncapped, next_frame: Instant::now() }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Step up to the next speed
+    pub fn faster(&mut self) {
+        self.speed = SPEEDS.iter().copied().find(|&speed| speed > self.speed).unwrap_or(self.speed);
+    }
+
+    /// Step down to the previous speed
+    pub fn slower(&mut self) {
+        self.speed = SPEEDS.iter().copied().rev().find(|&speed| speed < self.speed).unwrap_or(self.speed);
+    }
+
+    /// Start pacing from now, after a pause
+    pub fn reset(&mut self) {
+        self.next_frame = Instant::now();
+    }
+
+    /// Sleep until the next frame is due
+    ///
+    /// # Arguments
+    ///
+    /// * `fast_forward` - Speed to use instead of the normal one, 0 for no limit
+    ///
+    pub fn wait(&mut self, fast_forward: Option<f64>) {
+        let speed = fast_forward.unwrap_or(self.speed);
+        if self.uncapped || speed <= 0.0 {
+            self.reset();
+            return;
+        }
+        self.next_frame += Duration::from_secs_f64(1.0 / (FRAME_RATE * speed));
+        let now = Instant::now();
+        if self.next_frame > now {
+            thread::sleep(self.next_frame - now);
+        } else if now - self.next_frame > MAX_LAG {
+            self.next_frame = now;
+        }
+    }
+}